@@ -0,0 +1,172 @@
+use dashmap::DashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// Length of a [`RetryBudget`] window.
+const RETRY_WINDOW: Duration = Duration::from_secs(1);
+
+/// What a replica read budget is counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetScope {
+    /// One budget shared by every client.
+    Global,
+    /// A separate budget per proxy user.
+    User,
+}
+
+/// Caps how many reads the replica serves per time window; reads over the cap go to master.
+///
+/// Uses fixed windows: the counter for a scope resets once `window` has elapsed since the first
+/// read counted in it.
+#[derive(Debug)]
+pub struct ReplicaBudget {
+    limit: u64,
+    window: Duration,
+    scope: BudgetScope,
+    // Keyed by user, or by "" for the global scope.
+    windows: DashMap<String, Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    used: u64,
+}
+
+impl ReplicaBudget {
+    pub fn new(limit: u64, window: Duration, scope: BudgetScope) -> Self {
+        Self {
+            limit,
+            window,
+            scope,
+            windows: DashMap::new(),
+        }
+    }
+
+    /// Take one replica read from the budget. Returns `false` if it is exhausted for this window.
+    pub fn try_acquire(&self, user: &str) -> bool {
+        let key = match self.scope {
+            BudgetScope::Global => "",
+            BudgetScope::User => user,
+        };
+        let mut entry = match self.windows.get_mut(key) {
+            Some(entry) => entry,
+            None => self.windows.entry(key.to_string()).or_insert(Window {
+                started: Instant::now(),
+                used: 0,
+            }),
+        };
+
+        if entry.started.elapsed() >= self.window {
+            entry.started = Instant::now();
+            entry.used = 0;
+        }
+        if entry.used < self.limit {
+            entry.used += 1;
+            return true;
+        }
+        if entry.used == self.limit {
+            // Counted once more so this is logged only on the first overflow of the window.
+            entry.used += 1;
+            tracing::info!(
+                scope = ?self.scope,
+                user = key,
+                limit = self.limit,
+                "replica read budget exhausted; routing reads to master for the rest of the window"
+            );
+        }
+        false
+    }
+}
+
+/// `--retry-budget` and `--retry-budget-per-connection`: caps on the commands the proxy sends
+/// again, so that a sick backend can't have it multiply the traffic it gets. Counted in fixed
+/// one-second windows, across every connection and per connection.
+#[derive(Debug, Default)]
+pub struct RetryBudget {
+    global: RetryWindow,
+}
+
+/// Retries counted in the current one-second window, of every connection or of one.
+#[derive(Debug)]
+pub struct RetryWindow {
+    window: Mutex<RetryCount>,
+}
+
+#[derive(Debug)]
+struct RetryCount {
+    started: Instant,
+    used: u64,
+    denied: u64,
+}
+
+impl Default for RetryWindow {
+    fn default() -> Self {
+        Self {
+            window: Mutex::new(RetryCount {
+                started: Instant::now(),
+                used: 0,
+                denied: 0,
+            }),
+        }
+    }
+}
+
+impl RetryCount {
+    /// Whether `limit` leaves room for one more retry, starting a new window if this one is over.
+    fn has_room(&mut self, limit: Option<u64>) -> bool {
+        if self.started.elapsed() >= RETRY_WINDOW {
+            self.started = Instant::now();
+            self.used = 0;
+            self.denied = 0;
+        }
+        limit.is_none_or(|limit| self.used < limit)
+    }
+}
+
+impl RetryBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take one retry from the budget of every connection and from `own`, the connection's.
+    /// Returns `false` if either is exhausted for this window; nothing is taken then.
+    pub fn try_acquire(&self, own: &RetryWindow, cfg: &Config) -> bool {
+        let mut own = own.window.lock().expect("retry window lock poisoned");
+        let mut global = self
+            .global
+            .window
+            .lock()
+            .expect("retry window lock poisoned");
+        let (own_room, global_room) = (
+            own.has_room(cfg.retry_budget_per_connection),
+            global.has_room(cfg.retry_budget),
+        );
+        if own_room && global_room {
+            own.used += 1;
+            global.used += 1;
+            return true;
+        }
+        if !global_room {
+            global.denied += 1;
+            if global.denied == 1 {
+                tracing::warn!(
+                    limit = cfg.retry_budget,
+                    "retry budget exhausted; not retrying for the rest of the second"
+                );
+            }
+        } else {
+            own.denied += 1;
+            if own.denied == 1 {
+                tracing::info!(
+                    limit = cfg.retry_budget_per_connection,
+                    "connection retry budget exhausted; not retrying for the rest of the second"
+                );
+            }
+        }
+        false
+    }
+}
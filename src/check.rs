@@ -0,0 +1,186 @@
+use anyhow::{Context, Result, anyhow};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+use crate::config::{Config, RedisEndpoint};
+use crate::listener::PeerAddr;
+use crate::proxy::{Shared, connect_and_handshake};
+use crate::resolver::Resolver;
+use crate::resp::{Reply, RespStream, encode_command_str};
+use crate::versions::query_server_info;
+
+/// Pause between attempts of [`wait_for_backends`].
+const WAIT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Outcome of one `check-config` step, printed as a line of the report.
+pub struct Check {
+    pub name: String,
+    pub result: Result<String>,
+}
+
+impl Check {
+    pub fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            result: Ok(detail.into()),
+        }
+    }
+
+    pub fn failed(name: impl Into<String>, error: anyhow::Error) -> Self {
+        Self {
+            name: name.into(),
+            result: Err(error),
+        }
+    }
+}
+
+/// Print the report; returns whether every check passed.
+pub fn print_report(checks: &[Check]) -> bool {
+    for check in checks {
+        match &check.result {
+            Ok(detail) => println!("ok    {}: {detail}", check.name),
+            Err(e) => println!("FAIL  {}: {e:#}", check.name),
+        }
+    }
+    let failed = checks.iter().filter(|c| c.result.is_err()).count();
+    if failed == 0 {
+        println!("configuration OK");
+    } else {
+        println!("{failed} of {} checks failed", checks.len());
+    }
+    failed == 0
+}
+
+/// Connect to each backend like a client connection would and verify it answers `PING` and
+/// reports the expected `ROLE`.
+pub async fn check_backends(
+    cfg: &Config,
+    replicas: &[RedisEndpoint],
+    resolver: &Resolver,
+) -> Vec<Check> {
+    let mut checks = vec![check_backend(cfg, &cfg.master, "master", "master", resolver).await];
+    for replica in replicas {
+        checks.push(check_backend(cfg, replica, "replica", "slave", resolver).await);
+    }
+    checks
+}
+
+async fn check_backend(
+    cfg: &Config,
+    endpoint: &RedisEndpoint,
+    label: &str,
+    expected_role: &str,
+    resolver: &Resolver,
+) -> Check {
+    let name = format!("{label} {}:{}", endpoint.host, endpoint.port);
+    match probe(cfg, endpoint, expected_role, resolver).await {
+        Ok(detail) => Check::ok(name, detail),
+        Err(e) => Check::failed(name, e),
+    }
+}
+
+/// Connect to a backend like a client connection would, with a LOCAL PROXY header as for a health
+/// check, and check that it answers `PING`.
+pub async fn ping(
+    cfg: &Config,
+    endpoint: &RedisEndpoint,
+    resolver: &Resolver,
+) -> Result<RespStream> {
+    let preamble = cfg
+        .send_proxy_protocol
+        .map(|v| crate::proxy_protocol::encode_header(v, &PeerAddr::Unix));
+    let mut conn =
+        connect_and_handshake(endpoint, cfg.connect_timeout, resolver, preamble.as_deref()).await?;
+    let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+    let pong = command(&mut conn, &["PING"], wait).await?;
+    if pong.as_str() != Some("PONG") {
+        return Err(anyhow!("unexpected reply to PING: {pong:?}"));
+    }
+    Ok(conn)
+}
+
+/// Block startup until the master, and a replica if `with_replica`, answer `PING`. Fails once
+/// `deadline` has passed.
+pub async fn wait_for_backends(
+    shared: &Shared,
+    deadline: Duration,
+    with_replica: bool,
+) -> Result<()> {
+    let started = Instant::now();
+    tracing::info!(
+        ?deadline,
+        with_replica,
+        "waiting for backends before listening"
+    );
+    loop {
+        let cfg = shared.config();
+        let (master, replica) = shared.pool.pick();
+        let result = async {
+            let mut conn = ping(&cfg, &master, &shared.resolver)
+                .await
+                .with_context(|| format!("master {}:{}", master.host, master.port))?;
+            let _ = conn.shutdown().await;
+            if with_replica {
+                let replica = replica.ok_or_else(|| anyhow!("no replica discovered yet"))?;
+                let mut conn = ping(&cfg, &replica, &shared.resolver)
+                    .await
+                    .with_context(|| format!("replica {}:{}", replica.host, replica.port))?;
+                let _ = conn.shutdown().await;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                tracing::info!(waited = ?started.elapsed(), "backends are ready");
+                return Ok(());
+            }
+            Err(e) if started.elapsed() + WAIT_RETRY_INTERVAL >= deadline => {
+                return Err(e.context(format!("backends not ready after {deadline:?}")));
+            }
+            Err(e) => tracing::debug!(error = %format!("{e:#}"), "backends not ready yet"),
+        }
+        tokio::time::sleep(WAIT_RETRY_INTERVAL).await;
+    }
+}
+
+/// [`ping`], then check `ROLE`. Returns what was found, e.g. the server version.
+pub async fn probe(
+    cfg: &Config,
+    endpoint: &RedisEndpoint,
+    expected_role: &str,
+    resolver: &Resolver,
+) -> Result<String> {
+    let mut conn = ping(cfg, endpoint, resolver).await?;
+
+    let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+    let role = command(&mut conn, &["ROLE"], wait).await?;
+    let role = role
+        .as_list()
+        .and_then(|items| items.first())
+        .and_then(Reply::as_str)
+        .unwrap_or("unknown")
+        .to_string();
+    if role != expected_role {
+        return Err(anyhow!("ROLE is {role}, expected {expected_role}"));
+    }
+    let version = match query_server_info(&mut conn, wait).await {
+        Ok(info) => format!("Redis {} ({})", info.version, info.mode),
+        Err(_) => "version unknown".to_string(),
+    };
+    let _ = conn.shutdown().await;
+    Ok(format!("PING ok, ROLE {role}, {version}"))
+}
+
+pub async fn command(conn: &mut RespStream, args: &[&str], wait: Duration) -> Result<Reply> {
+    conn.write_all(&encode_command_str(args)).await?;
+    let (frame, _) = timeout(wait, conn.read_frame())
+        .await
+        .with_context(|| format!("{} timeout", args[0]))??
+        .ok_or_else(|| anyhow!("closed before replying to {}", args[0]))?;
+    match Reply::from_frame(&frame) {
+        Reply::Error(e) => Err(anyhow!("{} failed: {e}", args[0])),
+        reply => Ok(reply),
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result, anyhow};
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::config::ListenAddr;
+use crate::resp::{Reply, RespStream, RespVersion, encode_command_str};
+
+/// Minimal RESP client used by the CLI utilities to talk to a running proxy.
+pub struct ProxyClient {
+    stream: RespStream,
+}
+
+impl ProxyClient {
+    pub async fn connect(target: &ListenAddr, connect_timeout: Duration) -> Result<Self> {
+        let stream = match target {
+            ListenAddr::Tcp(addr) => {
+                let sock = timeout(connect_timeout, tokio::net::TcpStream::connect(addr))
+                    .await
+                    .context("connect timeout")?
+                    .with_context(|| format!("Failed to connect to {addr}"))?;
+                sock.set_nodelay(true)?;
+                RespStream::new(sock, RespVersion::Resp2)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let sock = timeout(connect_timeout, tokio::net::UnixStream::connect(path))
+                    .await
+                    .context("connect timeout")?
+                    .with_context(|| format!("Failed to connect to '{}'", path.display()))?;
+                RespStream::new(sock, RespVersion::Resp2)
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                return Err(anyhow!(
+                    "Unix socket targets are not supported on this platform"
+                ));
+            }
+        };
+        Ok(Self { stream })
+    }
+
+    /// Send one command and wait up to `wait` for its reply. Error replies become `Err`.
+    pub async fn command(&mut self, parts: &[&str], wait: Duration) -> Result<Reply> {
+        self.stream.write_all(&encode_command_str(parts)).await?;
+        let (frame, _raw) = timeout(wait, self.stream.read_frame())
+            .await
+            .context("timed out waiting for reply")??
+            .ok_or_else(|| anyhow!("connection closed by proxy"))?;
+        match Reply::from_frame(&frame) {
+            Reply::Error(e) => Err(anyhow!("{e}")),
+            reply => Ok(reply),
+        }
+    }
+
+    pub async fn auth(&mut self, username: Option<&str>, password: &str) -> Result<()> {
+        let wait = Duration::from_secs(5);
+        match username {
+            Some(user) => self.command(&["AUTH", user, password], wait).await?,
+            None => self.command(&["AUTH", password], wait).await?,
+        };
+        Ok(())
+    }
+}
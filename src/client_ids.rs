@@ -0,0 +1,78 @@
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The backend client IDs of proxied clients, for `CLIENT TRACKING ... REDIRECT <id>`.
+///
+/// A client learns its ID from `CLIENT ID`, which its master connection answers. Once that
+/// connection is replaced, e.g. after the master was lost, the ID names no one. So every ID a
+/// client was told keeps naming it: a redirect to one goes to the ID of its current master
+/// connection, until the client disconnects. So does every ID its master connections had, which
+/// redirects already resolved may name.
+#[derive(Debug, Default)]
+pub struct ClientIds {
+    next: AtomicU64,
+    /// The client each backend ID was told to.
+    told: DashMap<u64, u64>,
+    /// The backend ID of the current master connection of each client that asked for one.
+    current: DashMap<u64, u64>,
+}
+
+impl ClientIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A key for a new client connection; forgotten on drop.
+    pub fn register(&self) -> ClientKey<'_> {
+        ClientKey {
+            ids: self,
+            key: self.next.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// The client `key` was told `id` by its master connection.
+    pub fn told(&self, key: u64, id: u64) {
+        self.told.insert(id, key);
+        self.current.insert(key, id);
+    }
+
+    /// The master connection of `key` was replaced by one with `id`.
+    pub fn moved(&self, key: u64, id: u64) {
+        self.told.insert(id, key);
+        self.current.insert(key, id);
+    }
+
+    /// The backend ID a client means by `id`: that of the current master connection of the
+    /// client it was told to, or `id` itself when it wasn't told to one.
+    pub fn resolve(&self, id: u64) -> u64 {
+        self.told
+            .get(&id)
+            .and_then(|key| self.current.get(&*key).map(|current| *current))
+            .unwrap_or(id)
+    }
+
+    fn forget(&self, key: u64) {
+        if self.current.remove(&key).is_some() {
+            self.told.retain(|_, told| *told != key);
+        }
+    }
+}
+
+/// A client connection in [`ClientIds`].
+#[derive(Debug)]
+pub struct ClientKey<'a> {
+    ids: &'a ClientIds,
+    key: u64,
+}
+
+impl ClientKey<'_> {
+    pub fn get(&self) -> u64 {
+        self.key
+    }
+}
+
+impl Drop for ClientKey<'_> {
+    fn drop(&mut self) {
+        self.ids.forget(self.key);
+    }
+}
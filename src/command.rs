@@ -22,6 +22,75 @@ pub enum Request {
     Hello(HelloRequest),
 }
 
+/// The subcommands of `PROXY`, which the proxy answers itself and never forwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxySubcommand {
+    Offset,
+    MinOffset,
+    Consistency,
+    Route,
+    Page,
+    Status,
+    Profile,
+    Slowlog,
+    Config,
+    Whitelist,
+    Replica,
+    Switch,
+    Pause,
+    Unpause,
+}
+
+impl ProxySubcommand {
+    /// The subcommand of a `PROXY` command; `None` if it has none or an unknown one.
+    pub fn parse(cmd: &ParsedCommand) -> Option<Self> {
+        let sub = ascii_upper(cmd.args.first()?);
+        Some(match sub.as_str() {
+            "OFFSET" => Self::Offset,
+            "MINOFFSET" => Self::MinOffset,
+            "CONSISTENCY" => Self::Consistency,
+            "ROUTE" => Self::Route,
+            "PAGE" => Self::Page,
+            "STATUS" => Self::Status,
+            "PROFILE" => Self::Profile,
+            "SLOWLOG" => Self::Slowlog,
+            "CONFIG" => Self::Config,
+            "WHITELIST" => Self::Whitelist,
+            "REPLICA" => Self::Replica,
+            "SWITCH" => Self::Switch,
+            "PAUSE" => Self::Pause,
+            "UNPAUSE" => Self::Unpause,
+            _ => return None,
+        })
+    }
+
+    /// Whether the subcommand acts on the whole proxy, or sees other clients' commands, rather
+    /// than only on the connection running it. Refused where `admin_commands` is off.
+    pub fn is_admin(self) -> bool {
+        !matches!(
+            self,
+            Self::Offset
+                | Self::MinOffset
+                | Self::Consistency
+                | Self::Route
+                | Self::Page
+                | Self::Status
+        )
+    }
+
+    /// Whether `cmd` changes settings or traffic, which read-only clients may not, since that
+    /// could lift `read_only` itself.
+    pub fn changes_settings(self, cmd: &ParsedCommand) -> bool {
+        let second = cmd.args.get(1).map(ascii_upper);
+        match self {
+            Self::Config => second.as_deref() == Some("SET"),
+            Self::Whitelist => matches!(second.as_deref(), Some("ADD" | "REMOVE")),
+            Self::Replica | Self::Switch | Self::Pause | Self::Unpause => true,
+            _ => false,
+        }
+    }
+}
+
 pub fn parse_request(frame: &Frame) -> Result<Request> {
     match frame {
         Frame::Resp2(f) => parse_resp2(f),
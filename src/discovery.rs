@@ -0,0 +1,328 @@
+use anyhow::{Context, Result, anyhow};
+use hickory_resolver::TokioResolver;
+use hickory_resolver::proto::rr::RData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::config::RedisEndpoint;
+use crate::listener::PeerAddr;
+use crate::proxy::{Shared, connect_and_handshake};
+use crate::resp::{Reply, encode_command_str};
+
+/// Backend endpoints that new client connections pick from.
+///
+/// The master and the replica set are swapped together, so a new connection never pairs a new
+/// master with an old replica. Replicas are either static or maintained by discovery. Every
+/// change bumps `generation`, so connections can cheaply notice that their replica may have
+/// gone away.
+#[derive(Debug)]
+pub struct BackendPool {
+    /// Credentials, db and TLS settings applied to discovered replica addresses.
+    template: RedisEndpoint,
+    state: RwLock<Arc<PoolState>>,
+    generation: AtomicU64,
+    next: AtomicUsize,
+    /// Bumped by `PROXY SWITCH ... MIGRATE`: connections older than the switch move to the new
+    /// endpoint too.
+    master_moves: AtomicU64,
+    replica_moves: AtomicU64,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    master: RedisEndpoint,
+    replicas: Vec<RedisEndpoint>,
+}
+
+impl BackendPool {
+    pub fn fixed(master: RedisEndpoint, replicas: Vec<RedisEndpoint>) -> Self {
+        let template = replicas[0].clone();
+        Self::new(master, replicas, template)
+    }
+
+    /// A pool whose replicas are filled in by discovery.
+    pub fn discovered(master: RedisEndpoint, template: RedisEndpoint) -> Self {
+        Self::new(master, Vec::new(), template)
+    }
+
+    fn new(master: RedisEndpoint, replicas: Vec<RedisEndpoint>, template: RedisEndpoint) -> Self {
+        Self {
+            template,
+            state: RwLock::new(Arc::new(PoolState { master, replicas })),
+            generation: AtomicU64::new(0),
+            next: AtomicUsize::new(0),
+            master_moves: AtomicU64::new(0),
+            replica_moves: AtomicU64::new(0),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn master_moves(&self) -> u64 {
+        self.master_moves.load(Ordering::Acquire)
+    }
+
+    pub fn replica_moves(&self) -> u64 {
+        self.replica_moves.load(Ordering::Acquire)
+    }
+
+    /// Master and next replica (round-robin) for a new client connection.
+    pub fn pick(&self) -> (RedisEndpoint, Option<RedisEndpoint>) {
+        let state = self.state();
+        let replica = (!state.replicas.is_empty()).then(|| {
+            let i = self.next.fetch_add(1, Ordering::Relaxed) % state.replicas.len();
+            state.replicas[i].clone()
+        });
+        (state.master.clone(), replica)
+    }
+
+    /// Next replica (round-robin) among those `usable` accepts.
+    pub fn pick_replica(&self, usable: impl Fn(&RedisEndpoint) -> bool) -> Option<RedisEndpoint> {
+        let state = self.state();
+        let candidates: Vec<&RedisEndpoint> = state.replicas.iter().filter(|r| usable(r)).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Some(candidates[i].clone())
+    }
+
+    pub fn replicas(&self) -> Vec<RedisEndpoint> {
+        self.state().replicas.clone()
+    }
+
+    /// The current master, e.g. to reconnect after a failover.
+    pub fn master(&self) -> RedisEndpoint {
+        self.state().master.clone()
+    }
+
+    pub fn contains_replica(&self, host: &str, port: u16) -> bool {
+        self.state()
+            .replicas
+            .iter()
+            .any(|m| m.host == host && m.port == port)
+    }
+
+    fn state(&self) -> Arc<PoolState> {
+        self.state
+            .read()
+            .expect("backend pool lock poisoned")
+            .clone()
+    }
+
+    /// Replace the replicas, keeping the master.
+    pub fn update_replicas(&self, replicas: Vec<RedisEndpoint>) {
+        let master = self.state().master.clone();
+        self.replace(master, replicas);
+    }
+
+    /// Replace master and replicas in one step, logging what changed.
+    pub fn replace(&self, master: RedisEndpoint, replicas: Vec<RedisEndpoint>) {
+        let mut guard = self.state.write().expect("backend pool lock poisoned");
+        let current = guard.clone();
+        let same = |a: &RedisEndpoint, b: &RedisEndpoint| a.host == b.host && a.port == b.port;
+
+        let master_changed = !same(&current.master, &master);
+        let replicas_changed = current.replicas.len() != replicas.len()
+            || !replicas
+                .iter()
+                .all(|m| current.replicas.iter().any(|c| same(c, m)));
+        if !master_changed && !replicas_changed {
+            return;
+        }
+
+        if master_changed {
+            tracing::info!(
+                old = %format!("{}:{}", current.master.host, current.master.port),
+                new = %format!("{}:{}", master.host, master.port),
+                "master changed; applies to new connections"
+            );
+        }
+        for m in &current.replicas {
+            if !replicas.iter().any(|n| same(n, m)) {
+                tracing::info!(host = %m.host, port = m.port, "replica removed from pool");
+            }
+        }
+        for m in &replicas {
+            if !current.replicas.iter().any(|c| same(c, m)) {
+                tracing::info!(host = %m.host, port = m.port, "replica added to pool");
+            }
+        }
+
+        *guard = Arc::new(PoolState { master, replicas });
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Point new connections, and with `migrate` existing ones, at another master.
+    pub fn switch_master(&self, master: RedisEndpoint, migrate: bool) {
+        self.replace(master, self.replicas());
+        if migrate {
+            self.master_moves.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Make `replica` the only replica for new connections, and with `migrate` existing ones.
+    pub fn switch_replica(&self, replica: RedisEndpoint, migrate: bool) {
+        self.replace(self.master(), vec![replica]);
+        if migrate {
+            self.replica_moves.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Periodically resolve the SRV record `name` and keep `pool` in sync with its targets.
+///
+/// Only the targets with the lowest priority are used; weights are ignored and connections are
+/// spread round-robin. Lookup failures keep the previous members, while a name that no longer
+/// has any records empties the pool.
+pub async fn srv_discovery_loop(pool: Arc<BackendPool>, name: String, interval: Duration) {
+    let resolver = match TokioResolver::builder_tokio().and_then(|b| b.build()) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to load DNS configuration; SRV discovery disabled");
+            return;
+        }
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let addrs = match lookup_srv(&resolver, &name).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                tracing::warn!(srv = %name, error = ?e, "SRV lookup failed; keeping current replicas");
+                continue;
+            }
+        };
+        let members = addrs
+            .iter()
+            .map(|(host, port)| pool.template.with_address(host, *port))
+            .collect::<Result<Vec<_>>>();
+        match members {
+            Ok(members) => pool.update_replicas(members),
+            Err(e) => {
+                tracing::warn!(srv = %name, error = ?e, "invalid SRV target; keeping current replicas")
+            }
+        }
+    }
+}
+
+async fn lookup_srv(resolver: &TokioResolver, name: &str) -> Result<Vec<(String, u16)>> {
+    let lookup = match resolver.srv_lookup(name).await {
+        Ok(lookup) => lookup,
+        Err(e) if e.is_no_records_found() || e.is_nx_domain() => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!(e)),
+    };
+
+    let records: Vec<_> = lookup
+        .answers()
+        .iter()
+        .filter_map(|r| match &r.data {
+            RData::SRV(srv) => Some(srv),
+            _ => None,
+        })
+        .collect();
+    let Some(best) = records.iter().map(|srv| srv.priority).min() else {
+        return Ok(Vec::new());
+    };
+
+    let mut addrs: Vec<(String, u16)> = records
+        .iter()
+        .filter(|srv| srv.priority == best)
+        .map(|srv| {
+            let host = srv.target.to_utf8();
+            (host.trim_end_matches('.').to_string(), srv.port)
+        })
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+/// Periodically resolve every address behind the replica hostname (e.g. a Kubernetes headless
+/// service) and keep the pods that answer `ROLE` as a replica.
+///
+/// Pods are connected by IP with the replica URL's credentials, db and TLS settings; the TLS
+/// server name stays the service name. Scaling the StatefulSet is picked up on the next round.
+pub async fn headless_discovery_loop(shared: Arc<Shared>, interval: Duration) {
+    let pool = shared.pool.clone();
+    let (service, port) = (pool.template.host.clone(), pool.template.port);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let addrs = match shared.resolver.resolve(&service, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                tracing::warn!(%service, error = ?e, "headless service lookup failed; keeping current replicas");
+                continue;
+            }
+        };
+
+        let mut probes = JoinSet::new();
+        for addr in addrs {
+            let mut endpoint = pool.template.clone();
+            endpoint.host = addr.ip().to_string();
+            endpoint.port = addr.port();
+            let shared = shared.clone();
+            probes.spawn(async move {
+                match probe_replica(&shared, &endpoint).await {
+                    Ok(true) => Some(endpoint),
+                    Ok(false) => {
+                        tracing::debug!(host = %endpoint.host, "pod is not a replica; skipping");
+                        None
+                    }
+                    Err(e) => {
+                        tracing::debug!(host = %endpoint.host, error = ?e, "pod unreachable; skipping");
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut members = Vec::new();
+        while let Some(res) = probes.join_next().await {
+            if let Ok(Some(endpoint)) = res {
+                members.push(endpoint);
+            }
+        }
+        members.sort_by(|a, b| (&a.host, a.port).cmp(&(&b.host, b.port)));
+        pool.update_replicas(members);
+    }
+}
+
+/// Whether `endpoint` reports itself as a replica (`ROLE` starting with `slave`).
+async fn probe_replica(shared: &Shared, endpoint: &RedisEndpoint) -> Result<bool> {
+    let cfg = shared.config();
+    // Backends expecting a PROXY header get a LOCAL one, as for a health check.
+    let preamble = cfg
+        .send_proxy_protocol
+        .map(|v| crate::proxy_protocol::encode_header(v, &PeerAddr::Unix));
+    let mut conn = connect_and_handshake(
+        endpoint,
+        cfg.connect_timeout,
+        &shared.resolver,
+        preamble.as_deref(),
+    )
+    .await?;
+
+    conn.write_all(&encode_command_str(&["ROLE"])).await?;
+    let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+    let reply = timeout(wait, conn.read_frame())
+        .await
+        .context("ROLE timeout")??
+        .ok_or_else(|| anyhow!("closed before replying to ROLE"))?;
+    let _ = conn.shutdown().await;
+
+    let role = Reply::from_frame(&reply.0);
+    Ok(role
+        .as_list()
+        .and_then(|items| items.first())
+        .and_then(Reply::as_str)
+        == Some("slave"))
+}
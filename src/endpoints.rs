@@ -0,0 +1,95 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{FileBackends, RedisEndpoint};
+use crate::discovery::BackendPool;
+
+/// Contents of `--endpoints-file`, e.g.
+/// `{"master": "redis://m:6379", "replicas": ["redis://r1:6379", "redis://r2:6379"]}`.
+///
+/// `replica` (a single URL) and `replicas` may be combined; at least one replica is required.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndpointsFile {
+    master: String,
+    #[serde(default)]
+    replica: Option<String>,
+    #[serde(default)]
+    replicas: Vec<String>,
+}
+
+/// Backend endpoints loaded from an endpoints file.
+#[derive(Debug)]
+pub struct Endpoints {
+    pub master: RedisEndpoint,
+    pub replicas: Vec<RedisEndpoint>,
+}
+
+impl Endpoints {
+    /// Load the file, applying the config file's `[backends]` settings to its endpoints.
+    pub fn load(path: &Path, settings: &FileBackends) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read endpoints file '{}'", path.display()))?;
+        let file: EndpointsFile = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid endpoints file '{}'", path.display()))?;
+
+        let mut master = RedisEndpoint::from_redis_url(&file.master)?;
+        master.apply_settings(&settings.master);
+        let replicas = file
+            .replica
+            .iter()
+            .chain(&file.replicas)
+            .map(|url| {
+                let mut replica = RedisEndpoint::from_redis_url(url)?;
+                replica.apply_settings(&settings.replica);
+                Ok(replica)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if replicas.is_empty() {
+            return Err(anyhow!(
+                "Endpoints file '{}' lists no replica",
+                path.display()
+            ));
+        }
+        Ok(Self { master, replicas })
+    }
+}
+
+/// Poll the endpoints file and apply changes to `pool`; they take effect for new connections.
+///
+/// A file that fails to load or parse is skipped with a warning, keeping the current endpoints.
+pub async fn watch_endpoints_file(
+    pool: Arc<BackendPool>,
+    path: PathBuf,
+    interval: Duration,
+    settings: FileBackends,
+) {
+    let mut stamp = modified(&path);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let now = modified(&path);
+        if now == stamp {
+            continue;
+        }
+        // Recorded before loading so a broken file is reported once, not on every tick.
+        stamp = now;
+
+        match Endpoints::load(&path, &settings) {
+            Ok(endpoints) => {
+                tracing::info!(path = %path.display(), "endpoints file changed");
+                pool.replace(endpoints.master, endpoints.replicas);
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, "failed to reload endpoints file; keeping current endpoints")
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
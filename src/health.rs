@@ -0,0 +1,608 @@
+use anyhow::{Context, Result, anyhow};
+use dashmap::DashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::config::{Config, RedisEndpoint};
+use crate::offsets::{master_offset, replica_offset};
+use crate::proxy::Shared;
+use crate::resp::RespStream;
+
+/// How often a disabled health check looks again whether a reload enabled it.
+const HEALTH_CHECK_IDLE: Duration = Duration::from_secs(1);
+/// Check interval when only `max_replica_lag*` asks for checks.
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Master offsets kept to date replica offsets; older lag is reported as the oldest sample's age.
+const MASTER_OFFSET_SAMPLES: usize = 3600;
+/// How often [`loading_probe_loop`] asks a loading replica whether it is done.
+const LOADING_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+const HALF_OPEN: u8 = 2;
+
+/// Process-wide circuit breaker for replica reads.
+///
+/// Every client connection finds out about a dead replica on its own, each paying a timeout. The
+/// breaker counts those failures across connections: after `replica_breaker_threshold` in a row
+/// it opens and every read goes to master, without dialing or waiting for the replica. After
+/// `replica_breaker_cooldown` [`probe_loop`] moves it to half-open and checks the replica once;
+/// if it answers, the breaker closes and connections use the replica again.
+#[derive(Debug, Default)]
+pub struct ReplicaBreaker {
+    state: AtomicU8,
+    /// Replica failures since the last success.
+    failures: AtomicU32,
+    tripped: Notify,
+}
+
+impl ReplicaBreaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether connections may read from, or dial, the replica.
+    pub fn allows_replica(&self) -> bool {
+        self.state.load(Ordering::Acquire) == CLOSED
+    }
+
+    /// A connection's replica failed or timed out. `threshold` `None` never opens the breaker.
+    pub fn record_failure(&self, threshold: Option<u32>) {
+        let failures = self
+            .failures
+            .fetch_add(1, Ordering::AcqRel)
+            .saturating_add(1);
+        if threshold.is_some_and(|t| failures >= t)
+            && self
+                .state
+                .compare_exchange(CLOSED, OPEN, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            tracing::warn!(
+                failures,
+                "replica circuit breaker open; serving every read from master"
+            );
+            self.tripped.notify_one();
+        }
+    }
+
+    /// A connection's replica answered.
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Release);
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.state.load(Ordering::Acquire) {
+            CLOSED => "closed",
+            OPEN => "open",
+            _ => "half-open",
+        }
+    }
+}
+
+/// Whenever the breaker opens, wait out the cooldown and probe the replica with `PING` until it
+/// answers, then close the breaker.
+pub async fn probe_loop(shared: Arc<Shared>) {
+    let breaker = &shared.replica_breaker;
+    loop {
+        breaker.tripped.notified().await;
+        shared.pair.set_replica_up(false);
+        loop {
+            tokio::time::sleep(shared.config().replica_breaker_cooldown).await;
+            breaker.state.store(HALF_OPEN, Ordering::Release);
+
+            let cfg = shared.config();
+            let probed = match shared.pool.pick().1 {
+                Some(replica) => crate::check::ping(&cfg, &replica, &shared.resolver).await,
+                None => Err(anyhow::anyhow!("no replica in the pool")),
+            };
+            match probed {
+                Ok(mut conn) => {
+                    let _ = conn.shutdown().await;
+                    breaker.failures.store(0, Ordering::Release);
+                    breaker.state.store(CLOSED, Ordering::Release);
+                    shared.pair.set_replica_up(true);
+                    tracing::info!("replica answered the half-open probe; circuit breaker closed");
+                    break;
+                }
+                Err(e) => {
+                    breaker.state.store(OPEN, Ordering::Release);
+                    tracing::debug!(error = %format!("{e:#}"), "replica probe failed; circuit breaker stays open");
+                }
+            }
+        }
+    }
+}
+
+/// What the background health check last saw of each replica in the pool.
+///
+/// Connections skip a replica that is down or lagging when they dial one, and stop reading from
+/// the one they dialed while it is, so that no client has to time out or read stale data to find
+/// out. Replicas that haven't been checked yet count as usable.
+///
+/// `PROXY REPLICA DRAIN` takes a replica out of use the same way, so that it can be restarted,
+/// and so does a replica answering `-LOADING` until it has loaded its dataset.
+#[derive(Debug, Default)]
+pub struct ReplicaHealth {
+    checks: DashMap<(String, u16), ReplicaCheck>,
+    /// Replicas that answered a read with `-LOADING`, and since when; see [`loading_probe_loop`].
+    loading: DashMap<(String, u16), Instant>,
+    /// Replicas drained one by one: whether they still are, and how many times they were.
+    drained: DashMap<(String, u16), (bool, u64)>,
+    /// Set while every replica is drained.
+    all_drained: AtomicBool,
+    /// Drains of every replica so far.
+    all_drains: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReplicaCheck {
+    status: CheckStatus,
+    /// Round trip of the last answered `PING`.
+    latency: Option<Duration>,
+    /// Unknown while lag limits are off or an offset couldn't be read.
+    lag: Option<Lag>,
+    at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Up,
+    Down,
+    /// Answers, but is further behind the master than `max_replica_lag*` allows.
+    Lagging,
+}
+
+impl CheckStatus {
+    fn name(self) -> &'static str {
+        match self {
+            CheckStatus::Up => "up",
+            CheckStatus::Down => "down",
+            CheckStatus::Lagging => "lagging",
+        }
+    }
+}
+
+/// How far a replica is behind the master.
+#[derive(Debug, Clone, Copy)]
+struct Lag {
+    bytes: u64,
+    /// Time since the master first had data the replica still lacks, to within a check
+    /// interval.
+    age: Duration,
+}
+
+impl ReplicaHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_usable(&self, host: &str, port: u16) -> bool {
+        self.is_healthy(host, port)
+            && !self.is_drained(host, port)
+            && !self.loading.contains_key(&(host.to_string(), port))
+    }
+
+    /// The replica at `host:port` answered `-LOADING`, e.g. during a full resync. Unlike a
+    /// failure this keeps no connection from it: reads avoid it until it is done loading.
+    pub fn mark_loading(&self, host: &str, port: u16) {
+        let key = (host.to_string(), port);
+        if !self.loading.contains_key(&key) {
+            tracing::warn!(
+                %host,
+                port,
+                "replica is loading its dataset; reads avoid it until it is done"
+            );
+            self.loading.entry(key).or_insert_with(Instant::now);
+        }
+    }
+
+    fn is_healthy(&self, host: &str, port: u16) -> bool {
+        self.checks
+            .get(&(host.to_string(), port))
+            .is_none_or(|c| c.status == CheckStatus::Up)
+    }
+
+    pub fn is_drained(&self, host: &str, port: u16) -> bool {
+        self.all_drained.load(Ordering::Acquire)
+            || self
+                .drained
+                .get(&(host.to_string(), port))
+                .is_some_and(|d| d.0)
+    }
+
+    /// Stop reads to the replica at `addr`, or to every replica, until [`ReplicaHealth::resume`].
+    /// Returns whether it wasn't drained already.
+    pub fn drain(&self, addr: Option<(String, u16)>) -> bool {
+        match addr {
+            Some(addr) => {
+                let mut drain = self.drained.entry(addr).or_default();
+                let newly = !drain.0;
+                if newly {
+                    *drain = (true, drain.1 + 1);
+                }
+                newly
+            }
+            None => {
+                let newly = !self.all_drained.swap(true, Ordering::AcqRel);
+                if newly {
+                    self.all_drains.fetch_add(1, Ordering::AcqRel);
+                }
+                newly
+            }
+        }
+    }
+
+    /// End the drain of the replica at `addr`, or every drain. Returns whether there was one.
+    pub fn resume(&self, addr: Option<(String, u16)>) -> bool {
+        match addr {
+            Some(addr) => self
+                .drained
+                .get_mut(&addr)
+                .is_some_and(|mut d| std::mem::replace(&mut d.0, false)),
+            None => {
+                let mut any = self.all_drained.swap(false, Ordering::AcqRel);
+                for mut entry in self.drained.iter_mut() {
+                    any |= std::mem::replace(&mut entry.value_mut().0, false);
+                }
+                any
+            }
+        }
+    }
+
+    /// A count that goes up whenever the replica at `host:port` is drained. A connection that
+    /// dialed it under a lower count closes its connection, so that none is left open across a
+    /// restart of the replica, even when the drain is over before the connection's next command.
+    pub fn drains(&self, host: &str, port: u16) -> u64 {
+        let own = self
+            .drained
+            .get(&(host.to_string(), port))
+            .map_or(0, |d| d.1);
+        own + self.all_drains.load(Ordering::Acquire)
+    }
+
+    /// Record a check, logging when the replica's status changes.
+    fn record(
+        &self,
+        endpoint: &RedisEndpoint,
+        result: Result<Probe>,
+        lag: Option<Lag>,
+        cfg: &Config,
+    ) {
+        let key = (endpoint.host.clone(), endpoint.port);
+        let was = self.checks.get(&key).map_or(CheckStatus::Up, |c| c.status);
+        let too_far = lag.is_some_and(|lag| {
+            cfg.max_replica_lag_bytes.is_some_and(|max| lag.bytes > max)
+                || cfg.max_replica_lag.is_some_and(|max| lag.age > max)
+        });
+        let (status, latency) = match &result {
+            Ok(probe) if too_far => (CheckStatus::Lagging, Some(probe.latency)),
+            Ok(probe) => (CheckStatus::Up, Some(probe.latency)),
+            Err(_) => (CheckStatus::Down, None),
+        };
+        if status != was {
+            let (host, port) = (&endpoint.host, endpoint.port);
+            match (status, &result) {
+                (CheckStatus::Down, Err(e)) => tracing::warn!(
+                    %host,
+                    port,
+                    error = %format!("{e:#}"),
+                    "replica failed its health check; reads avoid it"
+                ),
+                (CheckStatus::Lagging, _) => tracing::warn!(
+                    %host,
+                    port,
+                    lag_bytes = lag.map(|l| l.bytes),
+                    lag_ms = lag.map(|l| l.age.as_millis() as u64),
+                    "replica lags too far behind master; reads avoid it"
+                ),
+                _ => tracing::info!(
+                    %host,
+                    port,
+                    ?latency,
+                    "replica passed its health check again"
+                ),
+            }
+        }
+        self.checks.insert(
+            key,
+            ReplicaCheck {
+                status,
+                latency,
+                lag,
+                at: Instant::now(),
+            },
+        );
+    }
+
+    /// Forget replicas that left the pool.
+    fn retain(&self, replicas: &[RedisEndpoint]) {
+        self.checks
+            .retain(|(host, port), _| replicas.iter().any(|r| &r.host == host && r.port == *port));
+    }
+
+    /// INFO-style lines for `PROXY STATUS`, one per checked replica.
+    pub fn render(&self) -> String {
+        let mut lines: Vec<String> = self
+            .checks
+            .iter()
+            .map(|entry| {
+                let ((host, port), check) = entry.pair();
+                let mut line = format!(
+                    "replica_health:{host}:{port},{},latency_us={},checked_ago_ms={}",
+                    check.status.name(),
+                    check.latency.map_or(-1, |l| l.as_micros() as i64),
+                    check.at.elapsed().as_millis(),
+                );
+                if let Some(lag) = check.lag {
+                    line.push_str(&format!(
+                        ",lag_bytes={},lag_ms={}",
+                        lag.bytes,
+                        lag.age.as_millis()
+                    ));
+                }
+                line + "\r\n"
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    /// The `replica_drained:` line for `PROXY STATUS`: `all`, the drained replicas, or empty.
+    pub fn render_drained(&self) -> String {
+        let drained = if self.all_drained.load(Ordering::Acquire) {
+            "all".to_string()
+        } else {
+            let mut addrs: Vec<String> = self
+                .drained
+                .iter()
+                .filter(|d| d.value().0)
+                .map(|d| format!("{}:{}", d.key().0, d.key().1))
+                .collect();
+            addrs.sort();
+            addrs.join(",")
+        };
+        format!("replica_drained:{drained}\r\n")
+    }
+
+    /// The `replica_loading:` line for `PROXY STATUS`: the replicas still loading, or empty.
+    pub fn render_loading(&self) -> String {
+        let mut addrs: Vec<String> = self
+            .loading
+            .iter()
+            .map(|l| format!("{}:{}", l.key().0, l.key().1))
+            .collect();
+        addrs.sort();
+        format!("replica_loading:{}\r\n", addrs.join(","))
+    }
+}
+
+/// Ask each replica that answered `-LOADING` whether it still is loading, until it is done and
+/// reads may use it again. A replica that left the pool meanwhile is forgotten.
+pub async fn loading_probe_loop(shared: Arc<Shared>) {
+    let health = &shared.replica_health;
+    loop {
+        tokio::time::sleep(LOADING_PROBE_INTERVAL).await;
+        let loading: Vec<(String, u16)> = health.loading.iter().map(|l| l.key().clone()).collect();
+        for key in loading {
+            let Some(endpoint) = shared
+                .pool
+                .replicas()
+                .into_iter()
+                .find(|r| (&r.host, r.port) == (&key.0, key.1))
+            else {
+                health.loading.remove(&key);
+                continue;
+            };
+            match still_loading(&shared, &endpoint).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Some((_, since)) = health.loading.remove(&key) {
+                        tracing::info!(
+                            host = %key.0,
+                            port = key.1,
+                            loading_ms = since.elapsed().as_millis() as u64,
+                            "replica finished loading; reads use it again"
+                        );
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!(host = %key.0, port = key.1, error = %format!("{e:#}"), "loading replica probe failed")
+                }
+            }
+        }
+    }
+}
+
+/// Whether `INFO persistence` says the replica is still loading its dataset.
+async fn still_loading(shared: &Shared, endpoint: &RedisEndpoint) -> Result<bool> {
+    let cfg = shared.config();
+    let mut conn = crate::check::ping(&cfg, endpoint, &shared.resolver).await?;
+    let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+    let info = crate::check::command(&mut conn, &["INFO", "persistence"], wait).await;
+    let _ = conn.shutdown().await;
+    let info = info?;
+    let text = info
+        .as_str()
+        .ok_or_else(|| anyhow!("unexpected reply to INFO: {info:?}"))?;
+    Ok(text.lines().any(|line| line.trim_end() == "loading:1"))
+}
+
+/// A replica's answer to one health check.
+struct Probe {
+    latency: Duration,
+    /// `slave_repl_offset`, read while lag limits are set.
+    offset: Option<u64>,
+}
+
+/// The master's replication offset at each check, oldest first, to tell how old a replica's
+/// offset is.
+#[derive(Debug, Default)]
+struct MasterOffsets {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl MasterOffsets {
+    fn push(&mut self, offset: u64) {
+        // A lower offset is a new replication history, e.g. after a failover.
+        if self.samples.back().is_some_and(|(_, last)| offset < *last) {
+            self.samples.clear();
+        }
+        if self.samples.len() == MASTER_OFFSET_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((Instant::now(), offset));
+    }
+
+    fn lag(&self, replica_offset: u64) -> Option<Lag> {
+        let &(_, latest) = self.samples.back()?;
+        let age = self
+            .samples
+            .iter()
+            .find(|(_, offset)| *offset > replica_offset)
+            .map_or(Duration::ZERO, |(at, _)| at.elapsed());
+        Some(Lag {
+            bytes: latest.saturating_sub(replica_offset),
+            age,
+        })
+    }
+}
+
+/// `PING` every replica in the pool each `replica_health_interval` and publish the results in
+/// [`ReplicaHealth`] and the pair status. With `max_replica_lag*` set, also compare each
+/// replica's replication offset to the master's. Each backend keeps one connection for the
+/// checks, dialed again after a failure.
+pub async fn health_check_loop(shared: Arc<Shared>) {
+    let mut conns: HashMap<(String, u16), RespStream> = HashMap::new();
+    let mut master_conn: Option<((String, u16), RespStream)> = None;
+    let mut master_offsets = MasterOffsets::default();
+    loop {
+        let cfg = shared.config();
+        let lag_limits = cfg.max_replica_lag_bytes.is_some() || cfg.max_replica_lag.is_some();
+        let Some(interval) = cfg
+            .replica_health_interval
+            .or(lag_limits.then_some(LAG_CHECK_INTERVAL))
+        else {
+            conns.clear();
+            master_conn = None;
+            tokio::time::sleep(HEALTH_CHECK_IDLE).await;
+            continue;
+        };
+
+        let mut master_offset_known = false;
+        if lag_limits {
+            let master = shared.pool.master();
+            let key = (master.host.clone(), master.port);
+            let conn = master_conn
+                .take()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, c)| c);
+            match check_master_offset(&shared, &master, conn).await {
+                Ok((conn, offset)) => {
+                    master_conn = Some((key, conn));
+                    master_offsets.push(offset);
+                    master_offset_known = true;
+                }
+                Err(e) => {
+                    tracing::debug!(error = %format!("{e:#}"), "could not read master offset; replica lag unknown");
+                }
+            }
+        }
+
+        let replicas = shared.pool.replicas();
+        shared.replica_health.retain(&replicas);
+        let mut checks = JoinSet::new();
+        for endpoint in replicas {
+            let conn = conns.remove(&(endpoint.host.clone(), endpoint.port));
+            let shared = shared.clone();
+            checks.spawn(async move {
+                let checked = check_replica(&shared, &endpoint, conn, lag_limits).await;
+                (endpoint, checked)
+            });
+        }
+        let mut any_up = false;
+        while let Some(joined) = checks.join_next().await {
+            let Ok((endpoint, checked)) = joined else {
+                continue;
+            };
+            let result = checked.map(|(conn, probe)| {
+                conns.insert((endpoint.host.clone(), endpoint.port), conn);
+                probe
+            });
+            let lag = match &result {
+                Ok(Probe {
+                    offset: Some(offset),
+                    ..
+                }) if master_offset_known => master_offsets.lag(*offset),
+                _ => None,
+            };
+            shared.replica_health.record(&endpoint, result, lag, &cfg);
+            any_up |= shared
+                .replica_health
+                .is_healthy(&endpoint.host, endpoint.port);
+        }
+        shared.pair.set_replica_up(any_up);
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// One `PING` over the replica's check connection, dialing it first if there is none, and its
+/// replication offset if `with_offset`. Returns the connection for the next check.
+async fn check_replica(
+    shared: &Shared,
+    endpoint: &RedisEndpoint,
+    conn: Option<RespStream>,
+    with_offset: bool,
+) -> Result<(RespStream, Probe)> {
+    let cfg = shared.config();
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => crate::check::ping(&cfg, endpoint, &shared.resolver).await?,
+    };
+    let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+    let started = Instant::now();
+    let pong = crate::check::command(&mut conn, &["PING"], wait).await?;
+    if pong.as_str() != Some("PONG") {
+        return Err(anyhow!("unexpected reply to PING: {pong:?}"));
+    }
+    let latency = started.elapsed();
+    let offset = if with_offset {
+        match timeout(wait, replica_offset(&mut conn))
+            .await
+            .context("INFO timeout")??
+        {
+            Ok(offset) => Some(offset),
+            Err(e) => {
+                tracing::debug!(host = %endpoint.host, port = endpoint.port, error = %e, "could not read replica offset");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    Ok((conn, Probe { latency, offset }))
+}
+
+async fn check_master_offset(
+    shared: &Shared,
+    master: &RedisEndpoint,
+    conn: Option<RespStream>,
+) -> Result<(RespStream, u64)> {
+    let cfg = shared.config();
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => crate::check::ping(&cfg, master, &shared.resolver).await?,
+    };
+    let wait = master.read_timeout.unwrap_or(cfg.connect_timeout);
+    let offset = timeout(wait, master_offset(&mut conn))
+        .await
+        .context("INFO timeout")??
+        .map_err(|e| anyhow!(e))?;
+    Ok((conn, offset))
+}
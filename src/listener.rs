@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::config::{Config, FileListener, ListenAddr, ProxyAuth};
+use crate::resp::AsyncStream;
+
+/// Pending connections per listening socket; what `TcpListener::bind` uses.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Client-facing listener: TCP or, on Unix, a Unix domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Address of an accepted client, for logs and PROXY headers sent to backends.
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+    /// `local` is the proxy address the client connected to.
+    Tcp {
+        addr: SocketAddr,
+        local: SocketAddr,
+    },
+    Unix,
+}
+
+impl std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp { addr, .. } => write!(f, "{addr}"),
+            PeerAddr::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(a) => Ok(Listener::Tcp(TcpListener::bind(a).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // A socket file left behind by a previous run would make bind fail.
+                if path.exists() {
+                    std::fs::remove_file(path).with_context(|| {
+                        format!("Failed to remove stale socket '{}'", path.display())
+                    })?;
+                }
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind '{}'", path.display()))?;
+                Ok(Listener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(anyhow::anyhow!(
+                "Unix socket listeners are not supported on this platform"
+            )),
+        }
+    }
+
+    /// `workers` sockets bound to the same TCP address with `SO_REUSEPORT`, so that the kernel
+    /// spreads new connections across their accept loops. A Unix socket, or a single worker,
+    /// is bound once as by [`Listener::bind`].
+    pub async fn bind_workers(addr: &ListenAddr, workers: usize) -> Result<Vec<Self>> {
+        let ListenAddr::Tcp(a) = addr else {
+            return Ok(vec![Self::bind(addr).await?]);
+        };
+        if workers <= 1 {
+            return Ok(vec![Self::bind(addr).await?]);
+        }
+        let first = reuseport_listener(*a).with_context(|| format!("Failed to bind {a}"))?;
+        // Port 0 picks a port for the first socket; the others must share it.
+        let bound = first.local_addr()?;
+        let mut listeners = vec![Listener::Tcp(first)];
+        for _ in 1..workers {
+            let listener =
+                reuseport_listener(bound).with_context(|| format!("Failed to bind {bound}"))?;
+            listeners.push(Listener::Tcp(listener));
+        }
+        Ok(listeners)
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn AsyncStream>, PeerAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (sock, addr) = l.accept().await?;
+                sock.set_nodelay(true)?;
+                let local = sock.local_addr()?;
+                Ok((Box::new(sock), PeerAddr::Tcp { addr, local }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let (sock, _) = l.accept().await?;
+                Ok((Box::new(sock), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn reuseport_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn reuseport_listener(_addr: SocketAddr) -> std::io::Result<TcpListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--workers above 1 needs SO_REUSEPORT, which this platform lacks",
+    ))
+}
+
+/// How clients of one listener are treated; the main listener uses the global settings.
+#[derive(Debug)]
+pub struct ListenerPolicy {
+    pub addr: ListenAddr,
+    /// Replaces the global proxy credentials on this listener.
+    pub auth: Option<ProxyAuth>,
+    /// `false` keeps clients off the replica: every command goes to master.
+    pub replica_reads: bool,
+    /// Replaces the global `read_only` on this listener.
+    pub read_only: Option<bool>,
+    /// Replaces the global `admin_commands` on this listener.
+    pub admin_commands: Option<bool>,
+    /// `--admin-listen`: only `PROXY STATUS` and admin subcommands are served.
+    pub admin_only: bool,
+    pub max_connections: Option<usize>,
+    active: AtomicUsize,
+}
+
+impl ListenerPolicy {
+    /// The global policy.
+    pub fn new(addr: ListenAddr) -> Self {
+        Self {
+            addr,
+            auth: None,
+            replica_reads: true,
+            read_only: None,
+            admin_commands: None,
+            admin_only: false,
+            max_connections: None,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn from_file(listener: &FileListener) -> Result<Self> {
+        let addr = listener
+            .listen
+            .parse()
+            .context("Invalid `listen` in [[listeners]]")?;
+        let auth = listener.password.as_ref().map(|password| ProxyAuth {
+            enabled: true,
+            username: listener
+                .username
+                .clone()
+                .unwrap_or_else(|| "default".to_string()),
+            password: password.clone(),
+        });
+        Ok(Self {
+            auth,
+            replica_reads: listener.replica_reads.unwrap_or(true),
+            read_only: listener.read_only,
+            admin_commands: listener.admin_commands,
+            max_connections: listener.max_connections,
+            ..Self::new(addr)
+        })
+    }
+
+    /// The `--admin-listen` policy.
+    pub fn admin(addr: ListenAddr, auth: Option<ProxyAuth>) -> Self {
+        Self {
+            auth,
+            replica_reads: false,
+            admin_commands: Some(true),
+            admin_only: true,
+            ..Self::new(addr)
+        }
+    }
+
+    /// Proxy credentials that clients of this listener must present.
+    pub fn auth<'a>(&'a self, cfg: &'a Config) -> &'a ProxyAuth {
+        self.auth.as_ref().unwrap_or(&cfg.proxy_auth)
+    }
+
+    /// Whether clients of this listener are refused writes.
+    pub fn read_only(&self, cfg: &Config) -> bool {
+        self.read_only.unwrap_or(cfg.read_only)
+    }
+
+    /// Whether clients of this listener may run admin `PROXY` subcommands.
+    pub fn admin_commands(&self, cfg: &Config) -> bool {
+        self.admin_commands.unwrap_or(cfg.admin_commands)
+    }
+
+    /// Count a new client against `max_connections`. `None` means the listener is full.
+    pub fn admit(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let active = self.active.fetch_add(1, Ordering::AcqRel);
+        let slot = ConnectionSlot(self.clone());
+        match self.max_connections {
+            Some(max) if active >= max => None,
+            _ => Some(slot),
+        }
+    }
+}
+
+/// A client counted against its listener's `max_connections`; released on drop.
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<ListenerPolicy>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
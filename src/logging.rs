@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// The installed log filter, which can be swapped while running.
+#[derive(Debug)]
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogControl {
+    /// Install the log subscriber, filtered by [`filter_for`].
+    pub fn init(file_level: Option<&str>) -> Result<Self> {
+        let (filter, handle) = reload::Layer::new(
+            filter_for(file_level).context("Invalid `log_level` in config file")?,
+        );
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer())
+            .init();
+        // Log panics like other events, so that they carry the span of the connection that
+        // panicked.
+        std::panic::set_hook(Box::new(|info| {
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("(non-string panic payload)");
+            let location = info
+                .location()
+                .map(|l| format!("{}:{}", l.file(), l.line()))
+                .unwrap_or_default();
+            tracing::error!(%location, "panic: {message}");
+        }));
+        Ok(Self { handle })
+    }
+
+    /// Current filter directives, e.g. `info`.
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|f| f.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Replace the filter with `directives`, e.g. `debug` or `redis_rwproxy=debug`.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)
+            .with_context(|| format!("Invalid log filter '{directives}'"))?;
+        self.apply(filter)
+    }
+
+    /// Re-apply [`filter_for`] after the config file changed. Returns whether the filter changed.
+    pub fn reload_from_file(&self, file_level: Option<&str>) -> Result<bool> {
+        let filter = filter_for(file_level)?;
+        if filter.to_string() == self.current() {
+            return Ok(false);
+        }
+        self.apply(filter)?;
+        Ok(true)
+    }
+
+    fn apply(&self, filter: EnvFilter) -> Result<()> {
+        self.handle
+            .reload(filter)
+            .context("Failed to apply log filter")
+    }
+}
+
+/// `RUST_LOG` if set, otherwise the config file's `log_level`, otherwise `info`.
+fn filter_for(file_level: Option<&str>) -> Result<EnvFilter> {
+    if let Ok(filter) = EnvFilter::try_from_default_env() {
+        return Ok(filter);
+    }
+    Ok(EnvFilter::try_new(file_level.unwrap_or("info"))?)
+}
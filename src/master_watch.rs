@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::proxy::Shared;
+
+/// First pause before redialing an unreachable master; doubles up to the max.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Whether the master is known to be unreachable.
+///
+/// When a client fails to dial the master, the others stop dialing it and answer `-MASTERDOWN`
+/// right away, while [`reconnect_loop`] alone retries it until it is back.
+#[derive(Debug, Default)]
+pub struct MasterWatch {
+    down: AtomicBool,
+    wake: Notify,
+}
+
+impl MasterWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Acquire)
+    }
+
+    /// A client failed to dial the master.
+    pub fn report_down(&self) {
+        if !self.down.swap(true, Ordering::AcqRel) {
+            tracing::warn!("master unreachable; answering MASTERDOWN until it is back");
+            self.wake.notify_one();
+        }
+    }
+}
+
+/// Redial the master with backoff whenever it is reported down, and let clients dial it again
+/// once it answers `PING`.
+pub async fn reconnect_loop(shared: Arc<Shared>) {
+    let watch = &shared.master_watch;
+    loop {
+        watch.wake.notified().await;
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            tokio::time::sleep(backoff).await;
+            let cfg = shared.config();
+            let master = shared.pool.master();
+            match crate::check::ping(&cfg, &master, &shared.resolver).await {
+                Ok(mut conn) => {
+                    let _ = conn.shutdown().await;
+                    watch.down.store(false, Ordering::Release);
+                    shared.pair.set_master_up(true);
+                    tracing::info!(host = %master.host, port = master.port, "master reachable again");
+                    break;
+                }
+                Err(e) => {
+                    tracing::debug!(error = %format!("{e:#}"), ?backoff, "master still unreachable");
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
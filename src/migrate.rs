@@ -0,0 +1,79 @@
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+use toml_edit::DocumentMut;
+
+/// Schema version of the config file understood by this release, stored as the top-level
+/// `version` key. Bump it together with a new entry in [`MIGRATIONS`] whenever a key is renamed,
+/// moved or changes meaning.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` file to version `n + 1`. Files without `version`
+/// predate versioning and are version 0.
+const MIGRATIONS: &[fn(&mut DocumentMut) -> Result<()>] = &[
+    // 0 -> 1: versioning introduced; no keys changed.
+    |_| Ok(()),
+];
+
+/// The `version` of a parsed config file.
+pub fn file_version(doc: &DocumentMut) -> Result<u32> {
+    let Some(item) = doc.get("version") else {
+        return Ok(0);
+    };
+    let version = item
+        .as_integer()
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| anyhow!("`version` must be a non-negative integer"))?;
+    if version > CONFIG_VERSION {
+        return Err(anyhow!(
+            "config file version {version} is newer than this release supports ({CONFIG_VERSION})"
+        ));
+    }
+    Ok(version)
+}
+
+/// Upgrade `doc` to [`CONFIG_VERSION`], keeping comments and formatting of untouched keys.
+/// Returns the version it was upgraded from, or `None` if it was already current.
+pub fn upgrade(doc: &mut DocumentMut) -> Result<Option<u32>> {
+    let from = file_version(doc)?;
+    if from == CONFIG_VERSION {
+        return Ok(None);
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(doc).with_context(|| {
+            format!(
+                "failed to migrate from version {version} to {}",
+                version + 1
+            )
+        })?;
+    }
+    stamp_version(doc)?;
+    Ok(Some(from))
+}
+
+/// Set `version` to the current one. A file that had none gets it as its first line, where root
+/// keys must go and where it is easy to find.
+fn stamp_version(doc: &mut DocumentMut) -> Result<()> {
+    if doc.contains_key("version") {
+        doc["version"] = toml_edit::value(i64::from(CONFIG_VERSION));
+    } else {
+        *doc = format!("version = {CONFIG_VERSION}\n{doc}").parse()?;
+    }
+    Ok(())
+}
+
+/// Read a config file as an editable document.
+pub fn load(path: &Path) -> Result<DocumentMut> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+    text.parse()
+        .with_context(|| format!("Invalid config file '{}'", path.display()))
+}
+
+/// Replace a config file with `doc`. It is written next to the file and renamed over it, so a
+/// crash never leaves half a config.
+pub fn save(path: &Path, doc: &DocumentMut) -> Result<()> {
+    let tmp = path.with_extension("toml.tmp");
+    std::fs::write(&tmp, doc.to_string())
+        .with_context(|| format!("failed to write '{}'", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace '{}'", path.display()))
+}
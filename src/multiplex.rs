@@ -0,0 +1,247 @@
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::time::timeout;
+
+use crate::command::ParsedCommand;
+use crate::proxy::{Shared, connect_and_handshake};
+use crate::resp::RespStream;
+use crate::routing::{DUAL_FORWARD, is_blocking, is_listed};
+use crate::versions::BackendRole;
+
+/// Commands waiting to be written to one shared connection.
+const CALL_QUEUE: usize = 1024;
+
+/// `--multiplex`: the commands of clients that hold no backend connections of their own are
+/// pipelined over a few connections per backend role shared by all of them, instead of every
+/// client dialing the master and the replica.
+///
+/// Only [`is_stateless`] commands go this way. A client whose command needs connection state on
+/// the backend, e.g. `MULTI`, `WATCH`, `SUBSCRIBE` or `SELECT`, dials its own connections and
+/// keeps them until it disconnects.
+#[derive(Debug)]
+pub struct Multiplexer {
+    master: Vec<Mutex<Option<SharedConn>>>,
+    replica: Vec<Mutex<Option<SharedConn>>>,
+    next: AtomicUsize,
+}
+
+/// One shared connection, written and read by [`run`].
+#[derive(Debug)]
+struct SharedConn {
+    calls: mpsc::Sender<Call>,
+    addr: (String, u16),
+    /// [`crate::discovery::BackendPool::master_moves`] or `replica_moves` when it was dialed.
+    moves: u64,
+    read_timeout: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Call {
+    raw: Bytes,
+    reply: oneshot::Sender<Result<Bytes>>,
+}
+
+/// Nothing to dial: the master is down, or no replica is healthy.
+#[derive(Debug)]
+pub struct Unavailable;
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("backend unavailable for shared connections")
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+impl Multiplexer {
+    /// `connections` shared connections per role, dialed on first use.
+    pub fn new(connections: usize) -> Self {
+        let slots = || (0..connections.max(1)).map(|_| Mutex::new(None)).collect();
+        Self {
+            master: slots(),
+            replica: slots(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Send one command to `role` over a shared connection and wait for its reply. Replica
+    /// reads time out after the replica's read timeout. [`Unavailable`] means nothing was sent.
+    pub async fn call(&self, shared: &Shared, role: BackendRole, raw: Bytes) -> Result<Bytes> {
+        let (calls, read_timeout) = self.connection(shared, role).await?;
+        let (reply, replied) = oneshot::channel();
+        calls
+            .send(Call { raw, reply })
+            .await
+            .map_err(|_| anyhow!("shared {} connection closed", role_name(role)))?;
+        let replied = async {
+            replied
+                .await
+                .map_err(|_| anyhow!("shared {} connection closed", role_name(role)))?
+        };
+        match role {
+            BackendRole::Master => replied.await,
+            BackendRole::Replica => {
+                let wait = read_timeout.unwrap_or(shared.config().replica_timeout);
+                timeout(wait, replied)
+                    .await
+                    .context("shared replica read timeout")?
+            }
+        }
+    }
+
+    /// A connection to `role` that is still current, from the next slot in turn.
+    async fn connection(
+        &self,
+        shared: &Shared,
+        role: BackendRole,
+    ) -> Result<(mpsc::Sender<Call>, Option<Duration>)> {
+        let slots = match role {
+            BackendRole::Master => &self.master,
+            BackendRole::Replica => &self.replica,
+        };
+        let mut slot = slots[self.next.fetch_add(1, Ordering::Relaxed) % slots.len()]
+            .lock()
+            .await;
+        if let Some(conn) = slot.as_ref()
+            && !(conn.calls.is_closed() || is_stale(shared, role, conn))
+        {
+            return Ok((conn.calls.clone(), conn.read_timeout));
+        }
+        // Replies still owed on a replaced connection are read by its task before it closes.
+        *slot = None;
+        let conn = dial(shared, role).await?;
+        let found = (conn.calls.clone(), conn.read_timeout);
+        *slot = Some(conn);
+        Ok(found)
+    }
+}
+
+fn role_name(role: BackendRole) -> &'static str {
+    match role {
+        BackendRole::Master => "master",
+        BackendRole::Replica => "replica",
+    }
+}
+
+/// Whether `conn` no longer goes where connections to `role` should.
+fn is_stale(shared: &Shared, role: BackendRole, conn: &SharedConn) -> bool {
+    let (host, port) = &conn.addr;
+    match role {
+        BackendRole::Master => {
+            let master = shared.pool.master();
+            conn.moves != shared.pool.master_moves() || (&master.host, master.port) != (host, *port)
+        }
+        BackendRole::Replica => {
+            conn.moves != shared.pool.replica_moves()
+                || !shared.pool.contains_replica(host, *port)
+                || !shared.replica_health.is_usable(host, *port)
+        }
+    }
+}
+
+async fn dial(shared: &Shared, role: BackendRole) -> Result<SharedConn> {
+    let (endpoint, moves) = match role {
+        BackendRole::Master if shared.master_watch.is_down() => return Err(Unavailable.into()),
+        BackendRole::Master => (shared.pool.master(), shared.pool.master_moves()),
+        BackendRole::Replica => {
+            let Some(endpoint) = shared
+                .pool
+                .pick_replica(|r| shared.replica_health.is_usable(&r.host, r.port))
+            else {
+                return Err(Unavailable.into());
+            };
+            (endpoint, shared.pool.replica_moves())
+        }
+    };
+    let cfg = shared.config();
+    let conn =
+        match connect_and_handshake(&endpoint, cfg.connect_timeout, &shared.resolver, None).await {
+            Ok(conn) => conn,
+            Err(e) if role == BackendRole::Master => {
+                tracing::debug!(error = ?e, "shared master connection dial failed");
+                shared.pair.set_master_up(false);
+                shared.master_watch.report_down();
+                return Err(Unavailable.into());
+            }
+            Err(e) => return Err(e.context("shared replica connection dial failed")),
+        };
+    tracing::debug!(role = role_name(role), host = %endpoint.host, port = endpoint.port, "dialed shared connection");
+    let (calls, queued) = mpsc::channel(CALL_QUEUE);
+    tokio::spawn(run(conn, queued));
+    Ok(SharedConn {
+        calls,
+        addr: (endpoint.host, endpoint.port),
+        moves,
+        read_timeout: endpoint.read_timeout,
+    })
+}
+
+/// Write calls as they come and hand each reply to the oldest call still waiting. Runs until
+/// the connection fails, or until it was replaced and every reply it owes has been read.
+async fn run(mut conn: RespStream, mut queued: mpsc::Receiver<Call>) {
+    let mut waiting: VecDeque<oneshot::Sender<Result<Bytes>>> = VecDeque::new();
+    let mut open = true;
+    let failed = loop {
+        if !open && waiting.is_empty() {
+            break None;
+        }
+        tokio::select! {
+            call = queued.recv(), if open => match call {
+                Some(call) => {
+                    if let Err(e) = conn.write_all(&call.raw).await {
+                        let _ = call.reply.send(Err(anyhow!("shared connection write failed")));
+                        break Some(e);
+                    }
+                    waiting.push_back(call.reply);
+                }
+                None => open = false,
+            },
+            frame = conn.read_frame(), if !waiting.is_empty() => match frame {
+                Ok(Some((_frame, raw))) => {
+                    let reply = waiting.pop_front().expect("a call is waiting");
+                    // A caller that gave up has dropped its receiver.
+                    let _ = reply.send(Ok(raw));
+                }
+                Ok(None) => break Some(anyhow!("backend closed the shared connection")),
+                Err(e) => break Some(e),
+            },
+        }
+    };
+    if let Some(e) = failed {
+        tracing::warn!(error = %format!("{e:#}"), "shared connection failed");
+        for reply in waiting {
+            let _ = reply.send(Err(anyhow!("shared connection failed")));
+        }
+    }
+    let _ = conn.shutdown().await;
+}
+
+/// Whether a command can share a backend connection with other clients: it neither reads nor
+/// changes connection state, and doesn't block the connection while it waits.
+pub fn is_stateless(cmd: &ParsedCommand, first_arg_upper: Option<&str>) -> bool {
+    let stateful = matches!(
+        cmd.name_upper.as_str(),
+        "MULTI"
+            | "EXEC"
+            | "DISCARD"
+            | "WATCH"
+            | "UNWATCH"
+            | "SUBSCRIBE"
+            | "PSUBSCRIBE"
+            | "SSUBSCRIBE"
+            | "UNSUBSCRIBE"
+            | "PUNSUBSCRIBE"
+            | "SUNSUBSCRIBE"
+            | "MONITOR"
+            | "CLIENT"
+            | "SELECT"
+            | "RESET"
+            | "READONLY"
+            | "READWRITE"
+    ) || is_listed(DUAL_FORWARD, &cmd.name_upper, first_arg_upper);
+    !stateful && !is_blocking(&cmd.name_upper, &cmd.args)
+}
@@ -0,0 +1,87 @@
+use anyhow::{Context, Result, anyhow};
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::resp::{Reply, RespStream, encode_command_str};
+
+/// Read-your-writes across connections: a client that wrote through one connection fetches the
+/// master's replication offset with `PROXY OFFSET`, and a client that must see that write sends
+/// it with `PROXY MINOFFSET`. Its reads are then served by the replica only once the replica has
+/// processed that offset, and by the master until then.
+#[derive(Debug, Default)]
+pub struct OffsetGate {
+    /// Offset replica reads must have caught up to; 0 when unset.
+    required: u64,
+    /// Highest offset the replica has been seen at, so it is only asked again while behind.
+    replica_seen: u64,
+}
+
+impl OffsetGate {
+    pub fn set_required(&mut self, offset: u64) {
+        self.required = offset;
+    }
+
+    /// The connection dialed another replica, whose offset hasn't been seen yet.
+    pub fn replica_changed(&mut self) {
+        self.replica_seen = 0;
+    }
+
+    /// Whether the replica is known to have reached the required offset, without asking it.
+    pub fn replica_known_caught_up(&self) -> bool {
+        self.replica_seen >= self.required
+    }
+
+    /// Whether the replica may serve a read, asking it for its offset if the last answer was
+    /// behind. A replica that can't tell counts as behind; `Err` means its connection is no
+    /// longer usable.
+    pub async fn replica_caught_up(
+        &mut self,
+        replica: &mut RespStream,
+        wait: Duration,
+    ) -> Result<bool> {
+        if self.replica_known_caught_up() {
+            return Ok(true);
+        }
+        match timeout(wait, query_repl_offset(replica, "slave_repl_offset"))
+            .await
+            .context("INFO timeout")??
+        {
+            Ok(offset) => self.replica_seen = self.replica_seen.max(offset),
+            Err(e) => tracing::debug!(error = %e, "could not read replica offset"),
+        }
+        Ok(self.replica_seen >= self.required)
+    }
+}
+
+/// The master's current replication offset, for `PROXY OFFSET`. The inner error is the
+/// master's answer when it has none to give.
+pub async fn master_offset(master: &mut RespStream) -> Result<Result<u64, String>> {
+    query_repl_offset(master, "master_repl_offset").await
+}
+
+/// A replica's replication offset, as [`master_offset`].
+pub async fn replica_offset(replica: &mut RespStream) -> Result<Result<u64, String>> {
+    query_repl_offset(replica, "slave_repl_offset").await
+}
+
+/// A numeric field of `INFO replication`. The outer error is a failed connection, the inner one
+/// a reply without the field.
+async fn query_repl_offset(stream: &mut RespStream, field: &str) -> Result<Result<u64, String>> {
+    stream
+        .write_all(&encode_command_str(&["INFO", "replication"]))
+        .await?;
+    let (frame, _) = stream
+        .read_frame()
+        .await?
+        .ok_or_else(|| anyhow!("backend closed during INFO"))?;
+    let text = match Reply::from_frame(&frame) {
+        Reply::Str(text) => String::from_utf8_lossy(&text).into_owned(),
+        Reply::Error(e) => return Ok(Err(format!("INFO failed: {e}"))),
+        _ => return Ok(Err("unexpected reply to INFO".to_string())),
+    };
+    Ok(text
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| format!("no {field} in INFO replication")))
+}
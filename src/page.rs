@@ -0,0 +1,317 @@
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::resp::{RespStream, RespVersion, encode_command};
+
+/// `PROXY PAGE <page-size> <command> <args...>`: a range read split into bounded reads of at most
+/// `page-size` elements, so that a huge `LRANGE key 0 -1` doesn't block the backend for long.
+/// The pages are separate commands and not a snapshot: elements added or removed meanwhile may
+/// be missed or repeated, as with `SCAN`.
+#[derive(Debug)]
+pub struct PageRequest {
+    /// The command as given, minus the arguments the pages vary.
+    parts: Vec<Bytes>,
+    style: Style,
+    size: u64,
+    /// Replies hold a score after each member, as separate elements in RESP2.
+    withscores: bool,
+}
+
+#[derive(Debug)]
+enum Style {
+    /// `start stop` index ranges; negative indices are resolved with the collection's length.
+    Index {
+        start: i64,
+        stop: i64,
+        len_cmd: &'static str,
+    },
+    /// Score or lex ranges, paged with `LIMIT offset count`.
+    Limit,
+    /// `LPOS` matches, paged with `RANK r COUNT n`. A negative rank searches from the tail.
+    Rank { first: i64 },
+}
+
+const SUPPORTED: &str = "LRANGE, ZRANGE, ZREVRANGE, ZRANGEBYSCORE, ZREVRANGEBYSCORE, \
+                         ZRANGEBYLEX, ZREVRANGEBYLEX and LPOS";
+
+impl PageRequest {
+    /// Parse the arguments after `PAGE`. The error is the message for the client.
+    pub fn parse(args: &[Bytes]) -> Result<Self, String> {
+        let usage = || "usage: PROXY PAGE <page-size> <command> <args...>".to_string();
+        let size = args
+            .first()
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .and_then(|s| s.parse::<u64>().ok())
+            .filter(|n| (1..=i64::MAX as u64).contains(n))
+            .ok_or_else(|| "page size must be a positive integer".to_string())?;
+        let parts = args.get(1..).filter(|p| p.len() >= 2).ok_or_else(usage)?;
+        let name = String::from_utf8_lossy(&parts[0]).to_ascii_uppercase();
+        let options: Vec<String> = parts
+            .iter()
+            .skip(4)
+            .map(|b| String::from_utf8_lossy(b).to_ascii_uppercase())
+            .collect();
+        let has = |opt: &str| options.iter().any(|o| o == opt);
+        if has("LIMIT") {
+            return Err("LIMIT is added by PROXY PAGE".to_string());
+        }
+        let wrong_args = || format!("wrong number of arguments for '{name}'");
+        let index = |len_cmd| -> Result<Style, String> {
+            if parts.len() < 4 {
+                return Err(wrong_args());
+            }
+            Ok(Style::Index {
+                start: int_arg(&parts[2])?,
+                stop: int_arg(&parts[3])?,
+                len_cmd,
+            })
+        };
+
+        let style = match name.as_str() {
+            "LRANGE" if parts.len() == 4 => index("LLEN")?,
+            "ZRANGE" if has("BYSCORE") || has("BYLEX") => Style::Limit,
+            "ZRANGE" | "ZREVRANGE" => index("ZCARD")?,
+            "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX" | "ZREVRANGEBYLEX"
+                if parts.len() >= 4 =>
+            {
+                Style::Limit
+            }
+            "LPOS" => return Self::parse_lpos(parts, size),
+            "LRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX" | "ZREVRANGEBYLEX" => {
+                return Err(wrong_args());
+            }
+            _ => return Err(format!("PROXY PAGE supports {SUPPORTED}")),
+        };
+        let parts = match style {
+            // The index arguments are replaced on each page.
+            Style::Index { .. } => [&parts[..2], &parts[4..]].concat(),
+            _ => parts.to_vec(),
+        };
+        Ok(Self {
+            parts,
+            style,
+            size,
+            withscores: has("WITHSCORES"),
+        })
+    }
+
+    /// `LPOS key element [RANK r] [MAXLEN len]`; `COUNT` is set by the pages.
+    fn parse_lpos(parts: &[Bytes], size: u64) -> Result<Self, String> {
+        if parts.len() < 3 || parts.len().is_multiple_of(2) {
+            return Err("wrong number of arguments for 'LPOS'".to_string());
+        }
+        let mut first = 1;
+        let mut kept = parts[..3].to_vec();
+        for pair in parts[3..].chunks(2) {
+            match String::from_utf8_lossy(&pair[0])
+                .to_ascii_uppercase()
+                .as_str()
+            {
+                "RANK" => first = int_arg(&pair[1])?,
+                "MAXLEN" => kept.extend_from_slice(pair),
+                "COUNT" => return Err("COUNT is added by PROXY PAGE".to_string()),
+                other => return Err(format!("unsupported LPOS option '{other}'")),
+            }
+        }
+        if first == 0 {
+            return Err("RANK can't be zero".to_string());
+        }
+        Ok(Self {
+            parts: kept,
+            style: Style::Rank { first },
+            size,
+            withscores: false,
+        })
+    }
+
+    /// Read every page from `backend` and return the reply for the client: one array with all
+    /// the elements, or the backend's error. `Err` means the backend connection failed.
+    pub async fn run(&self, backend: &mut RespStream, wait: Option<Duration>) -> Result<Vec<u8>> {
+        // Elements per member in the reply.
+        let per_member = if self.withscores && backend.version() == RespVersion::Resp2 {
+            2
+        } else {
+            1
+        };
+        let size = self.size;
+        let mut pages = Pages::default();
+
+        match self.style {
+            Style::Index {
+                start,
+                stop,
+                len_cmd,
+            } => {
+                let len = match query(
+                    backend,
+                    &[Bytes::from(len_cmd), self.parts[1].clone()],
+                    wait,
+                )
+                .await?
+                {
+                    PageReply::Int(len) => len,
+                    PageReply::Error(raw) => return Ok(raw.to_vec()),
+                    _ => return Err(anyhow!("unexpected reply to {len_cmd}")),
+                };
+                let Some((mut lo, hi)) = resolve_range(start, stop, len) else {
+                    return Ok(b"*0\r\n".to_vec());
+                };
+                while lo <= hi {
+                    let end = lo.saturating_add(size as i64 - 1).min(hi);
+                    let mut cmd = self.parts[..2].to_vec();
+                    cmd.push(Bytes::from(lo.to_string()));
+                    cmd.push(Bytes::from(end.to_string()));
+                    cmd.extend_from_slice(&self.parts[2..]);
+                    let wanted = (end - lo + 1) as u64 * per_member;
+                    match pages.fetch(backend, &cmd, wait, wanted).await? {
+                        Fetched::Full => lo = end + 1,
+                        Fetched::Last => break,
+                        Fetched::Error(raw) => return Ok(raw.to_vec()),
+                    }
+                }
+            }
+            Style::Limit => {
+                let mut offset = 0u64;
+                loop {
+                    let mut cmd = self.parts.clone();
+                    cmd.push(Bytes::from_static(b"LIMIT"));
+                    cmd.push(Bytes::from(offset.to_string()));
+                    cmd.push(Bytes::from(size.to_string()));
+                    match pages.fetch(backend, &cmd, wait, size * per_member).await? {
+                        Fetched::Full => offset += size,
+                        Fetched::Last => break,
+                        Fetched::Error(raw) => return Ok(raw.to_vec()),
+                    }
+                }
+            }
+            Style::Rank { first } => {
+                let mut rank = first;
+                loop {
+                    let mut cmd = self.parts.clone();
+                    cmd.push(Bytes::from_static(b"RANK"));
+                    cmd.push(Bytes::from(rank.to_string()));
+                    cmd.push(Bytes::from_static(b"COUNT"));
+                    cmd.push(Bytes::from(size.to_string()));
+                    match pages.fetch(backend, &cmd, wait, size).await? {
+                        Fetched::Full if first > 0 => rank = rank.saturating_add(size as i64),
+                        Fetched::Full => rank = rank.saturating_sub(size as i64),
+                        Fetched::Last => break,
+                        Fetched::Error(raw) => return Ok(raw.to_vec()),
+                    }
+                }
+            }
+        }
+
+        let mut out = format!("*{}\r\n", pages.count).into_bytes();
+        out.extend_from_slice(&pages.body);
+        Ok(out)
+    }
+}
+
+/// Elements gathered so far, still encoded as the backend sent them.
+#[derive(Default)]
+struct Pages {
+    count: u64,
+    body: Vec<u8>,
+}
+
+enum Fetched {
+    Full,
+    /// The page was short: the range is exhausted.
+    Last,
+    Error(Bytes),
+}
+
+impl Pages {
+    async fn fetch(
+        &mut self,
+        backend: &mut RespStream,
+        cmd: &[Bytes],
+        wait: Option<Duration>,
+        wanted: u64,
+    ) -> Result<Fetched> {
+        match query(backend, cmd, wait).await? {
+            PageReply::Array { count, body } => {
+                self.count += count;
+                self.body.extend_from_slice(&body);
+                Ok(if count < wanted {
+                    Fetched::Last
+                } else {
+                    Fetched::Full
+                })
+            }
+            PageReply::Error(raw) => Ok(Fetched::Error(raw)),
+            _ => Err(anyhow!("unexpected reply to a PROXY PAGE read")),
+        }
+    }
+}
+
+enum PageReply {
+    /// An array reply split into its length and its encoded elements.
+    Array {
+        count: u64,
+        body: Bytes,
+    },
+    Int(i64),
+    Error(Bytes),
+    Other,
+}
+
+async fn query(
+    backend: &mut RespStream,
+    cmd: &[Bytes],
+    wait: Option<Duration>,
+) -> Result<PageReply> {
+    backend.write_all(&encode_command(cmd)).await?;
+    loop {
+        let read = backend.read_frame();
+        let frame = match wait {
+            Some(wait) => timeout(wait, read)
+                .await
+                .context("PROXY PAGE read timeout")?,
+            None => read.await,
+        }?;
+        let Some((_, raw)) = frame else {
+            return Err(anyhow!("backend closed during PROXY PAGE"));
+        };
+        let Some(header_end) = raw.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(PageReply::Other);
+        };
+        let header = std::str::from_utf8(&raw[1..header_end]).unwrap_or_default();
+        return Ok(match raw[0] {
+            // Out-of-band RESP3 pushes are not part of the reply.
+            b'>' => continue,
+            b'*' => PageReply::Array {
+                count: header.parse().unwrap_or(0),
+                body: raw.slice(header_end + 2..),
+            },
+            b':' => PageReply::Int(header.parse().unwrap_or(0)),
+            b'-' | b'!' => PageReply::Error(raw),
+            _ => PageReply::Other,
+        });
+    }
+}
+
+fn int_arg(arg: &Bytes) -> Result<i64, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "value is not an integer or out of range".to_string())
+}
+
+/// Resolve a Redis `start stop` range against a collection of `len` elements, like `LRANGE` does.
+fn resolve_range(start: i64, stop: i64, len: i64) -> Option<(i64, i64)> {
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop && start < len).then_some((start, stop))
+}
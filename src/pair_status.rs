@@ -0,0 +1,122 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Aggregate health of the master/replica pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairStatus {
+    /// Master and replica are both healthy.
+    Green,
+    /// Master is healthy, the replica is degraded: reads are served by master.
+    Yellow,
+    /// Master is unreachable.
+    Red,
+}
+
+impl PairStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            PairStatus::Green => "GREEN",
+            PairStatus::Yellow => "YELLOW",
+            PairStatus::Red => "RED",
+        }
+    }
+
+    /// Numeric form for alerting: 0 green, 1 yellow, 2 red.
+    pub fn code(self) -> u8 {
+        match self {
+            PairStatus::Green => 0,
+            PairStatus::Yellow => 1,
+            PairStatus::Red => 2,
+        }
+    }
+}
+
+/// Tracks backend health from what client connections observe and derives the pair status.
+///
+/// Updates are a single atomic swap unless a backend flips between up and down.
+#[derive(Debug)]
+pub struct PairHealth {
+    master_up: AtomicBool,
+    replica_up: AtomicBool,
+    status: Mutex<(PairStatus, Instant)>,
+    transitions: AtomicU64,
+}
+
+impl Default for PairHealth {
+    fn default() -> Self {
+        Self {
+            master_up: AtomicBool::new(true),
+            replica_up: AtomicBool::new(true),
+            status: Mutex::new((PairStatus::Green, Instant::now())),
+            transitions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl PairHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_master_up(&self, up: bool) {
+        if self.master_up.swap(up, Ordering::AcqRel) != up {
+            self.recompute();
+        }
+    }
+
+    pub fn set_replica_up(&self, up: bool) {
+        if self.replica_up.swap(up, Ordering::AcqRel) != up {
+            self.recompute();
+        }
+    }
+
+    /// INFO-style report of the pair status, for `PROXY STATUS`.
+    pub fn render(&self) -> String {
+        let (status, since) = *self.status.lock().expect("pair status lock poisoned");
+        format!(
+            "pair_status:{}\r\npair_status_code:{}\r\npair_status_since_seconds:{}\r\npair_status_transitions:{}\r\nmaster_up:{}\r\nreplica_up:{}\r\n",
+            status.name(),
+            status.code(),
+            since.elapsed().as_secs(),
+            self.transitions.load(Ordering::Relaxed),
+            self.master_up.load(Ordering::Acquire) as u8,
+            self.replica_up.load(Ordering::Acquire) as u8,
+        )
+    }
+
+    fn recompute(&self) {
+        let next = if !self.master_up.load(Ordering::Acquire) {
+            PairStatus::Red
+        } else if !self.replica_up.load(Ordering::Acquire) {
+            PairStatus::Yellow
+        } else {
+            PairStatus::Green
+        };
+
+        let mut status = self.status.lock().expect("pair status lock poisoned");
+        let (prev, since) = *status;
+        if prev == next {
+            return;
+        }
+        *status = (next, Instant::now());
+        self.transitions.fetch_add(1, Ordering::Relaxed);
+
+        let after_secs = since.elapsed().as_secs();
+        if next.code() > prev.code() {
+            tracing::warn!(
+                from = prev.name(),
+                to = next.name(),
+                after_secs,
+                "pair status changed"
+            );
+        } else {
+            tracing::info!(
+                from = prev.name(),
+                to = next.name(),
+                after_secs,
+                "pair status changed"
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Longest pause `PROXY PAUSE` accepts.
+pub const MAX_PAUSE_DURATION: Duration = Duration::from_secs(300);
+/// Commands held across every connection; past it, commands are refused rather than held.
+const MAX_PAUSED_COMMANDS: usize = 10_000;
+
+/// `PROXY PAUSE`: hold client commands for a while, e.g. during a failover or backend swap,
+/// instead of failing them.
+///
+/// Each connection waits with the command it read; the ones a client pipelined after it stay
+/// unread until the pause ends.
+#[derive(Debug, Default)]
+pub struct TrafficPause {
+    paused: Mutex<Option<Paused>>,
+    waiting: AtomicUsize,
+    resumed: Notify,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Paused {
+    until: Instant,
+    /// Like `CLIENT PAUSE WRITE`: commands `read_only` would allow go on.
+    writes_only: bool,
+}
+
+/// Counts a command in [`TrafficPause::waiting`] while it is held, even if its connection goes
+/// away meanwhile.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl TrafficPause {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold commands for `duration`, replacing any pause in progress.
+    pub fn pause(&self, duration: Duration, writes_only: bool) {
+        *self.paused.lock().expect("pause lock poisoned") = Some(Paused {
+            until: Instant::now() + duration,
+            writes_only,
+        });
+        // Held commands look again, in case this pause is shorter or writes-only.
+        self.resumed.notify_waiters();
+    }
+
+    /// End the pause early. Returns whether there was one.
+    pub fn resume(&self) -> bool {
+        let was = self
+            .paused
+            .lock()
+            .expect("pause lock poisoned")
+            .take()
+            .is_some_and(|p| p.until > Instant::now());
+        self.resumed.notify_waiters();
+        was
+    }
+
+    /// Whether [`TrafficPause::wait`] would hold a command now.
+    pub fn holds(&self, write: bool) -> bool {
+        self.current().is_some_and(|p| write || !p.writes_only)
+    }
+
+    fn current(&self) -> Option<Paused> {
+        let paused = *self.paused.lock().expect("pause lock poisoned");
+        paused.filter(|p| p.until > Instant::now())
+    }
+
+    /// Wait out the pause for a command, `write` if `read_only` would refuse it. Returns `false`
+    /// without waiting if too many commands are held already.
+    pub async fn wait(&self, write: bool) -> bool {
+        loop {
+            // Registered before the pause is looked at, so a resume in between isn't missed.
+            let resumed = self.resumed.notified();
+            let Some(paused) = self.current().filter(|p| write || !p.writes_only) else {
+                return true;
+            };
+            if self.waiting.fetch_add(1, Ordering::AcqRel) >= MAX_PAUSED_COMMANDS {
+                self.waiting.fetch_sub(1, Ordering::AcqRel);
+                return false;
+            }
+            let _waiting = Waiting(&self.waiting);
+            tokio::select! {
+                _ = resumed => {}
+                _ = tokio::time::sleep_until(paused.until) => {}
+            }
+        }
+    }
+
+    /// INFO-style lines for `PROXY STATUS`.
+    pub fn render(&self) -> String {
+        let (mode, left) = match self.current() {
+            Some(p) if p.writes_only => ("write", p.until - Instant::now()),
+            Some(p) => ("all", p.until - Instant::now()),
+            None => ("none", Duration::ZERO),
+        };
+        format!(
+            "paused:{mode}\r\npaused_left_ms:{}\r\npaused_commands:{}\r\n",
+            left.as_millis(),
+            self.waiting.load(Ordering::Acquire)
+        )
+    }
+}
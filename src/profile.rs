@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::routing::Route;
+
+/// Shortest profile window accepted; shorter windows rarely contain enough samples to be useful.
+pub const MIN_PROFILE_DURATION: Duration = Duration::from_secs(1);
+/// Longest profile window accepted.
+pub const MAX_PROFILE_DURATION: Duration = Duration::from_secs(600);
+/// Latency samples kept per route; later samples are dropped once full.
+const MAX_LATENCY_SAMPLES: usize = 100_000;
+/// Rows kept in the "top" lists of a report.
+const TOP_N: usize = 20;
+
+/// On-demand traffic profiler.
+///
+/// Recording is a single atomic load unless a profile window is open, so it costs nothing in
+/// normal operation.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    active: AtomicBool,
+    window: Mutex<Option<Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    commands: HashMap<String, u64>,
+    clients: HashMap<String, u64>,
+    latencies_us: HashMap<Route, Vec<u64>>,
+    fallbacks: HashMap<&'static str, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a profile window. Returns `false` if one is already running.
+    pub fn start(&self) -> bool {
+        let mut window = self.window.lock().expect("profiler lock poisoned");
+        if window.is_some() {
+            return false;
+        }
+        *window = Some(Window {
+            started: Instant::now(),
+            commands: HashMap::new(),
+            clients: HashMap::new(),
+            latencies_us: HashMap::new(),
+            fallbacks: HashMap::new(),
+        });
+        self.active.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Close the window and render its JSON report.
+    pub fn finish(&self) -> Option<serde_json::Value> {
+        self.active.store(false, Ordering::Relaxed);
+        let window = self.window.lock().expect("profiler lock poisoned").take()?;
+        Some(window.report())
+    }
+
+    pub fn record_command(&self, client: &str, cmd_upper: &str, route: Route, elapsed: Duration) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut guard = self.window.lock().expect("profiler lock poisoned");
+        let Some(w) = guard.as_mut() else {
+            return;
+        };
+        *w.commands.entry(cmd_upper.to_string()).or_default() += 1;
+        *w.clients.entry(client.to_string()).or_default() += 1;
+        let samples = w.latencies_us.entry(route).or_default();
+        if samples.len() < MAX_LATENCY_SAMPLES {
+            samples.push(elapsed.as_micros() as u64);
+        }
+    }
+
+    pub fn record_fallback(&self, reason: &'static str) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut guard = self.window.lock().expect("profiler lock poisoned");
+        if let Some(w) = guard.as_mut() {
+            *w.fallbacks.entry(reason).or_default() += 1;
+        }
+    }
+}
+
+impl Window {
+    fn report(mut self) -> serde_json::Value {
+        let latency: serde_json::Map<String, serde_json::Value> = self
+            .latencies_us
+            .iter_mut()
+            .map(|(route, samples)| {
+                samples.sort_unstable();
+                let name = match route {
+                    Route::Master => "master",
+                    Route::Replica => "replica",
+                    Route::Both => "both",
+                };
+                (
+                    name.to_string(),
+                    serde_json::json!({
+                        "samples": samples.len(),
+                        "p50_us": percentile(samples, 50.0),
+                        "p90_us": percentile(samples, 90.0),
+                        "p99_us": percentile(samples, 99.0),
+                        "max_us": samples.last().copied().unwrap_or(0),
+                    }),
+                )
+            })
+            .collect();
+
+        serde_json::json!({
+            "duration_ms": self.started.elapsed().as_millis() as u64,
+            "total_commands": self.commands.values().sum::<u64>(),
+            "top_commands": top(self.commands.into_iter(), "command"),
+            "top_clients": top(self.clients.into_iter(), "client"),
+            "latency": latency,
+            "fallback_reasons": self.fallbacks,
+        })
+    }
+}
+
+fn top(counts: impl Iterator<Item = (String, u64)>, label: &str) -> serde_json::Value {
+    let mut rows: Vec<(String, u64)> = counts.collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    rows.truncate(TOP_N);
+    rows.into_iter()
+        .map(|(name, count)| serde_json::json!({ label: name, "count": count }))
+        .collect()
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -1,54 +1,350 @@
 use anyhow::{Context, Result, anyhow};
 use bytes::Bytes;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::time::timeout;
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
 
-use crate::command::{HelloRequest, ParsedCommand, Request, parse_request};
-use crate::config::{Config, ProxyAuth, RedisEndpoint};
-use crate::resp::{Frame, RespStream, RespVersion, encode_command, encode_command_str};
-use crate::routing::{Route, route_cmd};
+use crate::budget::{ReplicaBudget, RetryBudget, RetryWindow};
+use crate::client_ids::{ClientIds, ClientKey};
+use crate::command::{HelloRequest, ParsedCommand, ProxySubcommand, Request, parse_request};
+use crate::config::{Config, Consistency, ProxyAuth, RedisEndpoint, ReplicaTimeoutAction};
+use crate::discovery::BackendPool;
+use crate::health::{ReplicaBreaker, ReplicaHealth};
+use crate::listener::{ListenerPolicy, PeerAddr};
+use crate::logging::LogControl;
+use crate::master_watch::MasterWatch;
+use crate::multiplex::{Multiplexer, Unavailable, is_stateless};
+use crate::offsets::{OffsetGate, master_offset};
+use crate::page::PageRequest;
+use crate::pair_status::PairHealth;
+use crate::pause::{MAX_PAUSE_DURATION, TrafficPause};
+use crate::profile::{MAX_PROFILE_DURATION, MIN_PROFILE_DURATION, Profiler};
+use crate::recent_writes::{RecentWrites, overlaps, writes};
+use crate::resolver::Resolver;
+use crate::resp::{
+    AsyncStream, Frame, Reply, RespStream, RespVersion, encode_command, encode_command_str,
+};
+use crate::routing::{
+    ConnClass, PatternRules, ReplicaReads, Route, is_blocking, is_read_only, is_retryable,
+    needs_master, read_only_mode_allows, read_only_variant, rejected_reason, route_cmd,
+    sort_stores,
+};
+use crate::scripts::{ScriptRegistry, parse_function_list, shebang_no_writes};
+use crate::slowlog::{CommandTiming, SlowLog};
 use crate::stats::Stats;
+use crate::transaction::QueuedReads;
+use crate::tunables;
+use crate::versions::{BackendRole, BackendVersions, query_server_info};
+use crate::warm::WarmPool;
 
-#[derive(Debug, Clone, Copy)]
-struct ConnState {
-    in_multi: bool,
-    watch_active: bool,
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnState {
+    pub in_multi: bool,
+    pub watch_active: bool,
+    /// Set by `READONLY` and cleared by `READWRITE`: the client wants its reads served by a
+    /// replica, so every known read-only command is.
+    pub readonly: bool,
+    /// Set by `CLIENT TRACKING ON` and cleared by `OFF` or `RESET`. Invalidations come from the
+    /// node that served the reads, and only master is tracking, so every read goes there.
+    pub tracking: bool,
+    /// The route `client_rules` give the connection's name, kept up to date by the caller.
+    pub named_route: Option<Route>,
+}
+
+/// Entries `PROXY SLOWLOG GET` returns without a count, as in Redis.
+const SLOWLOG_DEFAULT_COUNT: usize = 10;
+/// Longest pause between retries of a write rejected with `-READONLY`; they start at
+/// `--retry-backoff-ms`.
+const READONLY_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Longest pause between resends of a read master said it can't serve just now.
+const TRANSIENT_RETRY_BACKOFF_MAX: Duration = Duration::from_millis(500);
+/// Redials of a master that dropped the connection mid-command, the pause doubling each time.
+const MASTER_REDIAL_ATTEMPTS: u32 = 5;
+const READ_ONLY_PROXY_REPLY: &[u8] = b"-READONLY You can't write against a read only proxy\r\n";
+const ADMIN_DENIED_REPLY: &[u8] =
+    b"-NOPERM admin PROXY commands are disabled for this connection\r\n";
+const ADMIN_ONLY_REPLY: &[u8] =
+    b"-NOPERM the admin listener only serves PROXY STATUS and admin PROXY commands\r\n";
+const PAUSE_FULL_REPLY: &[u8] = b"-TRYAGAIN the proxy is paused and holds too many commands\r\n";
+/// Client requests read ahead of the one being handled.
+const CLIENT_READ_AHEAD: usize = 128;
+/// Commands forwarded to master ahead of their replies, before the replies are relayed anyway.
+const MAX_PIPELINED: usize = 1024;
+const REPLICA_TIMEOUT_REPLY: &[u8] = b"-PROXYTIMEOUT replica did not answer in time\r\n";
+const MASTER_TIMEOUT_REPLY: &[u8] = b"-PROXYTIMEOUT master did not answer in time\r\n";
+const MASTERDOWN_REPLY: &[u8] =
+    b"-MASTERDOWN Link with MASTER is down and the proxy is reconnecting\r\n";
+const MASTER_LOST_REPLY: &[u8] =
+    b"-ERR master connection lost before replying; the command may or may not have been applied\r\n";
+
+/// Process-wide state shared by every client connection.
+#[derive(Debug)]
+pub struct Shared {
+    /// Swapped on config reload; read through [`Shared::config`].
+    pub cfg: RwLock<Arc<Config>>,
+    pub stats: Stats,
+    pub scripts: ScriptRegistry,
+    pub resolver: Resolver,
+    pub profiler: Profiler,
+    /// Cap on replica-served reads; `None` leaves replica usage unlimited.
+    pub replica_budget: Option<ReplicaBudget>,
+    pub pool: Arc<BackendPool>,
+    pub pair: PairHealth,
+    pub versions: BackendVersions,
+    pub master_watch: MasterWatch,
+    pub replica_breaker: ReplicaBreaker,
+    pub retry_budget: RetryBudget,
+    /// Backend client IDs told to clients, for `CLIENT TRACKING ... REDIRECT`.
+    pub client_ids: ClientIds,
+    pub replica_health: ReplicaHealth,
+    pub pause: TrafficPause,
+    /// Set with `--multiplex`.
+    pub multiplexer: Option<Multiplexer>,
+    /// Set with `--warm-connections`.
+    pub warm: Option<WarmPool>,
+    /// Connection handlers that panicked, for `PROXY STATUS`.
+    pub connection_panics: AtomicU64,
+    /// Writes of every connection, for `read_your_writes_global`.
+    pub recent_writes: Mutex<RecentWrites>,
+    pub slowlog: SlowLog,
+    pub log: LogControl,
+    /// The `--config` file, rewritten by `PROXY CONFIG REWRITE`.
+    pub config_file: Option<PathBuf>,
+}
+
+impl Shared {
+    /// The current configuration. Connections re-read it per command, so reloaded settings
+    /// apply to them without reconnecting.
+    pub fn config(&self) -> Arc<Config> {
+        self.cfg.read().expect("config lock poisoned").clone()
+    }
+
+    pub fn set_config(&self, cfg: Config) {
+        *self.cfg.write().expect("config lock poisoned") = Arc::new(cfg);
+    }
+
+    /// A connection's replica failed or timed out.
+    pub fn replica_failed(&self) {
+        self.pair.set_replica_up(false);
+        self.replica_breaker
+            .record_failure(self.config().replica_breaker_threshold);
+    }
+
+    pub fn replica_answered(&self) {
+        self.pair.set_replica_up(true);
+        self.replica_breaker.record_success();
+    }
+
+    /// A connection's replica at `addr` refused a read for `reason`, see [`replica_refused`].
+    /// It answered, so it counts as up; one that is loading is avoided until it is done.
+    fn replica_errored(&self, addr: Option<&(String, u16)>, reason: &str) {
+        self.replica_answered();
+        if reason == REPLICA_LOADING
+            && let Some((host, port)) = addr
+        {
+            self.replica_health.mark_loading(host, *port);
+        }
+    }
+
+    /// Whether reads may go to a connection's replica at `addr`, or to one it has yet to dial.
+    fn replica_allowed(&self, addr: Option<&(String, u16)>) -> bool {
+        self.replica_breaker.allows_replica()
+            && addr.is_none_or(|(host, port)| self.replica_health.is_usable(host, *port))
+    }
 }
 
-pub async fn handle_client(socket: TcpStream, cfg: Arc<Config>, stats: Arc<Stats>) {
-    if let Err(e) = handle_client_inner(socket, cfg, stats).await {
-        tracing::debug!(error = ?e, "connection terminated");
+pub async fn handle_client(
+    socket: impl AsyncStream + 'static,
+    peer: PeerAddr,
+    shared: Arc<Shared>,
+    tls: Option<TlsAcceptor>,
+    policy: Arc<ListenerPolicy>,
+) {
+    // `client` is filled in once the PROXY protocol header (if any) has been read.
+    // `class` is filled in once the first command has been seen, if classification is enabled.
+    let span = tracing::info_span!(
+        "conn",
+        client = tracing::field::Empty,
+        class = tracing::field::Empty
+    );
+    // Run in its own task, so that a panic unwinds only this connection, closing its sockets,
+    // and is reported here with the connection's span.
+    let task = {
+        let shared = shared.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_client_inner(socket, peer, &shared, tls, &policy).await {
+                    if e.downcast_ref::<MasterClosed>().is_some() {
+                        shared.pair.set_master_up(false);
+                    }
+                    tracing::debug!(error = ?e, "connection terminated");
+                }
+            }
+            .instrument(span.clone()),
+        )
+    };
+    if let Err(e) = task.await
+        && e.is_panic()
+    {
+        // The panic itself was logged by the hook `LogControl::init` installs.
+        shared.connection_panics.fetch_add(1, Ordering::Relaxed);
+        span.in_scope(|| tracing::warn!("connection handler panicked; connection closed"));
     }
 }
 
 async fn handle_client_inner(
-    client_sock: TcpStream,
-    cfg: Arc<Config>,
-    stats: Arc<Stats>,
+    mut client_sock: impl AsyncStream + 'static,
+    mut peer: PeerAddr,
+    shared: &Shared,
+    tls: Option<TlsAcceptor>,
+    policy: &Arc<ListenerPolicy>,
 ) -> Result<()> {
-    client_sock.set_nodelay(true)?;
-    let mut client = RespStream::new(client_sock, RespVersion::Resp2);
+    let Shared {
+        stats,
+        scripts,
+        profiler,
+        replica_budget,
+        ..
+    } = shared;
 
-    let mut master = connect_and_handshake(&cfg.master, cfg.connect_timeout).await?;
-    let mut replica = match connect_and_handshake(&cfg.replica, cfg.connect_timeout).await {
-        Ok(s) => Some(s),
-        Err(e) => {
-            tracing::warn!(error = ?e, "replica unavailable at connect; falling back to master-only");
-            None
+    let mut cfg = shared.config();
+
+    // PROXY header, TLS, the first command and HELLO/AUTH must complete before this deadline,
+    // otherwise the client is dropped.
+    let handshake_deadline = cfg.handshake_timeout.map(|t| Instant::now() + t);
+
+    if cfg.accept_proxy_protocol {
+        let header = crate::proxy_protocol::read_header(&mut client_sock);
+        let original = match handshake_deadline {
+            Some(deadline) => timeout_at(deadline, header)
+                .await
+                .context("PROXY protocol header timeout")?,
+            None => header.await,
+        }
+        .context("PROXY protocol header")?;
+        if let Some(original) = original {
+            tracing::debug!(via = %peer, original = %original, "PROXY protocol header");
+            peer = original;
+        }
+    }
+    tracing::Span::current().record("client", tracing::field::display(&peer));
+    let client_label = peer.to_string();
+
+    // Sent first on every backend connection so backends see the real client address.
+    let preamble = cfg
+        .send_proxy_protocol
+        .map(|v| crate::proxy_protocol::encode_header(v, &peer));
+
+    // A certificate mapped to a proxy user authenticates the connection without AUTH.
+    let mut cert_user = None;
+    let mut client = match tls {
+        Some(acceptor) => {
+            let accept = acceptor.accept(client_sock);
+            let tls_stream = match handshake_deadline {
+                Some(deadline) => timeout_at(deadline, accept)
+                    .await
+                    .context("client TLS handshake timeout")?,
+                None => accept.await,
+            }
+            .context("client TLS handshake failed")?;
+            if let (Some(listener_tls), Some(leaf)) = (
+                cfg.tls.as_ref(),
+                tls_stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|c| c.first()),
+            ) {
+                cert_user = crate::tls::map_cert_user(&listener_tls.client_cert_users, leaf);
+            }
+            RespStream::new(tls_stream, RespVersion::Resp2)
         }
+        None => RespStream::new(client_sock, RespVersion::Resp2),
     };
 
-    let mut authenticated = !cfg.proxy_auth.enabled;
-    let mut state = ConnState {
-        in_multi: false,
-        watch_active: false,
+    let Some(_slot) = policy.admit() else {
+        tracing::info!(listener = %policy.addr, "listener connection limit reached; refusing client");
+        let _ = client
+            .write_all(b"-ERR max number of clients reached\r\n")
+            .await;
+        return Ok(());
     };
 
+    // Requests are read while earlier ones are still being answered, so pipelined commands are
+    // there to be forwarded together.
+    client.read_ahead(CLIENT_READ_AHEAD);
+
+    let mut authenticated = !policy.auth(&cfg).enabled;
+    // Authenticated proxy user; stats are partitioned by it.
+    let mut user = "default".to_string();
+    if let Some(cert_user) = cert_user {
+        tracing::info!(user = %cert_user, "client authenticated by certificate");
+        authenticated = true;
+        user = cert_user;
+    }
+    let mut state = ConnState::default();
+    let mut class: Option<ConnClass> = None;
+    let mut session = SessionReplay::default();
+    let client_key = shared.client_ids.register();
+    let mut offsets = OffsetGate::default();
+    let mut recent_writes = RecentWrites::default();
+    let retry_window = RetryWindow::default();
+    // When this connection last wrote, for `pin_after_write`.
+    let mut last_write: Option<Instant> = None;
+    // Set with PROXY CONSISTENCY; otherwise the user's or the configured mode applies.
+    let mut consistency: Option<Consistency> = None;
+    // A write in `Consistency::Wait` that no replica confirmed; reads go to master until one is.
+    let mut write_unconfirmed = false;
+    // A `MULTI` of replica reads so far, for `replica_transactions`.
+    let mut queued_reads: Option<QueuedReads> = None;
+    // Set with PROXY ROUTE for the next command.
+    let mut route_hint: Option<Route> = None;
+
+    // The master is dialed by the first forwarded command and the replica by the first read
+    // routed to it, so idle and unauthenticated connections hold no backend connections.
+    let mut backends: Option<Backends> = None;
+
+    // A first complete command arrived: without AUTH, that ends the handshake.
+    let mut first_command = false;
     loop {
-        let Some((frame, raw)) = client.read_frame().await? else {
+        cfg = shared.config();
+        let next = match handshake_deadline.filter(|_| !authenticated || !first_command) {
+            Some(deadline) => match timeout_at(deadline, client.read_frame()).await {
+                Ok(r) => r?,
+                Err(_) if authenticated => {
+                    tracing::info!("client sent no command in time; closing");
+                    break;
+                }
+                Err(_) => {
+                    tracing::info!("client did not authenticate in time; closing");
+                    break;
+                }
+            },
+            None => match backends.as_mut().filter(|b| b.replies_pending()) {
+                // Drain the losers of hedged reads and the replica's replies to dual-forwarded
+                // commands while the client is quiet; what is still pending when it sends a
+                // command is drained before that command.
+                Some(b) => tokio::select! {
+                    next = client.read_frame() => next?,
+                    drained = b.drain_pending(shared) => {
+                        drained?;
+                        client.read_frame().await?
+                    }
+                },
+                None => client.read_frame().await?,
+            },
+        };
+        let Some((frame, raw)) = next else {
             break;
         };
+        first_command = true;
 
         let req = match parse_request(&frame) {
             Ok(r) => r,
@@ -63,21 +359,82 @@ async fn handle_client_inner(
 
         match req {
             Request::Hello(hello) => {
-                handle_hello(
+                if let Some(b) = backends.as_mut() {
+                    b.relay_pipelined(&mut client, shared, &user).await?;
+                }
+                if !authorize_hello(
                     &mut client,
-                    &mut master,
-                    &mut replica,
                     &mut authenticated,
-                    &cfg.proxy_auth,
-                    cfg.replica_timeout,
-                    &stats,
-                    hello,
+                    &mut user,
+                    policy.auth(&cfg),
+                    &hello,
+                )
+                .await?
+                {
+                    continue;
+                }
+                let version = client.version();
+                if policy.admin_only {
+                    let target = hello.protover.unwrap_or(version);
+                    client.set_version(target);
+                    let server_version = shared.versions.master_version();
+                    client
+                        .write_all(&local_hello_reply(
+                            target,
+                            server_version.as_deref().unwrap_or("unknown"),
+                        ))
+                        .await?;
+                    continue;
+                }
+                let b = match ensure_backends(
+                    &mut backends,
+                    shared,
+                    preamble.as_deref(),
+                    &session,
+                    version,
+                )
+                .await
+                {
+                    Ok(b) => b,
+                    Err(e) if e.is::<MasterDown>() => {
+                        // Let the client finish connecting; its protocol and name are applied to
+                        // the master once it is back.
+                        session.observe_hello(&hello);
+                        let target = hello.protover.unwrap_or(version);
+                        client.set_version(target);
+                        let server_version = shared.versions.master_version();
+                        client
+                            .write_all(&local_hello_reply(
+                                target,
+                                server_version.as_deref().unwrap_or("unknown"),
+                            ))
+                            .await?;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                b.drain_replica(shared).await;
+                let had_replica = b.replica.is_some();
+                handle_hello(
+                    &mut client,
+                    &mut b.master,
+                    &mut b.replica,
+                    &mut b.replica_owed,
+                    stats,
+                    &user,
+                    hello.clone(),
                 )
                 .await?;
+                session.observe_hello(&hello);
+                if had_replica && b.replica.is_none() {
+                    shared.replica_failed();
+                    b.replica_gone(&cfg);
+                }
                 continue;
             }
             Request::Command(mut cmd) => {
                 let mut raw = raw;
+                let hint = route_hint.take();
 
                 if cfg.force_eval_readonly && cmd.name_upper == "EVAL" {
                     rewrite_command_name(&mut cmd, &mut raw, "EVAL_RO");
@@ -87,6 +444,47 @@ async fn handle_client_inner(
                     rewrite_command_name(&mut cmd, &mut raw, "EVALSHA_RO");
                 }
 
+                if cfg.sort_readonly && cmd.name_upper == "SORT" && !sort_stores(&cmd.args) {
+                    rewrite_command_name(&mut cmd, &mut raw, "SORT_RO");
+                }
+
+                if let Some(variant) = read_only_variant(&cmd.name_upper, &cmd.args) {
+                    rewrite_command_name(&mut cmd, &mut raw, variant);
+                }
+
+                redirect_tracking(&mut cmd, &mut raw, &shared.client_ids);
+
+                let first_arg_upper = cmd
+                    .args
+                    .first()
+                    .and_then(|b| std::str::from_utf8(b).ok())
+                    .map(|s| s.to_ascii_uppercase());
+                let write = !read_only_mode_allows(&cmd.name_upper, first_arg_upper.as_deref());
+
+                // Replies still owed to pipelined commands go out before anything else answers
+                // the client. Commands forwarded on this connection may join them; these are
+                // the ones that could be answered otherwise before the route is known.
+                let ends_pipeline = !authenticated
+                    || policy.admin_only
+                    || policy.read_only(&cfg)
+                    || matches!(cmd.name_upper.as_str(), "AUTH" | "QUIT" | "PROXY")
+                    || (cfg.classify_connections
+                        && class.is_none()
+                        && ConnClass::sniff(&cmd.name_upper).is_some())
+                    || rejected_reason(&cmd.name_upper, first_arg_upper.as_deref()).is_some()
+                    || shared.pause.holds(write)
+                    || hint.is_some()
+                    || state.in_multi
+                    || state.watch_active
+                    || queued_reads.is_some()
+                    || (cfg.replica_transactions && cmd.name_upper == "MULTI")
+                    || (cfg.route_script_flags && cmd.name_upper == "FCALL");
+                if let Some(b) = backends.as_mut().filter(|b| !b.pipeline.is_empty())
+                    && (ends_pipeline || b.master_moves != shared.pool.master_moves())
+                {
+                    b.relay_pipelined(&mut client, shared, &user).await?;
+                }
+
                 // Auth gate.
                 if !authenticated && !is_auth_exempt(&cmd) {
                     client
@@ -97,142 +495,1757 @@ async fn handle_client_inner(
 
                 // Handle a few commands locally.
                 if cmd.name_upper == "AUTH" {
-                    handle_auth(&mut client, &mut authenticated, &cfg.proxy_auth, &cmd).await?;
+                    handle_auth(
+                        &mut client,
+                        &mut authenticated,
+                        &mut user,
+                        policy.auth(&cfg),
+                        &cmd,
+                    )
+                    .await?;
                     continue;
                 }
                 if cmd.name_upper == "QUIT" {
                     client.write_all(b"+OK\r\n").await?;
                     break;
                 }
+                if cmd.name_upper == "PROXY" {
+                    if let Some(reply) = proxy_refusal(policy, &cfg, &cmd) {
+                        client.write_all(reply).await?;
+                        continue;
+                    }
+                    let sub = ProxySubcommand::parse(&cmd);
+                    match sub {
+                        Some(ProxySubcommand::Offset) => {
+                            proxy_offset(
+                                &mut client,
+                                &mut backends,
+                                shared,
+                                preamble.as_deref(),
+                                &session,
+                                &state,
+                                &cmd,
+                            )
+                            .await?
+                        }
+                        Some(ProxySubcommand::MinOffset) => {
+                            proxy_minoffset(&mut client, &mut offsets, &cmd).await?
+                        }
+                        Some(ProxySubcommand::Consistency) => {
+                            let default = default_consistency(&cfg, &user);
+                            proxy_consistency(&mut client, &mut consistency, default, &cmd).await?
+                        }
+                        Some(ProxySubcommand::Route) if state.in_multi => {
+                            client
+                                .write_all(b"-ERR PROXY ROUTE is not allowed in MULTI\r\n")
+                                .await?
+                        }
+                        Some(ProxySubcommand::Route) => match parse_route_hint(&cmd) {
+                            Some(hint) => {
+                                route_hint = Some(hint);
+                                client.write_all(b"+OK\r\n").await?
+                            }
+                            None => {
+                                client
+                                    .write_all(b"-ERR usage: PROXY ROUTE MASTER|REPLICA\r\n")
+                                    .await?
+                            }
+                        },
+                        Some(ProxySubcommand::Page) if state.in_multi => {
+                            client
+                                .write_all(b"-ERR PROXY PAGE is not allowed in MULTI\r\n")
+                                .await?
+                        }
+                        Some(ProxySubcommand::Page) => {
+                            let replica_reads = policy.replica_reads
+                                && class.is_none_or(|c| c.uses_replica())
+                                && shared.replica_allowed(
+                                    backends.as_ref().and_then(|b| b.replica_addr.as_ref()),
+                                );
+                            proxy_page(
+                                &mut client,
+                                &mut backends,
+                                shared,
+                                preamble.as_deref(),
+                                &session,
+                                &cmd,
+                                replica_reads,
+                            )
+                            .await?
+                        }
+                        _ => handle_proxy_command(&mut client, shared, &cmd, sub).await?,
+                    }
+                    continue;
+                }
+                if policy.admin_only {
+                    let reply = match cmd.name_upper.as_str() {
+                        "PING" => b"+PONG\r\n".as_slice(),
+                        _ => ADMIN_ONLY_REPLY,
+                    };
+                    client.write_all(reply).await?;
+                    continue;
+                }
 
-                // Route and forward.
-                let first_arg_upper = cmd
-                    .args
-                    .first()
-                    .and_then(|b| std::str::from_utf8(b).ok())
-                    .map(|s| s.to_ascii_uppercase());
+                if cfg.classify_connections
+                    && class.is_none()
+                    && let Some(sniffed) = ConnClass::sniff(&cmd.name_upper)
+                {
+                    class = Some(sniffed);
+                    tracing::Span::current().record("class", sniffed.name());
+                    tracing::debug!(class = sniffed.name(), "classified connection");
+                    if sniffed == ConnClass::Replication {
+                        client
+                            .write_all(
+                                b"-ERR replication connections are not supported through the proxy\r\n",
+                            )
+                            .await?;
+                        break;
+                    }
+                }
+
+                // Refuse commands that would desync the backend connection.
+                if let Some(reason) = rejected_reason(&cmd.name_upper, first_arg_upper.as_deref()) {
+                    client
+                        .write_all(format!("-ERR {reason}\r\n").as_bytes())
+                        .await?;
+                    continue;
+                }
+                if policy.read_only(&cfg)
+                    && !read_only_mode_allows(&cmd.name_upper, first_arg_upper.as_deref())
+                {
+                    client.write_all(READ_ONLY_PROXY_REPLY).await?;
+                    continue;
+                }
+                if !shared.pause.wait(write).await {
+                    // The pause may have begun after pipelined commands were let through.
+                    if let Some(b) = backends.as_mut() {
+                        b.relay_pipelined(&mut client, shared, &user).await?;
+                    }
+                    client.write_all(PAUSE_FULL_REPLY).await?;
+                    continue;
+                }
+
+                let started = Instant::now();
+
+                // `--multiplex`: a client without backend connections of its own sends
+                // stateless commands over the shared ones.
+                if let Some(mux) = &shared.multiplexer
+                    && backends.is_none()
+                    && session.is_empty()
+                    && preamble.is_none()
+                    && client.version() == RespVersion::Resp2
+                    && hint.is_none()
+                    && queued_reads.is_none()
+                    && !state.in_multi
+                    && !state.watch_active
+                    && cfg.readonly_retry.is_none()
+                    && consistency.unwrap_or_else(|| default_consistency(&cfg, &user))
+                        != Consistency::Wait
+                    && is_stateless(&cmd, first_arg_upper.as_deref())
+                    && !((cfg.route_script_flags || cfg.analyze_scripts)
+                        && matches!(cmd.name_upper.as_str(), "EVAL" | "EVALSHA" | "FCALL"))
+                {
+                    let replica_available = policy.replica_reads
+                        && class.is_none_or(|c| c.uses_replica())
+                        && shared.replica_allowed(None);
+                    let mut route = decide_route(
+                        &cmd,
+                        first_arg_upper.as_deref(),
+                        &state,
+                        replica_available,
+                        false,
+                        &cfg.replica_reads,
+                        &cfg.key_rules,
+                    );
+                    if route == Route::Replica
+                        && let Some(reason) =
+                            master_fallback(&cfg, shared, &cmd, &recent_writes, last_write, false)
+                                .or((!offsets.replica_known_caught_up())
+                                    .then_some("replica behind offset token"))
+                    {
+                        profiler.record_fallback(reason);
+                        route = Route::Master;
+                    }
+                    // Commands for both backends set state, and take connections of their own.
+                    if route != Route::Both {
+                        let Some(served) =
+                            forward_multiplexed(&mut client, mux, shared, &raw, route, &user)
+                                .await?
+                        else {
+                            continue;
+                        };
+                        stats.record(&user, served, &cmd.name_upper);
+                        profiler.record_command(
+                            &client_label,
+                            &cmd.name_upper,
+                            served,
+                            started.elapsed(),
+                        );
+                        let master_routed = route_cmd(
+                            &cmd.name_upper,
+                            first_arg_upper.as_deref(),
+                            &cfg.replica_reads,
+                        ) == Route::Master
+                            && !is_read_only(&cmd.name_upper, first_arg_upper.as_deref());
+                        if master_routed
+                            && writes(&cmd)
+                            && !is_retryable(&cmd.name_upper, first_arg_upper.as_deref())
+                            && cfg.pin_after_write.is_some()
+                        {
+                            last_write = Some(Instant::now());
+                        }
+                        if master_routed {
+                            remember_write(&cfg, shared, &mut recent_writes, &cmd);
+                        }
+                        continue;
+                    }
+                }
+
+                // `PROXY SWITCH MASTER ... MIGRATE`: reconnect between transactions, replaying
+                // the session onto the new master as on a first dial.
+                if !state.in_multi
+                    && !state.watch_active
+                    && queued_reads.is_none()
+                    && backends
+                        .as_ref()
+                        .is_some_and(|b| b.master_moves != shared.pool.master_moves())
+                    && let Some(mut old) = backends.take()
+                {
+                    tracing::debug!("master switched; moving the connection to it");
+                    let _ = old.master.shutdown().await;
+                    if let Some(mut rep) = old.replica.take() {
+                        let _ = rep.shutdown().await;
+                    }
+                }
+
+                let Some(b) = backends_or_masterdown(
+                    &mut client,
+                    &mut backends,
+                    shared,
+                    preamble.as_deref(),
+                    &session,
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                // Listeners without replica reads never dial it, and pub/sub and MONITOR
+                // connections never read from it; free its slot.
+                if !policy.replica_reads || class.is_some_and(|c| !c.uses_replica()) {
+                    b.replica_dialed = true;
+                    b.replica_lost = None;
+                    if let Some(mut rep) = b.replica.take() {
+                        let _ = rep.shutdown().await;
+                    }
+                } else if let Some(lost) = &mut b.replica_lost
+                    && lost.retry_due(&cfg)
+                {
+                    tracing::debug!(after = ?lost.at.elapsed(), commands = lost.commands, "dialing the replica again");
+                    b.replica_lost = None;
+                    b.replica_dialed = false;
+                    offsets.replica_changed();
+                }
+
+                let waits = consistency.unwrap_or_else(|| default_consistency(&cfg, &user))
+                    == Consistency::Wait;
+                state.named_route = session
+                    .name()
+                    .and_then(|name| cfg.client_rules.matching(name));
+
+                // A transaction the proxy is queueing runs on the replica while it only reads.
+                if let Some(queued) = queued_reads.as_mut() {
+                    match cmd.name_upper.as_str() {
+                        "DISCARD" => {
+                            queued_reads = None;
+                            update_state(&mut state, &cmd);
+                            client.write_all(b"+OK\r\n").await?;
+                            continue;
+                        }
+                        "EXEC" => {
+                            let queued = queued_reads.take().expect("queue checked above");
+                            let fallback = queued.commands().find_map(|queued_cmd| {
+                                master_fallback(
+                                    &cfg,
+                                    shared,
+                                    queued_cmd,
+                                    &recent_writes,
+                                    last_write,
+                                    waits && write_unconfirmed,
+                                )
+                            });
+                            if fallback.is_none() && shared.replica_allowed(b.replica_addr.as_ref())
+                            {
+                                ensure_replica(b, shared, preamble.as_deref(), &session).await;
+                            }
+                            b.drain_replica(shared).await;
+                            let served = exec_queued_reads(
+                                &mut client,
+                                b,
+                                shared,
+                                &mut offsets,
+                                &queued,
+                                fallback,
+                            )
+                            .await?;
+                            stats.record(&user, served, "MULTI");
+                            for queued_cmd in queued.commands() {
+                                stats.record(&user, served, &queued_cmd.name_upper);
+                            }
+                            stats.record(&user, served, &cmd.name_upper);
+                            profiler.record_command(
+                                &client_label,
+                                &cmd.name_upper,
+                                served,
+                                started.elapsed(),
+                            );
+                            update_state(&mut state, &cmd);
+                            continue;
+                        }
+                        // Routed as if outside the transaction.
+                        _ if decide_route(
+                            &cmd,
+                            first_arg_upper.as_deref(),
+                            &ConnState {
+                                in_multi: false,
+                                ..state
+                            },
+                            true,
+                            false,
+                            &cfg.replica_reads,
+                            &cfg.key_rules,
+                        ) == Route::Replica =>
+                        {
+                            queued.push(cmd.clone(), raw.clone());
+                            client.write_all(b"+QUEUED\r\n").await?;
+                            continue;
+                        }
+                        _ => {
+                            // Not a replica read: the transaction continues on master.
+                            stats.record(&user, Route::Master, "MULTI");
+                            for queued_cmd in queued.commands() {
+                                stats.record(&user, Route::Master, &queued_cmd.name_upper);
+                            }
+                            queued.replay(&mut b.master, None).await?;
+                            queued_reads = None;
+                        }
+                    }
+                } else if cfg.replica_transactions
+                    && hint != Some(Route::Master)
+                    && cmd.name_upper == "MULTI"
+                    && !state.in_multi
+                    && !state.watch_active
+                    && policy.replica_reads
+                    && class.is_none_or(|c| c.uses_replica())
+                {
+                    queued_reads = Some(QueuedReads::default());
+                    update_state(&mut state, &cmd);
+                    client.write_all(b"+OK\r\n").await?;
+                    continue;
+                }
 
-                let route =
-                    decide_route(&cmd, first_arg_upper.as_deref(), &state, replica.is_some());
+                // Route and forward. In a transaction, `FUNCTION LIST` would only be queued, and
+                // the command goes to master anyway.
+                let read_only_script = (cfg.route_script_flags
+                    && !state.in_multi
+                    && !state.watch_active
+                    && script_is_read_only(&mut client, &mut b.master, scripts, &cmd).await?)
+                    || (cfg.analyze_scripts
+                        && cmd.args.first().is_some_and(|first| {
+                            scripts.analyzed_read_only(&cmd.name_upper, first)
+                        }));
+
+                let replica_available = (b.replica.is_some() || !b.replica_dialed)
+                    && shared.replica_allowed(b.replica_addr.as_ref());
+                let route = decide_route(
+                    &cmd,
+                    first_arg_upper.as_deref(),
+                    &state,
+                    replica_available,
+                    read_only_script,
+                    &cfg.replica_reads,
+                    &cfg.key_rules,
+                );
+                let mut route = apply_route_hint(
+                    route,
+                    hint,
+                    &cmd,
+                    first_arg_upper.as_deref(),
+                    &state,
+                    replica_available,
+                );
+                if route == Route::Replica
+                    && let Some(reason) = master_fallback(
+                        &cfg,
+                        shared,
+                        &cmd,
+                        &recent_writes,
+                        last_write,
+                        waits && write_unconfirmed,
+                    )
+                {
+                    profiler.record_fallback(reason);
+                    route = Route::Master;
+                }
+                let master_routed = !read_only_script
+                    && route_cmd(
+                        &cmd.name_upper,
+                        first_arg_upper.as_deref(),
+                        &cfg.replica_reads,
+                    ) == Route::Master
+                    && !is_read_only(&cmd.name_upper, first_arg_upper.as_deref());
+                let is_write = master_routed
+                    && writes(&cmd)
+                    && !is_retryable(&cmd.name_upper, first_arg_upper.as_deref());
+                // Writes in a transaction run at EXEC, and a WAIT inside MULTI would be queued.
+                let wait_for_replica =
+                    waits && ((is_write && !state.in_multi) || cmd.name_upper == "EXEC");
+                // How long a replica read runs before master is asked as well.
+                let hedge = if cfg.hedged_reads || cfg.hedged_reads_users.contains(&user) {
+                    Some(Duration::ZERO)
+                } else {
+                    cfg.speculative_retry
+                }
+                .filter(|_| route == Route::Replica && b.master.version() == RespVersion::Resp2);
+                // Plain commands outside a transaction are forwarded without waiting for the
+                // reply while the client has more commands buffered: master commands, and
+                // replica reads that need no checks on the replica first. A master command
+                // that touches the keys of a replica read in flight waits for its reply.
+                // Blocking commands, pub/sub and MONITOR may rightly wait on master for long.
+                let blocking = is_blocking(&cmd.name_upper, &cmd.args);
+                // Its reply is recorded in `ClientIds`.
+                let asks_client_id = !state.in_multi
+                    && cmd.name_upper == "CLIENT"
+                    && first_arg_upper.as_deref() == Some("ID");
+                let subscribing = !state.in_multi
+                    && matches!(
+                        cmd.name_upper.as_str(),
+                        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE"
+                    );
+                let master_wait = cfg
+                    .master_timeout
+                    .filter(|_| class.is_none_or(|c| c == ConnClass::Regular) && !blocking);
+                let transient_retry = cfg.transient_retry.filter(|_| {
+                    !state.in_multi && is_retryable(&cmd.name_upper, first_arg_upper.as_deref())
+                });
+                let pipelined = match route {
+                    Route::Master => {
+                        !wait_for_replica
+                            && !blocking
+                            && !subscribing
+                            && !asks_client_id
+                            && cfg.readonly_retry.is_none()
+                            && transient_retry.is_none()
+                            && (master_wait.is_some() || cfg.master_timeout.is_none())
+                    }
+                    Route::Replica => {
+                        hedge.is_none()
+                            && offsets.replica_known_caught_up()
+                            && replica_budget.is_none()
+                            && cfg.replica_timeout_action == ReplicaTimeoutAction::Disable
+                    }
+                    Route::Both => false,
+                } && !state.in_multi
+                    && !state.watch_active
+                    && (!b.pipeline.is_empty() || client.has_buffered_frame());
+                if !pipelined || (route == Route::Master && b.pipeline.conflicts(&cmd)) {
+                    b.relay_pipelined(&mut client, shared, &user).await?;
+                    if b.master_timed_out {
+                        // Not sent: it would queue behind the replies master still owes.
+                        client.write_all(MASTER_TIMEOUT_REPLY).await?;
+                        backends = None;
+                        continue;
+                    }
+                }
+                let needs_replica = match route {
+                    Route::Replica => true,
+                    // Session state the replay restores can wait until the replica is dialed.
+                    Route::Both => !SessionReplay::restores(&cmd, first_arg_upper.as_deref()),
+                    Route::Master => false,
+                };
+                if needs_replica {
+                    ensure_replica(b, shared, preamble.as_deref(), &session).await;
+                }
+                // A single replica read skips the replica's stale replies itself; an offset
+                // check or a pipeline needs them gone first, as do replies still to be checked.
+                // Other commands leave them for when the client is quiet.
+                if route == Route::Replica
+                    && !(offsets.replica_known_caught_up()
+                        && b.replica_owed.is_empty()
+                        && !pipelined)
+                {
+                    b.drain_replica(shared).await;
+                }
+                let had_replica = b.replica.is_some();
+                let replica_timeout = b.replica_timeout.unwrap_or(cfg.replica_timeout);
+                // Reads wait for the replica to reach the client's PROXY MINOFFSET, on master.
+                if route == Route::Replica
+                    && let Some(rep) = b.replica.as_mut()
+                {
+                    match offsets.replica_caught_up(rep, replica_timeout).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            profiler.record_fallback("replica behind offset token");
+                            route = Route::Master;
+                        }
+                        Err(e) => {
+                            tracing::warn!(error = ?e, "replica offset check failed; falling back to master");
+                            if let Some(mut rep) = b.replica.take() {
+                                let _ = rep.shutdown().await;
+                            }
+                            shared.replica_failed();
+                            route = Route::Master;
+                        }
+                    }
+                }
+                let hedge = hedge.filter(|_| route == Route::Replica);
+                let Backends {
+                    master,
+                    replica,
+                    master_pending,
+                    replica_pending,
+                    replica_owed,
+                    replica_addr,
+                    pipeline,
+                    master_timed_out,
+                    ..
+                } = b;
 
-                match route {
+                let mut drop_replica = false;
+                let mut master_lost = false;
+                let retries = Retries {
+                    shared,
+                    own: &retry_window,
+                    user: &user,
+                    command: &cmd.name_upper,
+                    route,
+                };
+                let served = match route {
+                    _ if pipelined => {
+                        let served = match replica.as_mut() {
+                            Some(rep) if route == Route::Replica => {
+                                let lost = match rep.write_all(&raw).await {
+                                    Ok(()) => false,
+                                    Err(e) => {
+                                        tracing::warn!(error = ?e, "replica write failed; falling back to master");
+                                        drop_replica = true;
+                                        true
+                                    }
+                                };
+                                pipeline.push_replica(&cmd, &raw, lost);
+                                Route::Replica
+                            }
+                            _ => {
+                                if route == Route::Replica {
+                                    profiler.record_fallback("replica unavailable");
+                                }
+                                master.write_all(&raw).await?;
+                                pipeline.push_master(master_wait);
+                                Route::Master
+                            }
+                        };
+                        stats.record(&user, served, &cmd.name_upper);
+                        if drop_replica && let Some(mut rep) = replica.take() {
+                            let _ = rep.shutdown().await;
+                            shared.replica_failed();
+                        }
+                        if pipeline.len() >= MAX_PIPELINED || !client.has_buffered_frame() {
+                            // A master lost meanwhile takes the connection with it: the commands
+                            // whose replies are missing can't all be retried.
+                            *master_timed_out |= pipeline
+                                .relay(
+                                    &mut client,
+                                    master,
+                                    (replica, replica_addr.as_ref()),
+                                    replica_timeout,
+                                    shared,
+                                    &user,
+                                )
+                                .await?;
+                        }
+                        served
+                    }
                     Route::Master => {
-                        stats.record(Route::Master, &cmd.name_upper);
-                        forward_master(&mut client, &mut master, &raw).await?;
+                        stats.record(&user, Route::Master, &cmd.name_upper);
+                        let forwarded = match cfg.readonly_retry {
+                            _ if wait_for_replica => {
+                                forward_master_waiting(&mut client, master, &raw, cfg.wait_timeout)
+                                    .await
+                                    .map(|confirmed| write_unconfirmed = !confirmed)
+                            }
+                            _ if blocking => {
+                                forward_master_blocking(&mut client, master, &raw).await
+                            }
+                            _ if asks_client_id => {
+                                forward_client_id(&mut client, master, &raw, shared, &client_key)
+                                    .await
+                                    .map(|()| session.client_id = Some(client_key.get()))
+                            }
+                            _ if subscribing => {
+                                relay_subscribed(&mut client, master, &raw, &cmd, shared, &user)
+                                    .await
+                            }
+                            // Reads never get `-READONLY`, so the shield has nothing for them.
+                            _ if transient_retry.is_some() => {
+                                forward_master_timed(
+                                    &mut client,
+                                    master,
+                                    &raw,
+                                    master_wait,
+                                    transient_retry.map(|window| (window, retries)),
+                                )
+                                .await
+                            }
+                            Some(window) if !state.in_multi && !state.watch_active => {
+                                let shield = ReadonlyShield {
+                                    shared,
+                                    preamble: preamble.as_deref(),
+                                    session: &session,
+                                    window,
+                                    retries,
+                                };
+                                forward_master_shielded(&mut client, master, &raw, shield).await
+                            }
+                            _ => {
+                                forward_master_timed(&mut client, master, &raw, master_wait, None)
+                                    .await
+                            }
+                        };
+                        match forwarded {
+                            Err(e) if e.is::<ClientGone>() => {
+                                tracing::debug!(command = %cmd.name_upper, "client left during a blocking command; closing the master connection");
+                                master_lost = true;
+                            }
+                            // Subscriptions aren't moved to a new master: closing the client
+                            // tells it to subscribe again.
+                            Err(e) if subscribing && e.is::<MasterClosed>() => return Err(e),
+                            Err(e) if e.is::<MasterTimeout>() => {
+                                tracing::warn!(command = %cmd.name_upper, "master did not answer in time; replacing the connection");
+                                client.write_all(MASTER_TIMEOUT_REPLY).await?;
+                                master_lost = true;
+                            }
+                            Err(e) if e.is::<MasterClosed>() => {
+                                // A transaction or WATCH died with the old connection.
+                                let retryable = !state.in_multi
+                                    && !state.watch_active
+                                    && is_retryable(&cmd.name_upper, first_arg_upper.as_deref());
+                                master_lost = !retry_on_new_master(
+                                    &mut client,
+                                    master,
+                                    &raw,
+                                    retryable.then_some(retries),
+                                    shared,
+                                    preamble.as_deref(),
+                                    &session,
+                                )
+                                .await?;
+                            }
+                            forwarded => forwarded?,
+                        }
+                        Route::Master
                     }
                     Route::Replica => {
-                        if let Some(rep) = replica.as_mut() {
-                            stats.record(Route::Replica, &cmd.name_upper);
-                            let ok = forward_replica_with_fallback(
+                        // Reads over the replica budget are served by master instead.
+                        let over_budget = replica.is_some()
+                            && replica_budget
+                                .as_ref()
+                                .is_some_and(|b| !b.try_acquire(&user));
+                        if let Some(rep) = replica.as_mut()
+                            && !over_budget
+                            && let Some(delay) = hedge
+                        {
+                            let hedged = forward_hedged(
+                                &mut client,
+                                (master, rep),
+                                replica_pending,
+                                &raw,
+                                delay,
+                                replica_timeout,
+                            )
+                            .await?;
+                            match hedged {
+                                Hedged::Master => {
+                                    if !delay.is_zero() {
+                                        profiler.record_fallback("speculative retry on master");
+                                    }
+                                    *replica_pending += 1;
+                                    stats.record(&user, Route::Master, &cmd.name_upper);
+                                    Route::Master
+                                }
+                                Hedged::Replica { master_asked } => {
+                                    *master_pending += usize::from(master_asked);
+                                    shared.replica_answered();
+                                    stats.record(&user, Route::Replica, &cmd.name_upper);
+                                    Route::Replica
+                                }
+                                Hedged::Refused(reason) => {
+                                    shared.replica_errored(replica_addr.as_ref(), reason);
+                                    stats.record(&user, Route::Master, &cmd.name_upper);
+                                    profiler.record_fallback(reason);
+                                    Route::Master
+                                }
+                                Hedged::ReplicaFailed(reason) => {
+                                    stats.record(&user, Route::Master, &cmd.name_upper);
+                                    profiler.record_fallback(reason);
+                                    shared.replica_failed();
+                                    drop_replica = true;
+                                    Route::Master
+                                }
+                            }
+                        } else if let Some(rep) = replica.as_mut()
+                            && !over_budget
+                        {
+                            stats.record(&user, Route::Replica, &cmd.name_upper);
+                            let fallback = forward_replica_with_fallback(
                                 &mut client,
-                                &mut master,
-                                rep,
+                                master,
+                                (rep, replica_pending),
                                 &raw,
-                                cfg.replica_timeout,
+                                replica_timeout,
+                                cfg.replica_timeout_action,
+                                retries,
                             )
                             .await?;
-                            if !ok {
-                                stats.record_replica_fallback(&cmd.name_upper);
-                                replica = None;
+                            match fallback {
+                                Some(Fallback::ReplicaFailed(reason)) => {
+                                    stats.record_replica_fallback(&user, &cmd.name_upper);
+                                    profiler.record_fallback(reason);
+                                    shared.replica_failed();
+                                    // Dropped once the command's timing has been taken.
+                                    drop_replica = true;
+                                }
+                                Some(Fallback::Refused(reason)) => {
+                                    stats.record_replica_fallback(&user, &cmd.name_upper);
+                                    profiler.record_fallback(reason);
+                                    shared.replica_errored(replica_addr.as_ref(), reason);
+                                }
+                                Some(Fallback::TimedOut) => {
+                                    if cfg.replica_timeout_action == ReplicaTimeoutAction::Retry {
+                                        stats.record_replica_fallback(&user, &cmd.name_upper);
+                                        profiler.record_fallback("replica timeout");
+                                    }
+                                    shared.replica_failed();
+                                }
+                                None => shared.replica_answered(),
                             }
+                            Route::Replica
                         } else {
-                            stats.record(Route::Master, &cmd.name_upper);
-                            forward_master(&mut client, &mut master, &raw).await?;
+                            profiler.record_fallback(if over_budget {
+                                "replica budget exhausted"
+                            } else {
+                                "replica unavailable"
+                            });
+                            stats.record(&user, Route::Master, &cmd.name_upper);
+                            forward_master(&mut client, master, &raw).await?;
+                            Route::Master
                         }
                     }
                     Route::Both => {
                         if replica.is_some() {
-                            stats.record(Route::Both, &cmd.name_upper);
-                            forward_both(
-                                &mut client,
-                                &mut master,
-                                &mut replica,
-                                &raw,
-                                cfg.replica_timeout,
-                            )
-                            .await?;
+                            stats.record(&user, Route::Both, &cmd.name_upper);
+                            // The client only waits for master; the replica's reply is checked
+                            // once the client has moved on.
+                            match forward_both(&mut client, master, replica, &raw).await? {
+                                Some(master_reply) => replica_owed.push_back(ReplicaOwed {
+                                    command: cmd.name_upper.clone(),
+                                    user: user.clone(),
+                                    master_reply: Some(master_reply),
+                                }),
+                                None => shared.replica_failed(),
+                            }
+                            Route::Both
                         } else {
                             // If replica is absent, this effectively becomes master-only.
-                            stats.record(Route::Master, &cmd.name_upper);
-                            forward_master(&mut client, &mut master, &raw).await?;
+                            stats.record(&user, Route::Master, &cmd.name_upper);
+                            forward_master(&mut client, master, &raw).await?;
+                            Route::Master
                         }
                     }
+                };
+                profiler.record_command(&client_label, &cmd.name_upper, served, started.elapsed());
+                // Replies relayed as a batch leave no timing of their own.
+                if !pipelined
+                    && let Some(timing) = CommandTiming::measure(
+                        &client,
+                        std::iter::once(&*master).chain(replica.as_ref()),
+                    )
+                {
+                    shared.slowlog.observe(
+                        cfg.slowlog_threshold,
+                        &client_label,
+                        &cmd.name_upper,
+                        served,
+                        timing,
+                    );
+                }
+                if drop_replica {
+                    *replica = None;
+                }
+                if had_replica && replica.is_none() {
+                    b.replica_gone(&cfg);
                 }
 
+                if is_write && cfg.pin_after_write.is_some() {
+                    last_write = Some(Instant::now());
+                }
+                if master_routed {
+                    remember_write(&cfg, shared, &mut recent_writes, &cmd);
+                }
                 update_state(&mut state, &cmd);
+                session.observe(&cmd, first_arg_upper.as_deref(), &raw);
+                if cfg.route_script_flags || cfg.analyze_scripts {
+                    track_scripts(scripts, &cmd, first_arg_upper.as_deref());
+                }
+                if master_lost {
+                    // The next command dials the master again, or is answered `-MASTERDOWN`. A
+                    // transaction or WATCH ended with the connection; what the session replay
+                    // restores on the new one stays.
+                    backends = None;
+                    state.in_multi = false;
+                    state.watch_active = false;
+                }
             }
         }
     }
 
     // Best-effort shutdown.
-    let _ = master.shutdown().await;
-    if let Some(mut rep) = replica {
-        let _ = rep.shutdown().await;
+    if let Some(Backends {
+        mut master,
+        replica,
+        ..
+    }) = backends
+    {
+        let _ = master.shutdown().await;
+        if let Some(mut rep) = replica {
+            let _ = rep.shutdown().await;
+        }
     }
     let _ = client.shutdown().await;
 
     Ok(())
 }
 
-fn is_auth_exempt(cmd: &ParsedCommand) -> bool {
-    matches!(cmd.name_upper.as_str(), "AUTH" | "HELLO" | "QUIT")
+/// Per-client backend connections.
+struct Backends {
+    master: RespStream,
+    replica: Option<RespStream>,
+    /// Set once the replica has been dialed (or ruled out). A replica that failed or was dropped
+    /// is not dialed again, and the connection continues master-only, unless `replica_lost`
+    /// clears it.
+    replica_dialed: bool,
+    /// Address of `replica`, checked against the pool whenever its membership changes.
+    replica_addr: Option<(String, u16)>,
+    /// The replica's own read timeout, used instead of the global `replica_timeout`.
+    replica_timeout: Option<std::time::Duration>,
+    /// [`ReplicaHealth::drains`] of the replica when it was dialed.
+    replica_drains: u64,
+    /// [`BackendPool::master_moves`] and [`BackendPool::replica_moves`] as of the last dial.
+    master_moves: u64,
+    replica_moves: u64,
+    /// Set while the replica is gone because it failed, until `replica_retry_after*` says to
+    /// dial it again.
+    replica_lost: Option<ReplicaLost>,
+    /// When the replica connection recently failed, oldest first; see [`Backends::replica_gone`].
+    replica_failures: VecDeque<Instant>,
+    /// Replies to hedged reads that lost the race, still to be read and discarded.
+    master_pending: usize,
+    replica_pending: usize,
+    /// Replies the replica owes to commands also sent to master, after the `replica_pending`
+    /// ones; see [`Backends::drain_replica`].
+    replica_owed: VecDeque<ReplicaOwed>,
+    /// Commands a client pipelined that were forwarded, replies still to be relayed.
+    pipeline: Pipeline,
+    /// Master didn't answer a pipelined command within `master_timeout`. The connection may
+    /// still send the replies it owed, so the next command dials a new one.
+    master_timed_out: bool,
+    pool_generation: u64,
 }
 
-fn decide_route(
-    cmd: &ParsedCommand,
-    first_arg_upper: Option<&str>,
-    state: &ConnState,
-    replica_available: bool,
-) -> Route {
-    // Force-master contexts.
-    if state.in_multi || state.watch_active {
-        return Route::Master;
+impl Backends {
+    fn replies_pending(&self) -> bool {
+        self.master_pending > 0 || self.replica_pending > 0 || !self.replica_owed.is_empty()
     }
 
-    match route_cmd(&cmd.name_upper, first_arg_upper) {
-        Route::Both => Route::Both,
-        Route::Replica if replica_available => Route::Replica,
-        _ => Route::Master,
+    /// [`Pipeline::relay`] on this client's connections.
+    async fn relay_pipelined(
+        &mut self,
+        client: &mut RespStream,
+        shared: &Shared,
+        user: &str,
+    ) -> Result<()> {
+        let cfg = shared.config();
+        let had_replica = self.replica.is_some();
+        self.master_timed_out |= self
+            .pipeline
+            .relay(
+                client,
+                &mut self.master,
+                (&mut self.replica, self.replica_addr.as_ref()),
+                self.replica_timeout.unwrap_or(cfg.replica_timeout),
+                shared,
+                user,
+            )
+            .await?;
+        if had_replica && self.replica.is_none() {
+            self.replica_gone(&cfg);
+        }
+        Ok(())
     }
-}
 
-fn update_state(state: &mut ConnState, cmd: &ParsedCommand) {
-    match cmd.name_upper.as_str() {
-        "MULTI" => state.in_multi = true,
-        "EXEC" | "DISCARD" => {
-            state.in_multi = false;
-            state.watch_active = false; // EXEC/DISCARD clears WATCH.
+    /// Read the replies no client is waiting for.
+    async fn drain_pending(&mut self, shared: &Shared) -> Result<()> {
+        self.drain_master().await?;
+        self.drain_replica(shared).await;
+        Ok(())
+    }
+
+    async fn drain_master(&mut self) -> Result<()> {
+        while self.master_pending > 0 {
+            if self.master.read_frame().await?.is_none() {
+                return Err(MasterClosed.into());
+            }
+            self.master_pending -= 1;
+        }
+        Ok(())
+    }
+
+    /// Read the replies the replica owes to hedged reads and dual-forwarded commands, so that
+    /// the next one it sends answers a new command. A replica that doesn't answer within
+    /// `replica_drain_timeout` is dropped, and so is one that refused a command master accepted, or the other
+    /// way around (e.g. `SELECT` beyond the replica's `databases`): replica reads would no
+    /// longer see what master does.
+    async fn drain_replica(&mut self, shared: &Shared) {
+        while self.replica_pending > 0 || !self.replica_owed.is_empty() {
+            let cfg = shared.config();
+            let Some(rep) = self.replica.as_mut() else {
+                self.replica_pending = 0;
+                self.replica_owed.clear();
+                break;
+            };
+            let wait = self
+                .replica_timeout
+                .unwrap_or(cfg.replica_timeout)
+                .max(cfg.replica_drain_timeout);
+            match timeout(wait, rep.read_frame()).await {
+                Ok(Ok(Some(_))) if self.replica_pending > 0 => self.replica_pending -= 1,
+                Ok(Ok(Some((frame, replica_raw)))) => {
+                    let owed = self.replica_owed.pop_front().expect("a reply is owed");
+                    if let Some((master_frame, master_raw)) = &owed.master_reply
+                        && is_error_reply(&frame) != is_error_reply(master_frame)
+                    {
+                        tracing::warn!(
+                            command = %owed.command,
+                            master = %String::from_utf8_lossy(master_raw).trim_end(),
+                            replica = %String::from_utf8_lossy(&replica_raw).trim_end(),
+                            "replica reply to dual-forwarded command differs from master; disabling replica"
+                        );
+                        // The replica itself is fine; only this connection's state on it
+                        // diverged.
+                        shared
+                            .stats
+                            .record_replica_mismatch(&owed.user, &owed.command);
+                        self.replica = None;
+                        self.replica_pending = 0;
+                        self.replica_owed.clear();
+                        self.replica_gone(&cfg);
+                    }
+                }
+                _ => {
+                    tracing::warn!("replica did not answer in time; dropping it");
+                    self.replica = None;
+                    self.replica_pending = 0;
+                    self.replica_owed.clear();
+                    shared.replica_failed();
+                    self.replica_gone(&cfg);
+                }
+            }
+        }
+    }
+
+    /// The replica connection failed and was dropped. Until `replica_disable_failures` failures
+    /// fall within `replica_disable_window`, the next replica read dials it again; then the
+    /// connection goes master-only until `replica_retry_after*` allows another try.
+    fn replica_gone(&mut self, cfg: &Config) {
+        let now = Instant::now();
+        while self
+            .replica_failures
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= cfg.replica_disable_window)
+        {
+            self.replica_failures.pop_front();
+        }
+        self.replica_failures.push_back(now);
+        if self.replica_failures.len() >= cfg.replica_disable_failures as usize {
+            self.replica_failures.clear();
+            self.replica_lost = Some(ReplicaLost::now());
+        } else {
+            tracing::debug!(
+                failures = self.replica_failures.len(),
+                "replica connection failed; dialing it again on the next read"
+            );
+            self.replica_dialed = false;
         }
-        "WATCH" => state.watch_active = true,
-        "UNWATCH" => state.watch_active = false,
-        _ => {}
     }
 }
 
-fn rewrite_command_name(cmd: &mut ParsedCommand, raw: &mut Bytes, new_name: &str) {
-    cmd.name_upper = new_name.to_string();
+/// A reply the replica owes to a command that was also sent to master, whose reply the client
+/// already has.
+#[derive(Debug)]
+struct ReplicaOwed {
+    /// The command, and the user who sent it, for stats.
+    command: String,
+    user: String,
+    /// Master's reply, when the replica's has to agree with it on success or failure.
+    master_reply: Option<(Frame, Bytes)>,
+}
 
-    let mut parts = Vec::with_capacity(cmd.args.len() + 1);
-    parts.push(Bytes::copy_from_slice(new_name.as_bytes()));
-    parts.extend(cmd.args.clone());
+/// When a connection lost its replica, and how many commands it has sent since.
+#[derive(Debug, Clone, Copy)]
+struct ReplicaLost {
+    at: Instant,
+    commands: u64,
+}
 
-    let encoded = encode_command(&parts);
-    *raw = encoded.freeze();
+impl ReplicaLost {
+    fn now() -> Self {
+        Self {
+            at: Instant::now(),
+            commands: 0,
+        }
+    }
+
+    /// Count a command and tell whether the replica is due to be dialed again.
+    fn retry_due(&mut self, cfg: &Config) -> bool {
+        self.commands += 1;
+        cfg.replica_retry_after
+            .is_some_and(|after| self.at.elapsed() >= after)
+            || cfg
+                .replica_retry_after_commands
+                .is_some_and(|after| self.commands >= after)
+    }
 }
 
-async fn handle_auth(
-    client: &mut RespStream,
-    authenticated: &mut bool,
-    proxy_auth: &ProxyAuth,
-    cmd: &ParsedCommand,
-) -> Result<()> {
-    let (user, pass) = match cmd.args.len() {
-        1 => (
-            "default".to_string(),
-            String::from_utf8_lossy(&cmd.args[0]).to_string(),
-        ),
-        2 => (
-            String::from_utf8_lossy(&cmd.args[0]).to_string(),
+/// Dial the master if this client has no backend connections yet. Fails with [`MasterDown`]
+/// when the master can't be reached, without dialing while [`MasterWatch`] knows it is down.
+async fn ensure_backends<'a>(
+    backends: &'a mut Option<Backends>,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+    version: RespVersion,
+) -> Result<&'a mut Backends> {
+    if backends.as_ref().is_some_and(|b| b.master_timed_out) {
+        *backends = None;
+    }
+    if backends.is_none() {
+        if shared.master_watch.is_down() {
+            return Err(MasterDown.into());
+        }
+        let pool_generation = shared.pool.generation();
+        let master = shared.pool.master();
+        let mut master_conn =
+            match dial_backend(shared, BackendRole::Master, &master, preamble).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::debug!(error = ?e, "master dial failed");
+                    shared.pair.set_master_up(false);
+                    shared.master_watch.report_down();
+                    return Err(MasterDown.into());
+                }
+            };
+        shared.pair.set_master_up(true);
+        // State the client set while the master was down, e.g. a locally answered HELLO 3.
+        session
+            .replay_master(&mut master_conn, version, &shared.client_ids)
+            .await?;
+        *backends = Some(Backends {
+            master: master_conn,
+            replica: None,
+            replica_dialed: false,
+            replica_addr: None,
+            replica_timeout: None,
+            replica_drains: 0,
+            master_moves: shared.pool.master_moves(),
+            replica_moves: shared.pool.replica_moves(),
+            replica_lost: None,
+            replica_failures: VecDeque::new(),
+            master_pending: 0,
+            replica_pending: 0,
+            replica_owed: VecDeque::new(),
+            pipeline: Pipeline::default(),
+            master_timed_out: false,
+            pool_generation,
+        });
+    }
+
+    let b = backends.as_mut().expect("backends connected above");
+    // `PROXY SWITCH REPLICA ... MIGRATE`: the next replica read dials the new replica.
+    let replica_moves = shared.pool.replica_moves();
+    if b.replica_moves != replica_moves {
+        b.replica_moves = replica_moves;
+        if let Some(mut rep) = b.replica.take() {
+            let _ = rep.shutdown().await;
+            b.pipeline.replica_closed();
+        }
+        b.replica_pending = 0;
+        b.replica_owed.clear();
+        b.replica_dialed = false;
+        b.replica_addr = None;
+        b.replica_lost = None;
+    }
+    let generation = shared.pool.generation();
+    if b.pool_generation != generation {
+        b.pool_generation = generation;
+        // Session state (protocol, SELECT, CLIENT SETNAME) can't be replayed onto another replica,
+        // so a connection whose replica left the pool continues master-only.
+        if let Some((host, port)) = &b.replica_addr
+            && !shared.pool.contains_replica(host, *port)
+        {
+            tracing::info!(%host, port, "replica left the pool; continuing master-only");
+            if let Some(mut rep) = b.replica.take() {
+                let _ = rep.shutdown().await;
+                b.pipeline.replica_closed();
+            }
+            b.replica_addr = None;
+        }
+    }
+    // A connection to a drained replica is closed before the next command, leaving only reads
+    // already answered behind; reads go to master until the drain ends and it is dialed again.
+    if b.replica.is_some()
+        && let Some((host, port)) = &b.replica_addr
+        && shared.replica_health.drains(host, *port) != b.replica_drains
+    {
+        tracing::debug!(%host, port, "replica drained; closing the connection to it");
+        if let Some(mut rep) = b.replica.take() {
+            let _ = rep.shutdown().await;
+            b.pipeline.replica_closed();
+        }
+        b.replica_pending = 0;
+        b.replica_owed.clear();
+        b.replica_dialed = false;
+    }
+    b.drain_master().await?;
+    Ok(b)
+}
+
+/// [`ensure_backends`] for a command that needs the master: while the master is down the client
+/// is answered `-MASTERDOWN` and `None` is returned.
+async fn backends_or_masterdown<'a>(
+    client: &mut RespStream,
+    backends: &'a mut Option<Backends>,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+) -> Result<Option<&'a mut Backends>> {
+    match ensure_backends(backends, shared, preamble, session, client.version()).await {
+        Ok(b) => Ok(Some(b)),
+        Err(e) if e.is::<MasterDown>() => {
+            client.write_all(MASTERDOWN_REPLY).await?;
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Dial the replica on first use and bring it to the session state of the master connection.
+/// Failures leave the connection master-only until `replica_retry_after*` allows another try.
+async fn ensure_replica(
+    b: &mut Backends,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+) {
+    // Dialed once the breaker closes again.
+    if b.replica_dialed || !shared.replica_breaker.allows_replica() {
+        return;
+    }
+    b.replica_dialed = true;
+
+    let Some(endpoint) = shared
+        .pool
+        .pick_replica(|r| shared.replica_health.is_usable(&r.host, r.port))
+    else {
+        tracing::warn!("no healthy replica in pool; falling back to master-only");
+        shared.pair.set_replica_up(false);
+        b.replica_lost = Some(ReplicaLost::now());
+        return;
+    };
+    let cfg = shared.config();
+    let version = b.master.version();
+    let connected = async {
+        let mut conn = dial_backend(shared, BackendRole::Replica, &endpoint, preamble).await?;
+        let wait = endpoint.read_timeout.unwrap_or(cfg.replica_timeout);
+        timeout(wait, session.replay(&mut conn, version))
+            .await
+            .context("replica session restore timeout")??;
+        anyhow::Ok(conn)
+    }
+    .await;
+    match connected {
+        Ok(conn) => {
+            // Only answered reads reset the breaker: a replica that accepts connections but
+            // times out on every read must still trip it.
+            shared.pair.set_replica_up(true);
+            b.replica = Some(conn);
+            b.replica_pending = 0;
+            b.replica_owed.clear();
+            b.replica_timeout = endpoint.read_timeout;
+            b.replica_drains = shared.replica_health.drains(&endpoint.host, endpoint.port);
+            b.replica_addr = Some((endpoint.host, endpoint.port));
+        }
+        Err(e) => {
+            tracing::warn!(error = ?e, "replica unavailable; falling back to master-only");
+            shared.replica_failed();
+            b.replica_lost = Some(ReplicaLost::now());
+        }
+    }
+}
+
+/// A connection of a client's own to `endpoint`: one `--warm-connections` has ready, or a new
+/// one.
+async fn dial_backend(
+    shared: &Shared,
+    role: BackendRole,
+    endpoint: &RedisEndpoint,
+    preamble: Option<&[u8]>,
+) -> Result<RespStream> {
+    if preamble.is_none()
+        && let Some(conn) = shared.warm.as_ref().and_then(|w| w.checkout(endpoint))
+    {
+        return Ok(conn);
+    }
+    let cfg = shared.config();
+    let mut conn =
+        connect_and_handshake(endpoint, cfg.connect_timeout, &shared.resolver, preamble).await?;
+    record_server_info(shared, role, endpoint, &mut conn).await?;
+    Ok(conn)
+}
+
+/// Ask a new backend connection for its version and mode, for [`BackendVersions`]. Backends
+/// that refuse `INFO` (e.g. renamed away) are only logged at debug level.
+pub async fn record_server_info(
+    shared: &Shared,
+    role: BackendRole,
+    endpoint: &RedisEndpoint,
+    conn: &mut RespStream,
+) -> Result<()> {
+    let addr = format!("{}:{}", endpoint.host, endpoint.port);
+    let wait = endpoint
+        .read_timeout
+        .unwrap_or(shared.config().connect_timeout);
+    match query_server_info(conn, wait).await {
+        Ok(info) => shared.versions.record(role, &addr, info),
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Err(e),
+        Err(e) => tracing::debug!(%addr, error = ?e, "could not read backend server info"),
+    }
+    Ok(())
+}
+
+fn is_auth_exempt(cmd: &ParsedCommand) -> bool {
+    matches!(cmd.name_upper.as_str(), "AUTH" | "HELLO" | "QUIT")
+}
+
+pub fn decide_route(
+    cmd: &ParsedCommand,
+    first_arg_upper: Option<&str>,
+    state: &ConnState,
+    replica_available: bool,
+    read_only_script: bool,
+    replica_reads: &ReplicaReads,
+    key_rules: &PatternRules,
+) -> Route {
+    // Force-master contexts.
+    if state.in_multi || state.watch_active {
+        return Route::Master;
+    }
+
+    let listed = route_cmd(&cmd.name_upper, first_arg_upper, replica_reads);
+    if state.tracking && listed != Route::Both {
+        return Route::Master;
+    }
+    // Key rules, then the connection name's, override the lists, but not connection state both
+    // backends must share.
+    if listed != Route::Both {
+        let rule = key_rules
+            .route_key(&cmd.name_upper, &cmd.args)
+            .or(state.named_route);
+        match rule {
+            Some(Route::Master) => return Route::Master,
+            Some(Route::Replica)
+                if replica_available
+                    && is_read_only(&cmd.name_upper, first_arg_upper)
+                    && !needs_master(&cmd.name_upper, &cmd.args) =>
+            {
+                return Route::Replica;
+            }
+            _ => {}
+        }
+    }
+
+    if read_only_script && replica_available {
+        return Route::Replica;
+    }
+
+    match listed {
+        Route::Both => Route::Both,
+        Route::Replica if replica_available && !needs_master(&cmd.name_upper, &cmd.args) => {
+            Route::Replica
+        }
+        Route::Master
+            if state.readonly
+                && replica_available
+                && replica_reads.readonly_hint(&cmd.name_upper, first_arg_upper)
+                && !needs_master(&cmd.name_upper, &cmd.args) =>
+        {
+            Route::Replica
+        }
+        _ => Route::Master,
+    }
+}
+
+/// The error reply a `PROXY` command gets instead of running on this listener, if any.
+pub fn proxy_refusal(
+    policy: &ListenerPolicy,
+    cfg: &Config,
+    cmd: &ParsedCommand,
+) -> Option<&'static [u8]> {
+    let sub = ProxySubcommand::parse(cmd)?;
+    if sub.is_admin() && !policy.admin_commands(cfg) {
+        Some(ADMIN_DENIED_REPLY)
+    } else if policy.read_only(cfg) && sub.changes_settings(cmd) {
+        Some(READ_ONLY_PROXY_REPLY)
+    } else if policy.admin_only && !sub.is_admin() && sub != ProxySubcommand::Status {
+        Some(ADMIN_ONLY_REPLY)
+    } else {
+        None
+    }
+}
+
+/// The route of `PROXY ROUTE MASTER|REPLICA`.
+pub fn parse_route_hint(cmd: &ParsedCommand) -> Option<Route> {
+    let [_, target] = &cmd.args[..] else {
+        return None;
+    };
+    match target.to_ascii_uppercase().as_slice() {
+        b"MASTER" => Some(Route::Master),
+        b"REPLICA" => Some(Route::Replica),
+        _ => None,
+    }
+}
+
+/// Apply a `PROXY ROUTE` hint to the command after it: `MASTER` sends a replica read to the
+/// master, and `REPLICA` reads any known read-only command from the replica. Writes, commands
+/// sent to both and commands in a transaction keep their route.
+pub fn apply_route_hint(
+    route: Route,
+    hint: Option<Route>,
+    cmd: &ParsedCommand,
+    first_arg_upper: Option<&str>,
+    state: &ConnState,
+    replica_available: bool,
+) -> Route {
+    match hint {
+        Some(Route::Master) if route == Route::Replica => Route::Master,
+        Some(Route::Replica)
+            if route == Route::Master
+                && !state.in_multi
+                && !state.watch_active
+                && !state.tracking
+                && replica_available
+                && is_read_only(&cmd.name_upper, first_arg_upper)
+                && !needs_master(&cmd.name_upper, &cmd.args) =>
+        {
+            Route::Replica
+        }
+        _ => route,
+    }
+}
+
+/// Record a command sent to master for `read_your_writes`.
+fn remember_write(
+    cfg: &Config,
+    shared: &Shared,
+    recent_writes: &mut RecentWrites,
+    cmd: &ParsedCommand,
+) {
+    if let Some(window) = cfg.read_your_writes {
+        recent_writes.record(cmd, window);
+        if cfg.read_your_writes_global {
+            shared
+                .recent_writes
+                .lock()
+                .expect("recent writes lock poisoned")
+                .record(cmd, window);
+        }
+    }
+}
+
+/// Why a replica read of `cmd` is served by master instead, if it is: its key was just written
+/// by this client (or, globally, any client), the connection is pinned after a write, or a
+/// `Consistency::Wait` write is still `unconfirmed`.
+fn master_fallback(
+    cfg: &Config,
+    shared: &Shared,
+    cmd: &ParsedCommand,
+    recent_writes: &RecentWrites,
+    last_write: Option<Instant>,
+    unconfirmed: bool,
+) -> Option<&'static str> {
+    if let Some(window) = cfg.read_your_writes
+        && (recent_writes.touches(cmd, window)
+            || (cfg.read_your_writes_global
+                && shared
+                    .recent_writes
+                    .lock()
+                    .expect("recent writes lock poisoned")
+                    .touches(cmd, window)))
+    {
+        return Some("key written recently");
+    }
+    if let Some(pin) = cfg.pin_after_write
+        && last_write.is_some_and(|at| at.elapsed() < pin)
+    {
+        return Some("pinned to master after a write");
+    }
+    unconfirmed.then_some("write not confirmed by a replica")
+}
+
+/// Run a transaction of replica reads the proxy queued, on the replica unless `fallback` says
+/// why not or the replica fails, and relay the `EXEC` reply. Returns where it ran. The replica
+/// must be dialed and drained already.
+async fn exec_queued_reads(
+    client: &mut RespStream,
+    b: &mut Backends,
+    shared: &Shared,
+    offsets: &mut OffsetGate,
+    queued: &QueuedReads,
+    mut fallback: Option<&'static str>,
+) -> Result<Route> {
+    let cfg = shared.config();
+    let wait = b.replica_timeout.unwrap_or(cfg.replica_timeout);
+    let ran = match b.replica.as_mut() {
+        Some(rep) if fallback.is_none() && shared.replica_allowed(b.replica_addr.as_ref()) => {
+            match offsets.replica_caught_up(rep, wait).await {
+                Ok(true) => Some(queued.exec(rep, Some(wait)).await),
+                Ok(false) => {
+                    fallback = Some("replica behind offset token");
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            }
+        }
+        _ => None,
+    };
+    match ran {
+        Some(Ok(reply)) => {
+            shared.replica_answered();
+            client.write_all(&reply).await?;
+            return Ok(Route::Replica);
+        }
+        Some(Err(e)) => {
+            tracing::warn!(error = ?e, "replica failed during a transaction; falling back to master");
+            if let Some(mut rep) = b.replica.take() {
+                let _ = rep.shutdown().await;
+            }
+            b.replica_gone(&cfg);
+            shared.replica_failed();
+            fallback = Some("replica failed during a transaction");
+        }
+        None => {}
+    }
+    shared
+        .profiler
+        .record_fallback(fallback.unwrap_or("replica unavailable"));
+    let reply = queued.exec(&mut b.master, None).await?;
+    client.write_all(&reply).await?;
+    Ok(Route::Master)
+}
+
+/// Connection state set by the client that a replacement master connection must be given
+/// before it can take over.
+#[derive(Debug, Default)]
+struct SessionReplay {
+    select: Option<Bytes>,
+    setname: Option<Bytes>,
+    /// The name `setname` gives the connection, for `client_rules`.
+    name: Option<Bytes>,
+    /// The connection's key in [`ClientIds`], once the client asked for its ID.
+    client_id: Option<u64>,
+    /// The last `CLIENT TRACKING ON`, which only master has. Its `REDIRECT` is resolved again
+    /// when it is replayed, in case that client's connection was replaced too.
+    tracking: Option<(ParsedCommand, Bytes)>,
+}
+
+impl SessionReplay {
+    fn observe(&mut self, cmd: &ParsedCommand, first_arg_upper: Option<&str>, raw: &Bytes) {
+        // RESET leaves the connection as a new one, with nothing to restore but its ID.
+        if cmd.name_upper == "RESET" {
+            *self = Self {
+                client_id: self.client_id,
+                ..Self::default()
+            };
+            return;
+        }
+        if Self::restores(cmd, first_arg_upper) {
+            match cmd.name_upper.as_str() {
+                "SELECT" => self.select = Some(raw.clone()),
+                _ => {
+                    self.setname = Some(raw.clone());
+                    self.name = cmd.args.get(1).cloned();
+                }
+            }
+        }
+        match (cmd.name_upper.as_str(), first_arg_upper, cmd.args.get(1)) {
+            ("CLIENT", Some("TRACKING"), Some(on)) if on.eq_ignore_ascii_case(b"ON") => {
+                self.tracking = Some((cmd.clone(), raw.clone()));
+            }
+            ("CLIENT", Some("TRACKING"), Some(off)) if off.eq_ignore_ascii_case(b"OFF") => {
+                self.tracking = None;
+            }
+            ("RESET", _, _) => self.tracking = None,
+            _ => {}
+        }
+    }
+
+    /// `HELLO ... SETNAME name` names the connection like `CLIENT SETNAME`.
+    fn observe_hello(&mut self, hello: &HelloRequest) {
+        if let Some(name) = &hello.setname {
+            self.setname = Some(Bytes::from(encode_command_str(&[
+                "CLIENT", "SETNAME", name,
+            ])));
+            self.name = Some(Bytes::from(name.clone()));
+        }
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        self.name.as_deref()
+    }
+
+    /// Whether the client has set no state a backend connection would need.
+    fn is_empty(&self) -> bool {
+        self.select.is_none() && self.setname.is_none() && self.tracking.is_none()
+    }
+
+    /// Whether [`SessionReplay::replay`] restores the state `cmd` sets.
+    fn restores(cmd: &ParsedCommand, first_arg_upper: Option<&str>) -> bool {
+        matches!(
+            (cmd.name_upper.as_str(), first_arg_upper),
+            ("SELECT", _) | ("CLIENT", Some("SETNAME"))
+        )
+    }
+
+    /// Bring a fresh connection to the state of the one it replaces.
+    async fn replay(&self, conn: &mut RespStream, version: RespVersion) -> Result<()> {
+        if version == RespVersion::Resp3 {
+            conn.write_all(&encode_command_str(&["HELLO", "3"])).await?;
+            conn.set_version(RespVersion::Resp3);
+            expect_ok(conn).await?;
+        }
+        for cmd in [&self.select, &self.setname].into_iter().flatten() {
+            conn.write_all(cmd).await?;
+            expect_ok(conn).await?;
+        }
+        Ok(())
+    }
+
+    /// [`SessionReplay::replay`] for a replacement master connection, whose client ID then
+    /// stands for the IDs the client was told, and which tracks keys for the client again.
+    async fn replay_master(
+        &self,
+        conn: &mut RespStream,
+        version: RespVersion,
+        ids: &ClientIds,
+    ) -> Result<()> {
+        self.replay(conn, version).await?;
+        if let Some(key) = self.client_id {
+            conn.write_all(&encode_command_str(&["CLIENT", "ID"]))
+                .await?;
+            let (frame, _) = conn
+                .read_frame()
+                .await?
+                .ok_or_else(|| anyhow!("closed while restoring session"))?;
+            match Reply::from_frame(&frame) {
+                Reply::Int(id) => ids.moved(key, id as u64),
+                reply => tracing::debug!(?reply, "master did not tell the client ID"),
+            }
+        }
+        if let Some((cmd, raw)) = &self.tracking {
+            let (mut cmd, mut raw) = (cmd.clone(), raw.clone());
+            redirect_tracking(&mut cmd, &mut raw, ids);
+            conn.write_all(&raw).await?;
+            expect_ok(conn).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn expect_ok(conn: &mut RespStream) -> Result<()> {
+    let (frame, raw) = conn
+        .read_frame()
+        .await?
+        .ok_or_else(|| anyhow!("closed while restoring session"))?;
+    if is_error_reply(&frame) {
+        return Err(anyhow!(
+            "restoring session failed: {}",
+            String::from_utf8_lossy(&raw)
+        ));
+    }
+    Ok(())
+}
+
+pub fn update_state(state: &mut ConnState, cmd: &ParsedCommand) {
+    match cmd.name_upper.as_str() {
+        "MULTI" => state.in_multi = true,
+        "EXEC" | "DISCARD" => {
+            state.in_multi = false;
+            state.watch_active = false; // EXEC/DISCARD clears WATCH.
+        }
+        "WATCH" => state.watch_active = true,
+        "UNWATCH" => state.watch_active = false,
+        "READONLY" => state.readonly = true,
+        "READWRITE" => state.readonly = false,
+        // RESET discards the transaction and every mode the connection was in.
+        "RESET" => {
+            state.in_multi = false;
+            state.watch_active = false;
+            state.readonly = false;
+            state.tracking = false;
+        }
+        "CLIENT"
+            if cmd
+                .args
+                .first()
+                .is_some_and(|a| a.eq_ignore_ascii_case(b"TRACKING")) =>
+        {
+            match cmd.args.get(1) {
+                Some(on) if on.eq_ignore_ascii_case(b"ON") => state.tracking = true,
+                Some(off) if off.eq_ignore_ascii_case(b"OFF") => state.tracking = false,
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether a scripting command runs a script or function flagged `no-writes`.
+///
+/// `FCALL` lookups refresh the shared `FUNCTION LIST` snapshot over this client's master link.
+async fn script_is_read_only(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    scripts: &ScriptRegistry,
+    cmd: &ParsedCommand,
+) -> Result<bool> {
+    let Some(first) = cmd.args.first() else {
+        return Ok(false);
+    };
+
+    match cmd.name_upper.as_str() {
+        "EVAL" => Ok(shebang_no_writes(first)),
+        "EVALSHA" => Ok(scripts.is_read_only_sha(first)),
+        "FCALL" => {
+            if scripts.functions_stale() {
+                master
+                    .write_all(&encode_command_str(&["FUNCTION", "LIST"]))
+                    .await?;
+                let (frame, _raw) = read_one_reply_from_master(master, client).await?;
+                match Reply::from_frame(&frame) {
+                    Reply::Error(e) => {
+                        // e.g. Redis < 7. Cache the empty result so we don't ask on every call.
+                        tracing::debug!(error = %e, "FUNCTION LIST failed; treating functions as writable");
+                        scripts.set_functions(Default::default());
+                    }
+                    reply @ Reply::List(_) => scripts.set_functions(parse_function_list(&reply)),
+                    reply => {
+                        tracing::debug!(?reply, "unexpected reply to FUNCTION LIST; not cached");
+                        return Ok(false);
+                    }
+                }
+            }
+            Ok(scripts.is_read_only_function(first))
+        }
+        _ => Ok(false),
+    }
+}
+
+fn track_scripts(scripts: &ScriptRegistry, cmd: &ParsedCommand, first_arg_upper: Option<&str>) {
+    match (cmd.name_upper.as_str(), first_arg_upper) {
+        ("SCRIPT", Some("LOAD")) => {
+            if let Some(body) = cmd.args.get(1) {
+                scripts.record_script_load(body);
+            }
+        }
+        ("SCRIPT", Some("FLUSH")) => scripts.flush_scripts(),
+        ("FUNCTION", Some("LOAD" | "DELETE" | "FLUSH" | "RESTORE")) => {
+            scripts.invalidate_functions()
+        }
+        _ => {}
+    }
+}
+
+/// `CLIENT TRACKING ON ... REDIRECT <id>`: an ID told to a client names the master connection
+/// it has now, see [`ClientIds`].
+fn redirect_tracking(cmd: &mut ParsedCommand, raw: &mut Bytes, ids: &ClientIds) {
+    if cmd.name_upper != "CLIENT"
+        || !cmd
+            .args
+            .first()
+            .is_some_and(|a| a.eq_ignore_ascii_case(b"TRACKING"))
+    {
+        return;
+    }
+    let Some(at) = cmd
+        .args
+        .iter()
+        .position(|a| a.eq_ignore_ascii_case(b"REDIRECT"))
+        .map(|at| at + 1)
+    else {
+        return;
+    };
+    let Some(id) = cmd
+        .args
+        .get(at)
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .and_then(|id| id.parse::<u64>().ok())
+    else {
+        return;
+    };
+    let resolved = ids.resolve(id);
+    if resolved == id {
+        return;
+    }
+    cmd.args[at] = Bytes::from(resolved.to_string());
+    let mut parts = Vec::with_capacity(cmd.args.len() + 1);
+    parts.push(Bytes::from_static(b"CLIENT"));
+    parts.extend(cmd.args.clone());
+    *raw = encode_command(&parts).freeze();
+}
+
+fn rewrite_command_name(cmd: &mut ParsedCommand, raw: &mut Bytes, new_name: &str) {
+    cmd.name_upper = new_name.to_string();
+
+    let mut parts = Vec::with_capacity(cmd.args.len() + 1);
+    parts.push(Bytes::copy_from_slice(new_name.as_bytes()));
+    parts.extend(cmd.args.clone());
+
+    let encoded = encode_command(&parts);
+    *raw = encoded.freeze();
+}
+
+async fn handle_auth(
+    client: &mut RespStream,
+    authenticated: &mut bool,
+    user: &mut String,
+    proxy_auth: &ProxyAuth,
+    cmd: &ParsedCommand,
+) -> Result<()> {
+    let (username, pass) = match cmd.args.len() {
+        1 => (
+            "default".to_string(),
+            String::from_utf8_lossy(&cmd.args[0]).to_string(),
+        ),
+        2 => (
+            String::from_utf8_lossy(&cmd.args[0]).to_string(),
             String::from_utf8_lossy(&cmd.args[1]).to_string(),
         ),
         _ => {
@@ -243,8 +2256,9 @@ async fn handle_auth(
         }
     };
 
-    if proxy_auth.verify(&user, &pass) {
+    if proxy_auth.verify(&username, &pass) {
         *authenticated = true;
+        *user = username;
         client.write_all(b"+OK\r\n").await?;
     } else {
         client
@@ -255,37 +2269,551 @@ async fn handle_auth(
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn handle_hello(
+/// `PROXY OFFSET`: the master's replication offset, read over this client's master connection
+/// so it covers the client's own writes.
+async fn proxy_offset(
+    client: &mut RespStream,
+    backends: &mut Option<Backends>,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+    state: &ConnState,
+    cmd: &ParsedCommand,
+) -> Result<()> {
+    let reply = if cmd.args.len() != 1 {
+        b"-ERR usage: PROXY OFFSET\r\n".to_vec()
+    } else if state.in_multi {
+        b"-ERR PROXY OFFSET is not allowed in MULTI\r\n".to_vec()
+    } else {
+        let Some(b) = backends_or_masterdown(client, backends, shared, preamble, session).await?
+        else {
+            return Ok(());
+        };
+        match master_offset(&mut b.master).await? {
+            Ok(offset) => format!(":{offset}\r\n").into_bytes(),
+            Err(e) => format!("-ERR {e}\r\n").into_bytes(),
+        }
+    };
+    client.write_all(&reply).await
+}
+
+/// `PROXY PAGE <page-size> <command> <args...>`, see [`PageRequest`]. The pages are read from
+/// the replica when this connection may use it (without `PROXY MINOFFSET` gating), and from the
+/// master if there is none or it fails midway.
+async fn proxy_page(
+    client: &mut RespStream,
+    backends: &mut Option<Backends>,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+    cmd: &ParsedCommand,
+    replica_reads: bool,
+) -> Result<()> {
+    let request = match PageRequest::parse(&cmd.args[1..]) {
+        Ok(request) => request,
+        Err(e) => return client.write_all(format!("-ERR {e}\r\n").as_bytes()).await,
+    };
+    let Some(b) = backends_or_masterdown(client, backends, shared, preamble, session).await? else {
+        return Ok(());
+    };
+    if replica_reads {
+        ensure_replica(b, shared, preamble, session).await;
+    }
+    b.drain_replica(shared).await;
+    if let Some(rep) = b.replica.as_mut() {
+        let wait = b.replica_timeout.unwrap_or(shared.config().replica_timeout);
+        match request.run(rep, Some(wait)).await {
+            Ok(reply) => return client.write_all(&reply).await,
+            Err(e) => {
+                tracing::warn!(error = ?e, "replica failed during PROXY PAGE; falling back to master");
+                if let Some(mut rep) = b.replica.take() {
+                    let _ = rep.shutdown().await;
+                }
+                b.replica_gone(&shared.config());
+                shared.replica_failed();
+            }
+        }
+    }
+    let reply = request.run(&mut b.master, None).await?;
+    client.write_all(&reply).await
+}
+
+/// The consistency mode of a connection that hasn't picked one with `PROXY CONSISTENCY`.
+fn default_consistency(cfg: &Config, user: &str) -> Consistency {
+    if cfg.consistency_wait_users.iter().any(|u| u == user) {
+        Consistency::Wait
+    } else {
+        cfg.consistency
+    }
+}
+
+/// `PROXY CONSISTENCY [EVENTUAL|WAIT]`: set this connection's mode, or show it without an
+/// argument.
+async fn proxy_consistency(
+    client: &mut RespStream,
+    consistency: &mut Option<Consistency>,
+    default: Consistency,
+    cmd: &ParsedCommand,
+) -> Result<()> {
+    let mode = cmd
+        .args
+        .get(1)
+        .map(|b| String::from_utf8_lossy(b).to_ascii_lowercase());
+    match (mode.as_deref(), cmd.args.len()) {
+        (None, _) => {
+            let mode = consistency.unwrap_or(default);
+            client.write_all(&encode_bulk(mode.name())).await
+        }
+        (Some("eventual"), 2) => {
+            *consistency = Some(Consistency::Eventual);
+            client.write_all(b"+OK\r\n").await
+        }
+        (Some("wait"), 2) => {
+            *consistency = Some(Consistency::Wait);
+            client.write_all(b"+OK\r\n").await
+        }
+        _ => {
+            client
+                .write_all(b"-ERR usage: PROXY CONSISTENCY [EVENTUAL|WAIT]\r\n")
+                .await
+        }
+    }
+}
+
+/// `PROXY MINOFFSET <offset>`: serve this connection's replica reads only from a replica that
+/// has reached `offset`; 0 lifts the requirement.
+async fn proxy_minoffset(
+    client: &mut RespStream,
+    offsets: &mut OffsetGate,
+    cmd: &ParsedCommand,
+) -> Result<()> {
+    let offset = cmd
+        .args
+        .get(1)
+        .filter(|_| cmd.args.len() == 2)
+        .and_then(|b| std::str::from_utf8(b).ok())
+        .and_then(|s| s.parse::<u64>().ok());
+    let reply: &[u8] = match offset {
+        Some(offset) => {
+            offsets.set_required(offset);
+            b"+OK\r\n"
+        }
+        None => b"-ERR usage: PROXY MINOFFSET <offset>\r\n",
+    };
+    client.write_all(reply).await
+}
+
+/// Proxy-local `PROXY <subcommand>` commands; these never reach the backends.
+async fn handle_proxy_command(
+    client: &mut RespStream,
+    shared: &Shared,
+    cmd: &ParsedCommand,
+    sub: Option<ProxySubcommand>,
+) -> Result<()> {
+    let profiler = &shared.profiler;
+
+    match sub {
+        Some(ProxySubcommand::Profile) => {
+            let Some(millis) = cmd
+                .args
+                .get(1)
+                .and_then(|b| std::str::from_utf8(b).ok())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                client
+                    .write_all(b"-ERR usage: PROXY PROFILE <milliseconds>\r\n")
+                    .await?;
+                return Ok(());
+            };
+            let duration = std::time::Duration::from_millis(millis);
+            if !(MIN_PROFILE_DURATION..=MAX_PROFILE_DURATION).contains(&duration) {
+                let msg = format!(
+                    "-ERR profile duration must be between {} and {} ms\r\n",
+                    MIN_PROFILE_DURATION.as_millis(),
+                    MAX_PROFILE_DURATION.as_millis()
+                );
+                client.write_all(msg.as_bytes()).await?;
+                return Ok(());
+            }
+            if !profiler.start() {
+                client
+                    .write_all(b"-ERR a profile is already running\r\n")
+                    .await?;
+                return Ok(());
+            }
+
+            tracing::info!(duration_ms = millis, "profile started");
+            tokio::time::sleep(duration).await;
+            let report = profiler.finish().map(|r| r.to_string()).unwrap_or_default();
+            tracing::info!("profile finished");
+
+            client.write_all(&encode_bulk(&report)).await?;
+        }
+        Some(ProxySubcommand::Status) => {
+            let status = format!(
+                "{}replica_breaker:{}\r\nconnection_panics:{}\r\nreplica_read_changes:{}\r\n{}{}{}{}{}",
+                shared.pair.render(),
+                shared.replica_breaker.name(),
+                shared.connection_panics.load(Ordering::Relaxed),
+                shared.config().replica_reads.changes().join(","),
+                shared.pause.render(),
+                shared.warm.as_ref().map(|w| w.render()).unwrap_or_default(),
+                shared.replica_health.render_drained(),
+                shared.replica_health.render_loading(),
+                shared.replica_health.render()
+            );
+            client.write_all(&encode_bulk(&status)).await?;
+        }
+        Some(ProxySubcommand::Slowlog) => {
+            let arg = |i: usize| {
+                cmd.args
+                    .get(i)
+                    .map(|b| String::from_utf8_lossy(b).to_ascii_uppercase())
+            };
+            let reply = match (arg(1).as_deref(), arg(2)) {
+                (Some("GET"), None) => shared.slowlog.render(SLOWLOG_DEFAULT_COUNT),
+                (Some("GET"), Some(n)) => match n.parse::<usize>() {
+                    Ok(n) => shared.slowlog.render(n),
+                    Err(_) => b"-ERR count must be a non-negative integer\r\n".to_vec(),
+                },
+                (Some("LEN"), None) => format!(":{}\r\n", shared.slowlog.len()).into_bytes(),
+                (Some("RESET"), None) => {
+                    shared.slowlog.reset();
+                    b"+OK\r\n".to_vec()
+                }
+                _ => b"-ERR usage: PROXY SLOWLOG GET [count] | LEN | RESET\r\n".to_vec(),
+            };
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Config) => {
+            let reply = proxy_config(shared, &cmd.args[1..]);
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Whitelist) => {
+            let reply = proxy_whitelist(shared, &cmd.args[1..]);
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Replica) => {
+            let reply = proxy_replica(shared, &cmd.args[1..]);
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Switch) => {
+            let reply = proxy_switch(shared, &cmd.args[1..]).await;
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Pause) => {
+            let reply = proxy_pause(shared, &cmd.args[1..]);
+            client.write_all(&reply).await?;
+        }
+        Some(ProxySubcommand::Unpause) => {
+            if shared.pause.resume() {
+                tracing::info!("traffic pause ended by PROXY UNPAUSE");
+            }
+            client.write_all(b"+OK\r\n").await?;
+        }
+        _ => {
+            client
+                .write_all(b"-ERR unknown PROXY subcommand\r\n")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// `PROXY SWITCH MASTER|REPLICA url [MIGRATE]`: check that `url` answers with the expected
+/// `ROLE`, then send new connections to it instead; a replica replaces every replica in the
+/// pool. With `MIGRATE`, existing connections move too: to the master at their next command
+/// outside a transaction, to the replica at their next replica read. Without it they keep
+/// their master, and go master-only if their replica left the pool.
+///
+/// Timeouts set for the endpoint being replaced carry over. Discovery and `--endpoints-file`
+/// still apply their next change on top.
+async fn proxy_switch(shared: &Shared, args: &[Bytes]) -> Vec<u8> {
+    const USAGE: &[u8] = b"-ERR usage: PROXY SWITCH MASTER|REPLICA <url> [MIGRATE]\r\n";
+    let args: Vec<String> = args
+        .iter()
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .collect();
+    let (role, url, migrate) = match args.as_slice() {
+        [role, url] => (role.to_ascii_uppercase(), url, false),
+        [role, url, flag] if flag.eq_ignore_ascii_case("MIGRATE") => {
+            (role.to_ascii_uppercase(), url, true)
+        }
+        _ => return USAGE.to_vec(),
+    };
+    let (current, expected_role) = match role.as_str() {
+        "MASTER" => (Some(shared.pool.master()), "master"),
+        "REPLICA" => (shared.pool.replicas().into_iter().next(), "slave"),
+        _ => return USAGE.to_vec(),
+    };
+    let mut endpoint = match RedisEndpoint::from_redis_url(url) {
+        Ok(endpoint) => endpoint,
+        Err(e) => return format!("-ERR {e}\r\n").into_bytes(),
+    };
+    if let Some(current) = current {
+        endpoint.connect_timeout = current.connect_timeout;
+        endpoint.read_timeout = current.read_timeout;
+    }
+
+    let cfg = shared.config();
+    let detail = match crate::check::probe(&cfg, &endpoint, expected_role, &shared.resolver).await {
+        Ok(detail) => detail,
+        Err(e) => {
+            let e = format!("{e:#}").replace(['\r', '\n'], " ");
+            return format!("-ERR {role} {url} failed its check: {e}\r\n").into_bytes();
+        }
+    };
+    tracing::info!(
+        role = %role.to_ascii_lowercase(),
+        host = %endpoint.host,
+        port = endpoint.port,
+        migrate,
+        %detail,
+        "backend switched by PROXY SWITCH"
+    );
+    match role.as_str() {
+        "MASTER" => shared.pool.switch_master(endpoint, migrate),
+        _ => shared.pool.switch_replica(endpoint, migrate),
+    }
+    b"+OK\r\n".to_vec()
+}
+
+/// `PROXY PAUSE milliseconds [WRITE|ALL]`: hold client commands, or only those `read_only`
+/// would refuse, until the time is up or `PROXY UNPAUSE`. `PROXY` commands are never held.
+fn proxy_pause(shared: &Shared, args: &[Bytes]) -> Vec<u8> {
+    const USAGE: &[u8] = b"-ERR usage: PROXY PAUSE <milliseconds> [WRITE|ALL]\r\n";
+    let millis = args
+        .first()
+        .and_then(|b| std::str::from_utf8(b).ok())
+        .and_then(|s| s.parse::<u64>().ok());
+    let writes_only = match args.get(1).map(|b| b.to_ascii_uppercase()).as_deref() {
+        None | Some(b"ALL") => false,
+        Some(b"WRITE") => true,
+        Some(_) => return USAGE.to_vec(),
+    };
+    let (Some(millis), None) = (millis, args.get(2)) else {
+        return USAGE.to_vec();
+    };
+    let duration = Duration::from_millis(millis);
+    if duration > MAX_PAUSE_DURATION {
+        return format!(
+            "-ERR pause must be at most {} ms\r\n",
+            MAX_PAUSE_DURATION.as_millis()
+        )
+        .into_bytes();
+    }
+    shared.pause.pause(duration, writes_only);
+    tracing::info!(
+        duration_ms = millis,
+        writes_only,
+        "traffic paused by PROXY PAUSE"
+    );
+    b"+OK\r\n".to_vec()
+}
+
+/// `PROXY REPLICA DRAIN [host:port] | RESUME [host:port]`: stop sending reads to a replica, or
+/// every replica, so it can be restarted without clients timing out on it, and start again.
+/// Reads go to master meanwhile; each connection closes its connection to a drained replica
+/// before its next command, once the reads already sent to it are answered.
+fn proxy_replica(shared: &Shared, args: &[Bytes]) -> Vec<u8> {
+    let args: Vec<String> = args
+        .iter()
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .collect();
+    let sub = args.first().map(|s| s.to_ascii_uppercase());
+    let addr = match args.get(1..).unwrap_or_default() {
+        [] => None,
+        [addr] => match addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.to_string(), port.parse::<u16>().ok()?)))
+        {
+            Some((host, port)) if shared.pool.contains_replica(&host, port) => Some((host, port)),
+            _ => return format!("-ERR '{addr}' is not a replica in the pool\r\n").into_bytes(),
+        },
+        _ => {
+            return b"-ERR usage: PROXY REPLICA DRAIN [host:port] | RESUME [host:port]\r\n"
+                .to_vec();
+        }
+    };
+    let target = addr
+        .as_ref()
+        .map_or("all".to_string(), |(host, port)| format!("{host}:{port}"));
+    let changed = match sub.as_deref() {
+        Some("DRAIN") => {
+            let changed = shared.replica_health.drain(addr);
+            if changed {
+                tracing::info!(replica = %target, "replica drained; reads go to master");
+            }
+            changed
+        }
+        Some("RESUME") => {
+            let changed = shared.replica_health.resume(addr);
+            if changed {
+                tracing::info!(replica = %target, "replica drain over; reads use it again");
+            }
+            changed
+        }
+        _ => {
+            return b"-ERR usage: PROXY REPLICA DRAIN [host:port] | RESUME [host:port]\r\n"
+                .to_vec();
+        }
+    };
+    format!(":{}\r\n", u8::from(changed)).into_bytes()
+}
+
+/// `PROXY WHITELIST ADD cmd | REMOVE cmd | LIST`: change which commands are read from the
+/// replica until the next reload, for every connection's next command. `cmd` may be a
+/// `COMMAND SUBCOMMAND` pair.
+fn proxy_whitelist(shared: &Shared, args: &[Bytes]) -> Vec<u8> {
+    let args: Vec<String> = args
+        .iter()
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .collect();
+    let sub = args.first().map(|s| s.to_ascii_uppercase());
+    let target = args.get(1..).unwrap_or_default().join(" ");
+    let mut cfg = (*shared.config()).clone();
+    let changed = match sub.as_deref() {
+        Some("LIST") if target.is_empty() => {
+            let commands = cfg.replica_reads.commands();
+            let mut out = format!("*{}\r\n", commands.len()).into_bytes();
+            for c in commands {
+                out.extend(encode_bulk(c));
+            }
+            return out;
+        }
+        Some("ADD") if !target.is_empty() => match cfg.replica_reads.add(&target) {
+            Ok(changed) => changed,
+            Err(e) => return format!("-ERR {e}\r\n").into_bytes(),
+        },
+        Some("REMOVE") if !target.is_empty() => cfg.replica_reads.remove(&target),
+        _ => return b"-ERR usage: PROXY WHITELIST ADD cmd | REMOVE cmd | LIST\r\n".to_vec(),
+    };
+    if changed {
+        tracing::info!(
+            change = %format!("{} {target}", sub.unwrap_or_default()),
+            replica_read_changes = %cfg.replica_reads.changes().join(","),
+            "replica read list changed by PROXY WHITELIST"
+        );
+        shared.set_config(cfg);
+    }
+    format!(":{}\r\n", u8::from(changed)).into_bytes()
+}
+
+/// `PROXY CONFIG GET pattern | SET name value [name value ...] | REWRITE`.
+fn proxy_config(shared: &Shared, args: &[Bytes]) -> Vec<u8> {
+    let args: Vec<String> = args
+        .iter()
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .collect();
+    let sub = args.first().map(|s| s.to_ascii_uppercase());
+    let result = match (sub.as_deref(), args.get(1..).unwrap_or_default()) {
+        (Some("GET"), [pattern]) => {
+            let pairs = tunables::get(shared, pattern);
+            let mut out = format!("*{}\r\n", pairs.len() * 2).into_bytes();
+            for (name, value) in pairs {
+                out.extend(encode_bulk(&name));
+                out.extend(encode_bulk(&value));
+            }
+            return out;
+        }
+        (Some("SET"), rest) if !rest.is_empty() && rest.len() % 2 == 0 => {
+            let pairs: Vec<_> = rest
+                .chunks(2)
+                .map(|p| (p[0].clone(), p[1].clone()))
+                .collect();
+            tunables::set(shared, &pairs)
+        }
+        (Some("REWRITE"), []) => tunables::rewrite(shared),
+        _ => {
+            return b"-ERR usage: PROXY CONFIG GET pattern | SET name value [name value ...] | REWRITE\r\n"
+                .to_vec();
+        }
+    };
+    match result {
+        Ok(()) => b"+OK\r\n".to_vec(),
+        // Error replies are a single line.
+        Err(e) => format!("-ERR {}\r\n", format!("{e:#}").replace(['\r', '\n'], " ")).into_bytes(),
+    }
+}
+
+fn encode_bulk(s: &str) -> Vec<u8> {
+    let mut out = format!("${}\r\n", s.len()).into_bytes();
+    out.extend_from_slice(s.as_bytes());
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// HELLO's reply while the master is down, so clients can finish connecting.
+fn local_hello_reply(target: RespVersion, server_version: &str) -> Vec<u8> {
+    let proto = match target {
+        RespVersion::Resp2 => 2,
+        RespVersion::Resp3 => 3,
+    };
+    let mut out = match target {
+        RespVersion::Resp2 => b"*14\r\n".to_vec(),
+        RespVersion::Resp3 => b"%7\r\n".to_vec(),
+    };
+    for (key, value) in [
+        ("server", encode_bulk("redis")),
+        ("version", encode_bulk(server_version)),
+        ("proto", format!(":{proto}\r\n").into_bytes()),
+        ("id", b":0\r\n".to_vec()),
+        ("mode", encode_bulk("standalone")),
+        ("role", encode_bulk("master")),
+        ("modules", b"*0\r\n".to_vec()),
+    ] {
+        out.extend_from_slice(&encode_bulk(key));
+        out.extend_from_slice(&value);
+    }
+    out
+}
+
+/// Apply HELLO's AUTH option against proxy-level auth.
+///
+/// Returns `Ok(false)` if an error reply was sent and HELLO must not proceed.
+async fn authorize_hello(
     client: &mut RespStream,
-    master: &mut RespStream,
-    replica: &mut Option<RespStream>,
     authenticated: &mut bool,
+    user: &mut String,
     proxy_auth: &ProxyAuth,
-    replica_timeout: std::time::Duration,
-    stats: &Arc<Stats>,
-    hello: HelloRequest,
-) -> Result<()> {
+    hello: &HelloRequest,
+) -> Result<bool> {
     // If proxy-level auth is required, HELLO must either already be authenticated or carry AUTH.
     if proxy_auth.enabled {
         if let Some((u, p)) = &hello.auth {
             if proxy_auth.verify(u, p) {
                 *authenticated = true;
+                *user = u.clone();
             } else {
                 client
                     .write_all(b"-WRONGPASS invalid username-password pair\r\n")
                     .await?;
-                return Ok(());
+                return Ok(false);
             }
         }
         if !*authenticated {
             client
                 .write_all(b"-NOAUTH Authentication required.\r\n")
                 .await?;
-            return Ok(());
+            return Ok(false);
         }
+    } else if let Some((u, _)) = &hello.auth {
+        // Not enforced, but still useful to attribute stats.
+        *user = u.clone();
     }
 
+    Ok(true)
+}
+
+async fn handle_hello(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    replica: &mut Option<RespStream>,
+    replica_owed: &mut VecDeque<ReplicaOwed>,
+    stats: &Stats,
+    user: &str,
+    hello: HelloRequest,
+) -> Result<()> {
     // Determine target protocol for this connection.
     let target = hello.protover.unwrap_or(client.version());
 
@@ -307,94 +2835,691 @@ async fn handle_hello(
 
     // Stats: HELLO is effectively BOTH when replica is available, otherwise master-only.
     if replica.is_some() {
-        stats.record(Route::Both, "HELLO");
+        stats.record(user, Route::Both, "HELLO");
     } else {
-        stats.record(Route::Master, "HELLO");
+        stats.record(user, Route::Master, "HELLO");
+    }
+
+    // Send to master, switch protocol before reading the response.
+    master.write_all(&hello_cmd).await?;
+    master.set_version(target);
+
+    if replica.is_some() {
+        // Avoid holding a mutable borrow across `.await` so we can disable the replica on failures.
+        let write_result = {
+            let rep = replica.as_mut().unwrap();
+            rep.write_all(&hello_cmd).await
+        };
+
+        match write_result {
+            Ok(()) => {
+                if let Some(rep) = replica.as_mut() {
+                    rep.set_version(target);
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error=?e, "replica write failed during HELLO; disabling replica");
+                if let Some(mut rep) = replica.take() {
+                    let _ = rep.shutdown().await;
+                }
+            }
+        }
+    }
+
+    // Read master's HELLO response and forward to client.
+    let (_frame, raw) = read_one_reply_from_master(master, client).await?;
+    client.set_version(target);
+    client.write_all(&raw).await?;
+
+    // The replica's reply is read once the client has moved on.
+    if replica.is_some() {
+        replica_owed.push_back(ReplicaOwed {
+            command: "HELLO".to_string(),
+            user: user.to_string(),
+            master_reply: None,
+        });
+    }
+
+    Ok(())
+}
+
+async fn forward_master(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+) -> Result<()> {
+    master.write_all(raw.as_ref()).await?;
+    let (_frame, reply_raw) = read_one_reply_from_master(master, client).await?;
+    client.write_all(reply_raw.as_ref()).await?;
+    Ok(())
+}
+
+/// [`forward_master`], failing with [`MasterTimeout`] if master takes longer than `wait` to
+/// answer. A [transient error](is_transient_error) has the command sent again after a pause
+/// while `retry` lasts and its [`Retries`] allow.
+async fn forward_master_timed(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+    wait: Option<Duration>,
+    retry: Option<(Duration, Retries<'_>)>,
+) -> Result<()> {
+    if wait.is_none() && retry.is_none() {
+        return forward_master(client, master, raw).await;
+    }
+    let deadline = retry.map(|(window, _)| Instant::now() + window);
+    let mut backoff = retry.map_or(Duration::ZERO, |(_, retries)| retries.backoff());
+    let mut attempts = 0u32;
+    loop {
+        let answered = async {
+            master.write_all(raw.as_ref()).await?;
+            read_one_reply_from_master(master, client).await
+        };
+        let (frame, reply_raw) = match wait {
+            Some(wait) => timeout(wait, answered).await.map_err(|_| MasterTimeout)??,
+            None => answered.await?,
+        };
+        let left = deadline.map_or(Duration::ZERO, |d| {
+            d.saturating_duration_since(Instant::now())
+        });
+        if !is_transient_error(&frame)
+            || left.is_zero()
+            || !retry.is_some_and(|(_, retries)| retries.allow(attempts + 1))
+        {
+            if attempts > 0 && is_transient_error(&frame) {
+                tracing::warn!(
+                    attempts,
+                    "master still can't serve the read after its retries; returning the error"
+                );
+            } else if attempts > 0 {
+                tracing::info!(attempts, "master served the read after retries");
+            }
+            client.write_all(reply_raw.as_ref()).await?;
+            return Ok(());
+        }
+        if attempts == 0 {
+            tracing::debug!(reply = %String::from_utf8_lossy(&reply_raw).trim_end(), "master can't serve the read just now; retrying");
+        }
+        // The last attempt is made when the window ends.
+        tokio::time::sleep(backoff.min(left)).await;
+        backoff = (backoff * 2).min(TRANSIENT_RETRY_BACKOFF_MAX);
+        attempts += 1;
+    }
+}
+
+/// [`forward_master`] for a [blocking](is_blocking) command, which may rightly wait on master for
+/// as long as its timeout says, or forever with 0. Fails with [`ClientGone`] if the client
+/// disconnects meanwhile: the master connection must then be closed, which unblocks the command
+/// on the server, as `CLIENT UNBLOCK` would, before it takes data no one will receive.
+async fn forward_master_blocking(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+) -> Result<()> {
+    let gone = client.peer_closed();
+    master.write_all(raw.as_ref()).await?;
+    let (_frame, reply_raw) = tokio::select! {
+        reply = read_one_reply_from_master(master, client) => reply?,
+        () = gone => return Err(ClientGone.into()),
+    };
+    client.write_all(reply_raw.as_ref()).await?;
+    Ok(())
+}
+
+/// Forward `CLIENT ID`, taking note of the ID in [`ClientIds`].
+async fn forward_client_id(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+    shared: &Shared,
+    key: &ClientKey<'_>,
+) -> Result<()> {
+    master.write_all(raw.as_ref()).await?;
+    let (frame, reply_raw) = read_one_reply_from_master(master, client).await?;
+    if let Reply::Int(id) = Reply::from_frame(&frame) {
+        shared.client_ids.told(key.get(), id as u64);
+    }
+    client.write_all(reply_raw.as_ref()).await?;
+    Ok(())
+}
+
+/// Subscriber mode, entered with `raw`, a (P/S)SUBSCRIBE: every frame master sends is relayed
+/// to the client as it comes, subscription replies and messages alike, and the client's
+/// commands go to master as they are, until nothing is subscribed any more. Then the connection
+/// is back in normal mode. Fails with [`ClientGone`] once the client leaves.
+///
+/// A command is read from the client only once master answered the ones before it, so that
+/// the subscription counts say whether one after an unsubscribe still belongs here.
+///
+/// With `--replica-expired-events`, keyspace notification (un)subscribes are made again on a
+/// replica connection of their own, whose `expired` events are relayed too.
+async fn relay_subscribed(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+    cmd: &ParsedCommand,
+    shared: &Shared,
+    user: &str,
+) -> Result<()> {
+    let mut subs = Subscriptions::default();
+    let mut quitting = false;
+    let mut mirror = None;
+    master.write_all(raw.as_ref()).await?;
+    subs.sent(cmd);
+    mirror_notifications(&mut mirror, cmd, shared).await;
+    loop {
+        tokio::select! {
+            next = client.read_frame(), if subs.owed.is_empty() => {
+                let Some((frame, raw)) = next? else {
+                    return Err(ClientGone.into());
+                };
+                match parse_request(&frame) {
+                    Ok(Request::Command(cmd)) => {
+                        shared.stats.record(user, Route::Master, &cmd.name_upper);
+                        quitting = cmd.name_upper == "QUIT";
+                        subs.sent(&cmd);
+                        mirror_notifications(&mut mirror, &cmd, shared).await;
+                    }
+                    // It would change the protocol of both connections under the messages.
+                    Ok(Request::Hello(_)) => {
+                        client
+                            .write_all(b"-ERR HELLO is not allowed while subscribed\r\n")
+                            .await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        let _ = client
+                            .write_all(format!("-ERR Protocol error: {e}\r\n").as_bytes())
+                            .await;
+                        return Err(e);
+                    }
+                }
+                master.write_all(raw.as_ref()).await?;
+            }
+            next = master.read_frame() => {
+                let Some((frame, raw)) = next? else {
+                    if quitting {
+                        return Err(ClientGone.into());
+                    }
+                    return Err(MasterClosed.into());
+                };
+                client.write_all(raw.as_ref()).await?;
+                subs.received(&frame);
+                if subs.is_done() {
+                    if let Some(mut conn) = mirror {
+                        let _ = conn.shutdown().await;
+                    }
+                    return Ok(());
+                }
+            }
+            next = read_mirror(&mut mirror) => match next {
+                Ok(Some((frame, raw))) if is_expired_event(&frame) => {
+                    // The mirror speaks RESP2; a RESP3 client gets its messages as pushes.
+                    let mut raw = bytes::BytesMut::from(raw.as_ref());
+                    if client.version() == RespVersion::Resp3 {
+                        raw[0] = b'>';
+                    }
+                    client.write_all(&raw).await?;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => {
+                    tracing::debug!("replica connection for expired events lost");
+                    mirror = None;
+                }
+            },
+        }
+    }
+}
+
+/// Make a keyspace notification (un)subscribe again on the replica connection of
+/// [`relay_subscribed`], dialed on first use. Other commands, and everything without
+/// `--replica-expired-events`, stay with master.
+async fn mirror_notifications(
+    mirror: &mut Option<RespStream>,
+    cmd: &ParsedCommand,
+    shared: &Shared,
+) {
+    let cfg = shared.config();
+    let unsubscribe = match cmd.name_upper.as_str() {
+        "SUBSCRIBE" | "PSUBSCRIBE" => false,
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" => true,
+        _ => return,
+    };
+    if !cfg.replica_expired_events {
+        return;
+    }
+    let channels: Vec<bytes::Bytes> = cmd
+        .args
+        .iter()
+        .filter(|c| {
+            c.starts_with(b"__keyspace@")
+                || c.starts_with(b"__keyevent@")
+                || c.starts_with(b"__key*")
+        })
+        .cloned()
+        .collect();
+    // Without channels, an unsubscribe ends them all, on the mirror too.
+    if channels.is_empty() && !(unsubscribe && cmd.args.is_empty()) {
+        return;
+    }
+    if mirror.is_none() {
+        if unsubscribe {
+            return;
+        }
+        let Some(endpoint) = shared
+            .pool
+            .pick_replica(|r| shared.replica_health.is_usable(&r.host, r.port))
+        else {
+            return;
+        };
+        match connect_and_handshake(&endpoint, cfg.connect_timeout, &shared.resolver, None).await {
+            Ok(conn) => *mirror = Some(conn),
+            Err(e) => {
+                tracing::debug!(error = %format!("{e:#}"), "no replica connection for expired events");
+                return;
+            }
+        }
+    }
+    let mut parts = vec![bytes::Bytes::copy_from_slice(cmd.name_upper.as_bytes())];
+    parts.extend(channels);
+    if let Some(conn) = mirror.as_mut()
+        && conn.write_all(&encode_command(&parts)).await.is_err()
+    {
+        *mirror = None;
+    }
+}
+
+/// The next frame of the mirror connection of [`relay_subscribed`]; never resolves without one.
+async fn read_mirror(mirror: &mut Option<RespStream>) -> Result<Option<(Frame, bytes::Bytes)>> {
+    match mirror {
+        Some(conn) => conn.read_frame().await,
+        None => std::future::pending().await,
     }
+}
 
-    // Send to master, switch protocol before reading the response.
-    master.write_all(&hello_cmd).await?;
-    master.set_version(target);
+/// Whether a pub/sub message reports a key expiring: on `__keyevent@<db>__:expired`, or as the
+/// `expired` event of a `__keyspace@<db>__:<key>` channel.
+fn is_expired_event(frame: &Frame) -> bool {
+    let reply = Reply::from_frame(frame);
+    let (channel, payload) = match reply.as_list() {
+        Some([Reply::Str(kind), Reply::Str(channel), Reply::Str(payload)])
+            if kind.as_ref() == b"message" =>
+        {
+            (channel, payload)
+        }
+        Some(
+            [
+                Reply::Str(kind),
+                _,
+                Reply::Str(channel),
+                Reply::Str(payload),
+            ],
+        ) if kind.as_ref() == b"pmessage" => (channel, payload),
+        _ => return false,
+    };
+    (channel.starts_with(b"__keyevent@") && channel.ends_with(b"__:expired"))
+        || (channel.starts_with(b"__keyspace@") && payload.as_ref() == b"expired")
+}
 
-    if replica.is_some() {
-        // Avoid holding a mutable borrow across `.await` so we can disable the replica on failures.
-        let write_result = {
-            let rep = replica.as_mut().unwrap();
-            rep.write_all(&hello_cmd).await
+/// What a subscriber connection is subscribed to, as master's confirmations count it, and the
+/// frames master still owes to the commands sent, for [`relay_subscribed`].
+#[derive(Debug, Default)]
+struct Subscriptions {
+    channels: i64,
+    patterns: i64,
+    /// Shard channels, from `SSUBSCRIBE`, counted apart from the others by the server.
+    shard: i64,
+    owed: VecDeque<Owed>,
+}
+
+/// The reply master owes to one command sent in subscriber mode.
+#[derive(Debug)]
+struct Owed {
+    frames: usize,
+    /// The kind of the confirmations that answer it, for the (un)subscribe commands.
+    confirmed_by: Option<&'static str>,
+    /// It was a `RESET`, which ends every subscription.
+    reset: bool,
+}
+
+impl Subscriptions {
+    fn sent(&mut self, cmd: &ParsedCommand) {
+        let (confirmed_by, held) = match cmd.name_upper.as_str() {
+            "SUBSCRIBE" => (Some("subscribe"), None),
+            "PSUBSCRIBE" => (Some("psubscribe"), None),
+            "SSUBSCRIBE" => (Some("ssubscribe"), None),
+            "UNSUBSCRIBE" => (Some("unsubscribe"), Some(self.channels)),
+            "PUNSUBSCRIBE" => (Some("punsubscribe"), Some(self.patterns)),
+            "SUNSUBSCRIBE" => (Some("sunsubscribe"), Some(self.shard)),
+            _ => (None, None),
         };
+        // Subscriptions are confirmed one channel at a time, and an unsubscribe without
+        // channels ends all of that kind, or is answered once if there were none.
+        let frames = match held {
+            Some(held) if cmd.args.is_empty() => usize::try_from(held).unwrap_or(0).max(1),
+            _ if confirmed_by.is_some() => cmd.args.len().max(1),
+            _ => 1,
+        };
+        self.owed.push_back(Owed {
+            frames,
+            confirmed_by,
+            reset: cmd.name_upper == "RESET",
+        });
+    }
 
-        match write_result {
-            Ok(()) => {
-                if let Some(rep) = replica.as_mut() {
-                    rep.set_version(target);
+    /// Count one frame from master: a confirmation, a reply to another command, or a message.
+    fn received(&mut self, frame: &Frame) {
+        let reply = Reply::from_frame(frame);
+        let items = reply.as_list().unwrap_or_default();
+        let kind = items.first().and_then(Reply::as_str);
+        let confirmed = match (kind, items) {
+            (Some(kind @ ("subscribe" | "unsubscribe")), [_, _, Reply::Int(count)]) => {
+                self.channels = count - self.patterns;
+                Some(kind)
+            }
+            (Some(kind @ ("psubscribe" | "punsubscribe")), [_, _, Reply::Int(count)]) => {
+                self.patterns = count - self.channels;
+                Some(kind)
+            }
+            (Some(kind @ ("ssubscribe" | "sunsubscribe")), [_, _, Reply::Int(count)]) => {
+                self.shard = *count;
+                Some(kind)
+            }
+            _ => None,
+        };
+        let message = match frame {
+            Frame::Resp3(f) => matches!(f, crate::resp::Resp3Frame::Push { .. }),
+            Frame::Resp2(_) => matches!(kind, Some("message" | "pmessage" | "smessage")),
+        };
+        match (confirmed, self.owed.front_mut()) {
+            (Some(kind), Some(owed)) if owed.confirmed_by == Some(kind) => {
+                owed.frames -= 1;
+                if owed.frames == 0 {
+                    self.owed.pop_front();
                 }
             }
-            Err(e) => {
-                tracing::warn!(error=?e, "replica write failed during HELLO; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
+            // Unasked for: the server drops shard subscriptions whose slot it no longer
+            // serves with a `sunsubscribe` of its own. The count above is all it changes.
+            (Some(_), _) => {}
+            // Any other reply, e.g. an error for an (un)subscribe, answers a command whole.
+            (None, Some(_)) if !message => {
+                let owed = self.owed.pop_front().expect("a command is owed a reply");
+                if owed.reset && reply.as_str() == Some("RESET") {
+                    (self.channels, self.patterns, self.shard) = (0, 0, 0);
                 }
             }
+            (None, _) => {}
         }
     }
 
-    // Read master's HELLO response and forward to client.
-    let (_frame, raw) = read_one_reply_from_master(master, client).await?;
-    client.set_version(target);
-    client.write_all(&raw).await?;
-
-    // Discard replica's HELLO response.
-    if replica.is_some() {
-        // Drain one reply from replica to keep stream aligned. If it fails/times out, disable replica.
-        let drain_result = {
-            let rep = replica.as_mut().unwrap();
-            timeout(replica_timeout, rep.read_frame()).await
-        };
+    /// Whether the connection left subscriber mode: nothing subscribed, and nothing owed.
+    fn is_done(&self) -> bool {
+        self.owed.is_empty() && self.channels <= 0 && self.patterns <= 0 && self.shard <= 0
+    }
+}
 
-        match drain_result {
-            Ok(Ok(Some(_))) => {}
-            Ok(Ok(None)) => {
-                tracing::warn!("replica closed during HELLO reply drain; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
-                }
-            }
-            Ok(Err(e)) => {
-                tracing::warn!(error=?e, "replica read failed during HELLO reply drain; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
-                }
+/// Forward a write and hold its reply until `WAIT 1` says a replica has it, for
+/// [`Consistency::Wait`]. Returns whether a replica confirmed the write within `wait`; failed
+/// writes need no confirmation.
+async fn forward_master_waiting(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+    wait: Duration,
+) -> Result<bool> {
+    master.write_all(raw.as_ref()).await?;
+    let (frame, reply_raw) = read_one_reply_from_master(master, client).await?;
+    let confirmed = if is_error_reply(&frame) {
+        true
+    } else {
+        let millis = wait.as_millis().max(1).to_string();
+        master
+            .write_all(&encode_command_str(&["WAIT", "1", &millis]))
+            .await?;
+        let (ack, _) = read_one_reply_from_master(master, client).await?;
+        match Reply::from_frame(&ack) {
+            Reply::Int(replicas) if replicas >= 1 => true,
+            reply => {
+                tracing::debug!(
+                    ?reply,
+                    "no replica confirmed the write in time; reading from master"
+                );
+                false
             }
-            Err(_) => {
-                tracing::warn!("replica timeout during HELLO reply drain; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
-                }
+        }
+    };
+    client.write_all(reply_raw.as_ref()).await?;
+    Ok(confirmed)
+}
+
+/// The master closed the connection before replying to `raw`. A `retryable` command is sent
+/// again on new master connections, with backoff, and the first of them that answers replaces
+/// `master`. Otherwise the client gets an error instead of a reply and `false` is returned: the
+/// dead master connection must be dropped.
+async fn retry_on_new_master(
+    client: &mut RespStream,
+    master: &mut RespStream,
+    raw: &bytes::Bytes,
+    retries: Option<Retries<'_>>,
+    shared: &Shared,
+    preamble: Option<&[u8]>,
+    session: &SessionReplay,
+) -> Result<bool> {
+    shared.pair.set_master_up(false);
+    let Some(retries) = retries else {
+        tracing::warn!(
+            "master closed the connection before replying; not retrying a command that may have run"
+        );
+        client.write_all(MASTER_LOST_REPLY).await?;
+        return Ok(false);
+    };
+
+    let version = master.version();
+    let mut backoff = retries.backoff();
+    let mut attempts = 0;
+    for attempt in 1..=MASTER_REDIAL_ATTEMPTS {
+        if !retries.allow(attempt) {
+            break;
+        }
+        attempts = attempt;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+
+        let endpoint = shared.pool.master();
+        let retried = async {
+            let mut conn = connect_and_handshake(
+                &endpoint,
+                shared.config().connect_timeout,
+                &shared.resolver,
+                preamble,
+            )
+            .await?;
+            session
+                .replay_master(&mut conn, version, &shared.client_ids)
+                .await?;
+            conn.write_all(raw.as_ref()).await?;
+            let (_frame, reply) = read_one_reply_from_master(&mut conn, client).await?;
+            anyhow::Ok((conn, reply))
+        }
+        .await;
+        match retried {
+            Ok((conn, reply)) => {
+                tracing::info!(
+                    attempt,
+                    "master connection lost; retried the command on a new one"
+                );
+                shared.pair.set_master_up(true);
+                let _ = std::mem::replace(master, conn).shutdown().await;
+                client.write_all(reply.as_ref()).await?;
+                return Ok(true);
             }
+            Err(e) => tracing::debug!(attempt, error = ?e, "master redial failed"),
         }
     }
 
-    Ok(())
+    if attempts == 0 {
+        tracing::warn!("master closed the connection before replying; out of retries");
+        client.write_all(MASTER_LOST_REPLY).await?;
+        return Ok(false);
+    }
+    tracing::warn!(
+        attempts,
+        "master connection lost and redialing failed; answering MASTERDOWN"
+    );
+    shared.master_watch.report_down();
+    client.write_all(MASTERDOWN_REPLY).await?;
+    Ok(false)
 }
 
-async fn forward_master(
+/// What [`forward_master_shielded`] needs to reconnect to the master.
+struct ReadonlyShield<'a> {
+    shared: &'a Shared,
+    preamble: Option<&'a [u8]>,
+    session: &'a SessionReplay,
+    window: Duration,
+    retries: Retries<'a>,
+}
+
+/// What a retry path asks before it sends a command again: `--retry-max` and the retry budgets.
+/// Retries the budgets deny are counted under the command in the stats.
+#[derive(Clone, Copy)]
+struct Retries<'a> {
+    shared: &'a Shared,
+    /// The connection's own budget window.
+    own: &'a RetryWindow,
+    user: &'a str,
+    command: &'a str,
+    route: Route,
+}
+
+impl Retries<'_> {
+    /// Whether retry number `attempt`, counted from 1, may be made.
+    fn allow(&self, attempt: u32) -> bool {
+        let cfg = self.shared.config();
+        if cfg.retry_max.is_some_and(|max| attempt > max) {
+            return false;
+        }
+        if self.shared.retry_budget.try_acquire(self.own, &cfg) {
+            return true;
+        }
+        self.shared
+            .stats
+            .record_retry_denied(self.user, self.route, self.command);
+        false
+    }
+
+    /// The pause before the first retry.
+    fn backoff(&self) -> Duration {
+        self.shared.config().retry_backoff
+    }
+}
+
+/// Like [`forward_master`], but a `-READONLY` reply (the master was demoted by a failover) is
+/// retried on new connections to the current master until one accepts the write or `window`
+/// runs out, in which case the client gets the original error. Retrying is safe because a
+/// rejected write was not executed.
+///
+/// The new master comes from the pool, which the endpoints file keeps up to date, or from
+/// re-resolving the master hostname.
+async fn forward_master_shielded(
     client: &mut RespStream,
     master: &mut RespStream,
     raw: &bytes::Bytes,
+    shield: ReadonlyShield<'_>,
 ) -> Result<()> {
     master.write_all(raw.as_ref()).await?;
-    let (_frame, reply_raw) = read_one_reply_from_master(master, client).await?;
-    client.write_all(reply_raw.as_ref()).await?;
+    let (frame, mut reply) = read_one_reply_from_master(master, client).await?;
+    if !is_readonly_error(&frame) {
+        client.write_all(reply.as_ref()).await?;
+        return Ok(());
+    }
+
+    let ReadonlyShield {
+        shared,
+        preamble,
+        session,
+        window,
+        retries,
+    } = shield;
+    let deadline = Instant::now() + window;
+    let mut backoff = retries.backoff();
+    let mut attempts = 0u32;
+    tracing::info!("master replied READONLY; waiting for the failover to complete");
+    while Instant::now() + backoff < deadline && retries.allow(attempts + 1) {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(READONLY_RETRY_BACKOFF_MAX);
+        attempts += 1;
+
+        let endpoint = shared.pool.master();
+        let version = master.version();
+        let attempt = async {
+            let mut conn = connect_and_handshake(
+                &endpoint,
+                shared.config().connect_timeout,
+                &shared.resolver,
+                preamble,
+            )
+            .await?;
+            session
+                .replay_master(&mut conn, version, &shared.client_ids)
+                .await?;
+            conn.write_all(raw.as_ref()).await?;
+            let (frame, reply) = read_one_reply_from_master(&mut conn, client).await?;
+            anyhow::Ok((conn, frame, reply))
+        };
+        match timeout_at(deadline, attempt).await {
+            Ok(Ok((conn, frame, next_reply))) if !is_readonly_error(&frame) => {
+                tracing::info!(
+                    host = %endpoint.host,
+                    port = endpoint.port,
+                    attempts,
+                    "write accepted by the new master; switched master connection"
+                );
+                let _ = std::mem::replace(master, conn).shutdown().await;
+                client.write_all(next_reply.as_ref()).await?;
+                return Ok(());
+            }
+            Ok(Ok((mut conn, _, next_reply))) => {
+                reply = next_reply;
+                let _ = conn.shutdown().await;
+            }
+            Ok(Err(e)) => tracing::debug!(error = ?e, "master reconnect failed; retrying"),
+            Err(_) => break,
+        }
+    }
+
+    tracing::warn!(
+        attempts,
+        "master still read-only after the failover retry window; returning the error"
+    );
+    client.write_all(reply.as_ref()).await?;
     Ok(())
 }
 
+fn is_readonly_error(frame: &Frame) -> bool {
+    is_error_reply(frame)
+        && matches!(Reply::from_frame(frame), Reply::Error(e) if e.starts_with("READONLY"))
+}
+
+/// Whether master refused to run a command for now: it is loading its dataset (`-LOADING`),
+/// running a script past the busy limit (`-BUSY`, not `-BUSYKEY`), or is a replica that lost
+/// its own master (`-MASTERDOWN`). The command wasn't run, so sending it again is safe.
+fn is_transient_error(frame: &Frame) -> bool {
+    is_error_reply(frame)
+        && matches!(
+            Reply::from_frame(frame),
+            Reply::Error(e) if matches!(e.split(' ').next(), Some("LOADING" | "BUSY" | "MASTERDOWN"))
+        )
+}
+
+/// Send a dual-forwarded command to both backends and relay master's reply. Returns master's
+/// reply if the replica got the command too; its own reply is left for the caller to read.
 async fn forward_both(
     client: &mut RespStream,
     master: &mut RespStream,
     replica: &mut Option<RespStream>,
     raw: &bytes::Bytes,
-    replica_timeout: std::time::Duration,
-) -> Result<()> {
+) -> Result<Option<(Frame, Bytes)>> {
     master.write_all(raw.as_ref()).await?;
     if replica.is_some() {
         let write_result = {
@@ -409,78 +3534,288 @@ async fn forward_both(
         }
     }
 
-    let (_frame, reply_raw) = read_one_reply_from_master(master, client).await?;
+    let (master_frame, reply_raw) = read_one_reply_from_master(master, client).await?;
     client.write_all(reply_raw.as_ref()).await?;
+    Ok(replica.is_some().then_some((master_frame, reply_raw)))
+}
 
-    if replica.is_some() {
-        let drain_result = {
-            let rep = replica.as_mut().unwrap();
-            timeout(replica_timeout, rep.read_frame()).await
-        };
+/// Why [`forward_replica_with_fallback`] served a read from master instead.
+enum Fallback {
+    /// The replica connection is no longer usable.
+    ReplicaFailed(&'static str),
+    /// The replica answered with an error of its own; see [`replica_refused`].
+    Refused(&'static str),
+    /// The replica didn't answer in time, but stays under [`ReplicaTimeoutAction::Retry`] and
+    /// [`ReplicaTimeoutAction::Error`]. The client was answered by master or `-PROXYTIMEOUT`.
+    TimedOut,
+}
 
-        match drain_result {
-            Ok(Ok(Some(_))) => {}
-            Ok(Ok(None)) => {
-                tracing::warn!("replica closed while draining reply; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
-                }
-            }
-            Ok(Err(e)) => {
-                tracing::warn!(error=?e, "replica read failed while draining reply; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
+const SCRIPT_MISSING: &str = "script missing on replica";
+const REPLICA_ERROR: &str = "replica error";
+const REPLICA_LOADING: &str = "replica loading";
+
+/// Send a command over `--multiplex`'s shared connections, a replica read to a replica, which
+/// falls back to master as on a connection of its own. Returns where the command was served, or
+/// `None` if the client was answered `-MASTERDOWN`.
+async fn forward_multiplexed(
+    client: &mut RespStream,
+    mux: &Multiplexer,
+    shared: &Shared,
+    raw: &Bytes,
+    route: Route,
+    user: &str,
+) -> Result<Option<Route>> {
+    if route == Route::Replica {
+        let over_budget = shared
+            .replica_budget
+            .as_ref()
+            .is_some_and(|b| !b.try_acquire(user));
+        let fallback = if over_budget {
+            "replica budget exhausted"
+        } else {
+            match mux.call(shared, BackendRole::Replica, raw.clone()).await {
+                Ok(reply) => {
+                    shared.replica_answered();
+                    match replica_refused(&reply) {
+                        Some(reason) => reason,
+                        None => {
+                            client.write_all(&reply).await?;
+                            return Ok(Some(Route::Replica));
+                        }
+                    }
                 }
-            }
-            Err(_) => {
-                tracing::warn!("replica read timeout while draining reply; disabling replica");
-                if let Some(mut rep) = replica.take() {
-                    let _ = rep.shutdown().await;
+                Err(e) if e.is::<Unavailable>() => "replica unavailable",
+                Err(e) => {
+                    tracing::warn!(error = %format!("{e:#}"), "shared replica read failed; falling back to master");
+                    shared.replica_failed();
+                    "replica read failed"
                 }
             }
+        };
+        shared.profiler.record_fallback(fallback);
+    }
+    match mux.call(shared, BackendRole::Master, raw.clone()).await {
+        Ok(reply) => {
+            client.write_all(&reply).await?;
+            Ok(Some(Route::Master))
         }
+        Err(e) if e.is::<Unavailable>() => {
+            client.write_all(MASTERDOWN_REPLY).await?;
+            Ok(None)
+        }
+        Err(e) => Err(e),
     }
+}
 
-    Ok(())
+/// Whether a replica reply says it lacks the script of an `EVALSHA_RO` or the function of an
+/// `FCALL_RO`. Scripts loaded on master directly are not on the replica, whose script cache
+/// isn't replicated, so master is asked instead.
+fn script_missing(reply: &[u8]) -> bool {
+    reply.starts_with(b"-NOSCRIPT") || reply.starts_with(b"-ERR Function not found")
+}
+
+/// Why master is asked instead when a replica answers a read with `reply`: any error, e.g.
+/// `-LOADING` during a resync or `-CLUSTERDOWN`, may be the replica's own, and one the read
+/// gets anywhere comes from master just the same. `None` for a reply to relay.
+fn replica_refused(reply: &[u8]) -> Option<&'static str> {
+    if script_missing(reply) {
+        Some(SCRIPT_MISSING)
+    } else if reply.starts_with(b"-LOADING") {
+        Some(REPLICA_LOADING)
+    } else if matches!(reply.first(), Some(b'-' | b'!')) {
+        Some(REPLICA_ERROR)
+    } else {
+        None
+    }
 }
 
 /// Forward a whitelisted read to replica. If replica errors or times out, resend to master.
 ///
-/// Returns `Ok(true)` if replica remains usable, `Ok(false)` if replica should be disabled.
+/// Returns `None` if the replica served the read, or why master did instead.
 async fn forward_replica_with_fallback(
     client: &mut RespStream,
     master: &mut RespStream,
-    replica: &mut RespStream,
+    (replica, replica_pending): (&mut RespStream, &mut usize),
     raw: &bytes::Bytes,
     replica_timeout: std::time::Duration,
-) -> Result<bool> {
+    on_timeout: ReplicaTimeoutAction,
+    retries: Retries<'_>,
+) -> Result<Option<Fallback>> {
+    let attempts = match on_timeout {
+        ReplicaTimeoutAction::Retry => retries
+            .shared
+            .config()
+            .replica_timeout_retries
+            .saturating_add(1),
+        _ => 1,
+    };
+    for attempt in 1..=attempts {
+        if let Err(e) = replica.write_all(raw.as_ref()).await {
+            tracing::warn!(error=?e, "replica write failed; falling back to master");
+            forward_master(client, master, raw).await?;
+            return Ok(Some(Fallback::ReplicaFailed("replica write failed")));
+        }
+
+        // Late replies to reads that timed out before come first.
+        let reply = timeout(replica_timeout, async {
+            while *replica_pending > 0 {
+                if replica.read_frame().await?.is_none() {
+                    return Ok(None);
+                }
+                *replica_pending -= 1;
+            }
+            replica.read_frame().await
+        })
+        .await;
+        return match reply {
+            Ok(Ok(Some((_frame, reply_raw)))) => match replica_refused(&reply_raw) {
+                Some(reason) => {
+                    tracing::debug!(reason, "replica answered with an error; asking master");
+                    forward_master(client, master, raw).await?;
+                    Ok(Some(Fallback::Refused(reason)))
+                }
+                None => {
+                    client.write_all(reply_raw.as_ref()).await?;
+                    Ok(None)
+                }
+            },
+            Ok(Ok(None)) => {
+                tracing::warn!("replica closed; falling back to master");
+                forward_master(client, master, raw).await?;
+                Ok(Some(Fallback::ReplicaFailed("replica closed")))
+            }
+            Ok(Err(e)) => {
+                tracing::warn!(error=?e, "replica read failed; falling back to master");
+                forward_master(client, master, raw).await?;
+                Ok(Some(Fallback::ReplicaFailed("replica read failed")))
+            }
+            Err(_) if on_timeout == ReplicaTimeoutAction::Disable => {
+                tracing::warn!("replica read timeout; falling back to master");
+                forward_master(client, master, raw).await?;
+                Ok(Some(Fallback::ReplicaFailed("replica timeout")))
+            }
+            Err(_) => {
+                // The reply may still come; it is skipped when it does.
+                *replica_pending += 1;
+                if attempt < attempts && retries.allow(attempt) {
+                    tracing::debug!(attempt, "replica read timeout; retrying");
+                    continue;
+                }
+                if on_timeout == ReplicaTimeoutAction::Error {
+                    tracing::warn!("replica read timeout; answering -PROXYTIMEOUT");
+                    client.write_all(REPLICA_TIMEOUT_REPLY).await?;
+                } else {
+                    tracing::warn!(attempt, "replica read timeout; answering from master");
+                    forward_master(client, master, raw).await?;
+                }
+                Ok(Some(Fallback::TimedOut))
+            }
+        };
+    }
+    unreachable!("every attempt returns or continues")
+}
+
+/// Which backend's reply [`forward_hedged`] relayed.
+enum Hedged {
+    Master,
+    /// `master_asked` if the master got the read too and still owes its reply.
+    Replica {
+        master_asked: bool,
+    },
+    /// The replica answered with an error; see [`replica_refused`]. Master's reply was relayed.
+    Refused(&'static str),
+    /// The replica failed before master answered; master's reply was relayed.
+    ReplicaFailed(&'static str),
+}
+
+/// Send a read to the replica and, if it hasn't answered within `delay`, to master as well;
+/// relay whichever reply comes first. The replica first skips the `replica_pending` replies of
+/// earlier hedged reads it lost, and the loser's reply is left pending for the caller to count.
+/// Only used on RESP2 connections, where every frame the master sends is a reply.
+async fn forward_hedged(
+    client: &mut RespStream,
+    (master, replica): (&mut RespStream, &mut RespStream),
+    replica_pending: &mut usize,
+    raw: &bytes::Bytes,
+    delay: Duration,
+    replica_timeout: Duration,
+) -> Result<Hedged> {
     if let Err(e) = replica.write_all(raw.as_ref()).await {
-        tracing::warn!(error=?e, "replica write failed; falling back to master");
-        forward_master(client, master, raw).await?;
-        return Ok(false);
+        tracing::warn!(error = ?e, "replica write failed; using the master reply");
+        master.write_all(raw.as_ref()).await?;
+        let (_, reply_raw) = read_one_reply_from_master(master, client).await?;
+        client.write_all(reply_raw.as_ref()).await?;
+        return Ok(Hedged::ReplicaFailed("replica write failed"));
     }
 
-    match timeout(replica_timeout, replica.read_frame()).await {
-        Ok(Ok(Some((_frame, reply_raw)))) => {
-            client.write_all(reply_raw.as_ref()).await?;
-            Ok(true)
-        }
-        Ok(Ok(None)) => {
-            tracing::warn!("replica closed; falling back to master");
-            forward_master(client, master, raw).await?;
-            Ok(false)
-        }
-        Ok(Err(e)) => {
-            tracing::warn!(error=?e, "replica read failed; falling back to master");
-            forward_master(client, master, raw).await?;
-            Ok(false)
+    let replica_failure = |failed: &Result<Result<Option<_>>, _>| match failed {
+        Ok(Ok(_)) => "replica closed",
+        Ok(Err(_)) => "replica read failed",
+        Err(_) => "replica timeout",
+    };
+    let replica_reply = timeout(replica_timeout, async {
+        while *replica_pending > 0 {
+            if replica.read_frame().await?.is_none() {
+                return Ok(None);
+            }
+            *replica_pending -= 1;
         }
-        Err(_) => {
-            tracing::warn!("replica read timeout; falling back to master");
-            forward_master(client, master, raw).await?;
-            Ok(false)
+        replica.read_frame().await
+    });
+    tokio::pin!(replica_reply);
+    if !delay.is_zero() {
+        tokio::select! {
+            reply = &mut replica_reply => {
+                let reply_raw = match reply {
+                    Ok(Ok(Some((_, reply_raw)))) => match replica_refused(&reply_raw) {
+                        Some(reason) => {
+                            master.write_all(raw.as_ref()).await?;
+                            let (_, reply_raw) = read_one_reply_from_master(master, client).await?;
+                            client.write_all(reply_raw.as_ref()).await?;
+                            return Ok(Hedged::Refused(reason));
+                        }
+                        None => reply_raw,
+                    },
+                    failed => {
+                        let reason = replica_failure(&failed);
+                        tracing::warn!(reason, "replica read failed; falling back to master");
+                        master.write_all(raw.as_ref()).await?;
+                        let (_, reply_raw) = read_one_reply_from_master(master, client).await?;
+                        client.write_all(reply_raw.as_ref()).await?;
+                        return Ok(Hedged::ReplicaFailed(reason));
+                    }
+                };
+                client.write_all(reply_raw.as_ref()).await?;
+                return Ok(Hedged::Replica { master_asked: false });
+            }
+            _ = tokio::time::sleep(delay) => {}
         }
     }
+
+    master.write_all(raw.as_ref()).await?;
+    let (reply_raw, winner) = tokio::select! {
+        reply = master.read_frame() => match reply? {
+            Some((_, raw)) => (raw, Hedged::Master),
+            None => return Err(MasterClosed.into()),
+        },
+        reply = &mut replica_reply => match reply {
+            Ok(Ok(Some((_, raw)))) => match replica_refused(&raw) {
+                Some(reason) => {
+                    let (_, raw) = read_one_reply_from_master(master, client).await?;
+                    (raw, Hedged::Refused(reason))
+                }
+                None => (raw, Hedged::Replica { master_asked: true }),
+            },
+            failed => {
+                let reason = replica_failure(&failed);
+                tracing::warn!(reason, "replica failed a hedged read; using the master reply");
+                let (_, raw) = read_one_reply_from_master(master, client).await?;
+                (raw, Hedged::ReplicaFailed(reason))
+            }
+        },
+    };
+    client.write_all(reply_raw.as_ref()).await?;
+    Ok(winner)
 }
 
 async fn read_one_reply_from_master(
@@ -490,7 +3825,7 @@ async fn read_one_reply_from_master(
     loop {
         let Some((frame, raw)) = master.read_frame().await? else {
             // Master went away => close client per spec.
-            return Err(anyhow!("master connection closed"));
+            return Err(MasterClosed.into());
         };
 
         if let (Frame::Resp3(f), RespVersion::Resp3) = (&frame, master.version())
@@ -505,17 +3840,204 @@ async fn read_one_reply_from_master(
     }
 }
 
-async fn connect_and_handshake(
+/// Commands a client pipelined that were forwarded ahead of their replies, in the order the
+/// client sent them. Replica reads and the master commands after them run concurrently on the
+/// two backends; [`Pipeline::relay`] puts the replies back in order.
+#[derive(Debug, Default)]
+struct Pipeline {
+    entries: Vec<Pipelined>,
+}
+
+#[derive(Debug)]
+enum Pipelined {
+    /// With the `master_timeout` that applies to it.
+    Master(Option<Duration>),
+    /// A read sent to the replica; kept for the keys it reads and to ask master if the replica
+    /// fails it.
+    Replica {
+        cmd: ParsedCommand,
+        raw: Bytes,
+        /// The replica connection it went to was closed before the reply was read.
+        lost: bool,
+    },
+}
+
+impl Pipeline {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push_master(&mut self, wait: Option<Duration>) {
+        self.entries.push(Pipelined::Master(wait));
+    }
+
+    fn push_replica(&mut self, cmd: &ParsedCommand, raw: &Bytes, lost: bool) {
+        self.entries.push(Pipelined::Replica {
+            cmd: cmd.clone(),
+            raw: raw.clone(),
+            lost,
+        });
+    }
+
+    /// Whether master command `cmd` may change what a replica read still in flight reads, and
+    /// so has to wait for its reply.
+    fn conflicts(&self, cmd: &ParsedCommand) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            Pipelined::Replica { cmd: read, .. } => overlaps(read, cmd),
+            Pipelined::Master(_) => false,
+        })
+    }
+
+    /// The replica connection is being closed; the reads sent to it are asked of master.
+    fn replica_closed(&mut self) {
+        for entry in &mut self.entries {
+            if let Pipelined::Replica { lost, .. } = entry {
+                *lost = true;
+            }
+        }
+    }
+
+    /// Relay the replies to every pipelined command, in order, in one write to the client.
+    ///
+    /// Replica replies are read first. If the replica fails, it is dropped and the reads it
+    /// owes go to master after everything already in flight there, which is safe since no
+    /// master command in the pipeline touches their keys.
+    ///
+    /// Returns whether master ran out of `master_timeout`; the commands it didn't answer then
+    /// are answered `-PROXYTIMEOUT`, and the master connection can't be used any more.
+    async fn relay(
+        &mut self,
+        client: &mut RespStream,
+        master: &mut RespStream,
+        (replica, replica_addr): (&mut Option<RespStream>, Option<&(String, u16)>),
+        replica_timeout: Duration,
+        shared: &Shared,
+        user: &str,
+    ) -> Result<bool> {
+        if self.entries.is_empty() {
+            return Ok(false);
+        }
+        let entries = std::mem::take(&mut self.entries);
+        let mut replies: Vec<Option<Bytes>> = vec![None; entries.len()];
+        let mut fallbacks = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let Pipelined::Replica { cmd, raw, lost } = entry else {
+                continue;
+            };
+            let failed = match replica.as_mut().filter(|_| !lost) {
+                None if *lost => Some("replica closed"),
+                None => Some("replica unavailable"),
+                Some(rep) => match timeout(replica_timeout, rep.read_frame()).await {
+                    Ok(Ok(Some((_, reply)))) => match replica_refused(&reply) {
+                        Some(reason) => {
+                            shared.replica_errored(replica_addr, reason);
+                            Some(reason)
+                        }
+                        None => {
+                            shared.replica_answered();
+                            replies[i] = Some(reply);
+                            None
+                        }
+                    },
+                    failed => {
+                        let reason = match failed {
+                            Ok(Ok(_)) => "replica closed",
+                            Ok(Err(_)) => "replica read failed",
+                            Err(_) => "replica timeout",
+                        };
+                        tracing::warn!(
+                            reason,
+                            "replica failed a pipelined read; falling back to master"
+                        );
+                        if let Some(mut rep) = replica.take() {
+                            let _ = rep.shutdown().await;
+                        }
+                        shared.replica_failed();
+                        Some(reason)
+                    }
+                },
+            };
+            if let Some(reason) = failed {
+                shared.profiler.record_fallback(reason);
+                shared.stats.record_replica_fallback(user, &cmd.name_upper);
+                master.write_all(raw).await?;
+                fallbacks.push(i);
+            }
+        }
+        let fallback_wait = shared.config().master_timeout;
+        let on_master = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| match entry {
+                Pipelined::Master(wait) => Some((i, *wait)),
+                Pipelined::Replica { .. } => None,
+            });
+        let mut timed_out = false;
+        for (i, wait) in on_master.chain(fallbacks.into_iter().map(|i| (i, fallback_wait))) {
+            let reply = match wait {
+                _ if timed_out => Bytes::from_static(MASTER_TIMEOUT_REPLY),
+                Some(wait) => match timeout(wait, read_one_reply_from_master(master, client)).await
+                {
+                    Ok(read) => read?.1,
+                    Err(_) => {
+                        tracing::warn!(
+                            "master did not answer a pipelined command in time; replacing the connection"
+                        );
+                        timed_out = true;
+                        Bytes::from_static(MASTER_TIMEOUT_REPLY)
+                    }
+                },
+                None => read_one_reply_from_master(master, client).await?.1,
+            };
+            replies[i] = Some(reply);
+        }
+        let mut out = bytes::BytesMut::new();
+        for reply in replies {
+            out.extend_from_slice(&reply.expect("every pipelined command answered"));
+        }
+        client.write_all(&out).await?;
+        Ok(timed_out)
+    }
+}
+
+/// Dial `endpoint` and authenticate. `connect_timeout` applies unless the endpoint has its own.
+pub async fn connect_and_handshake(
     endpoint: &RedisEndpoint,
     connect_timeout: std::time::Duration,
+    resolver: &Resolver,
+    preamble: Option<&[u8]>,
 ) -> Result<RespStream> {
-    let addr = (&endpoint.host[..], endpoint.port);
-    let sock = timeout(connect_timeout, TcpStream::connect(addr))
-        .await
-        .context("connect timeout")??;
+    let connect_timeout = endpoint.connect_timeout.unwrap_or(connect_timeout);
+    let mut sock = timeout(
+        connect_timeout,
+        connect_tcp(resolver, &endpoint.host, endpoint.port),
+    )
+    .await
+    .context("connect timeout")??;
     sock.set_nodelay(true)?;
 
-    let mut stream = RespStream::new(sock, RespVersion::Resp2);
+    // The PROXY header precedes everything else, including TLS.
+    if let Some(preamble) = preamble {
+        sock.write_all(preamble).await?;
+    }
+
+    let mut stream = match &endpoint.tls {
+        Some(tls) => {
+            let tls_stream = timeout(
+                connect_timeout,
+                tls.connector.connect(tls.server_name.clone(), sock),
+            )
+            .await
+            .context("TLS handshake timeout")?
+            .context("backend TLS handshake failed")?;
+            RespStream::new(tls_stream, RespVersion::Resp2)
+        }
+        None => RespStream::new(sock, RespVersion::Resp2),
+    };
 
     // Backend AUTH
     if let Some(pass) = &endpoint.password {
@@ -564,6 +4086,74 @@ async fn connect_and_handshake(
     Ok(stream)
 }
 
+/// Connect to the first reachable address of `host`, trying each resolved address in order.
+async fn connect_tcp(resolver: &Resolver, host: &str, port: u16) -> Result<TcpStream> {
+    let addrs = resolver.resolve(host, port).await?;
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) => {
+                tracing::debug!(host, %addr, error = ?e, "backend address unreachable");
+                last_err = Some(e);
+            }
+        }
+    }
+    // Every cached address failed; look the name up again next time.
+    resolver.invalidate(host, port);
+    Err(last_err
+        .map(anyhow::Error::from)
+        .unwrap_or_else(|| anyhow!("no addresses for '{host}'")))
+}
+
+/// The master closed a client's backend connection.
+#[derive(Debug)]
+struct MasterClosed;
+
+impl std::fmt::Display for MasterClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("master connection closed")
+    }
+}
+
+impl std::error::Error for MasterClosed {}
+
+/// The master didn't answer a command within `master_timeout`.
+#[derive(Debug)]
+struct MasterTimeout;
+
+impl std::fmt::Display for MasterTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("master did not answer in time")
+    }
+}
+
+impl std::error::Error for MasterTimeout {}
+
+/// The client disconnected while its blocking command waited on master.
+#[derive(Debug)]
+struct ClientGone;
+
+impl std::fmt::Display for ClientGone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("client disconnected during a blocking command")
+    }
+}
+
+impl std::error::Error for ClientGone {}
+
+/// The master could not be dialed for a client.
+#[derive(Debug)]
+struct MasterDown;
+
+impl std::fmt::Display for MasterDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("master unreachable")
+    }
+}
+
+impl std::error::Error for MasterDown {}
+
 fn is_error_reply(frame: &Frame) -> bool {
     match frame {
         Frame::Resp2(f) => matches!(f, crate::resp::Resp2Frame::Error(_)),
@@ -0,0 +1,187 @@
+use anyhow::{Result, anyhow};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::listener::PeerAddr;
+
+/// PROXY protocol version emitted towards backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Read a HAProxy PROXY protocol (v1 or v2) header from the start of a client stream.
+///
+/// Reads exactly the header bytes so RESP (or TLS) framing can start right after.
+/// Returns `None` for `UNKNOWN`/`LOCAL` headers, where the transport address should be kept.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<PeerAddr>> {
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head).await?;
+    if &head == b"PROXY" {
+        read_v1(stream).await
+    } else if head == V2_SIGNATURE[..5] {
+        read_v2(stream, head).await
+    } else {
+        Err(anyhow!("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<PeerAddr>> {
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| anyhow!("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => {
+            let addr = parse_v1_addr(src, sport)?;
+            let local = parse_v1_addr(dst, dport)?;
+            Ok(Some(PeerAddr::Tcp { addr, local }))
+        }
+        _ => Err(anyhow!("malformed PROXY v1 header '{line}'")),
+    }
+}
+
+fn parse_v1_addr(ip: &str, port: &str) -> Result<SocketAddr> {
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| anyhow!("invalid PROXY v1 address '{ip}'"))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow!("invalid PROXY v1 port '{port}'"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S, head: [u8; 5]) -> Result<Option<PeerAddr>> {
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&head);
+    stream.read_exact(&mut header[5..]).await?;
+    if header[..12] != V2_SIGNATURE {
+        return Err(anyhow!("invalid PROXY v2 signature"));
+    }
+
+    let ver_cmd = header[12];
+    if ver_cmd >> 4 != 2 {
+        return Err(anyhow!(
+            "unsupported PROXY protocol version {}",
+            ver_cmd >> 4
+        ));
+    }
+    let family = header[13];
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    // LOCAL: health checks from the balancer itself.
+    if ver_cmd & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family {
+        // TCP or UDP over IPv4.
+        0x11 | 0x12 if body.len() >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            let sport = u16::from_be_bytes([body[8], body[9]]);
+            let dport = u16::from_be_bytes([body[10], body[11]]);
+            Ok(Some(PeerAddr::Tcp {
+                addr: SocketAddr::new(src.into(), sport),
+                local: SocketAddr::new(dst.into(), dport),
+            }))
+        }
+        // TCP or UDP over IPv6.
+        0x21 | 0x22 if body.len() >= 36 => {
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&body[..16]);
+            dst.copy_from_slice(&body[16..32]);
+            let sport = u16::from_be_bytes([body[32], body[33]]);
+            let dport = u16::from_be_bytes([body[34], body[35]]);
+            Ok(Some(PeerAddr::Tcp {
+                addr: SocketAddr::new(Ipv6Addr::from(src).into(), sport),
+                local: SocketAddr::new(Ipv6Addr::from(dst).into(), dport),
+            }))
+        }
+        0x31 | 0x32 => Ok(Some(PeerAddr::Unix)),
+        0x00 => Ok(None),
+        _ => Err(anyhow!(
+            "malformed PROXY v2 address block (family {family:#04x})"
+        )),
+    }
+}
+
+/// Encode a PROXY header describing `peer`, to be sent first on a backend connection.
+///
+/// Clients without a TCP address (Unix sockets) are announced as `UNKNOWN`/`LOCAL`, which makes
+/// the backend fall back to the proxy's own address.
+pub fn encode_header(version: ProxyProtocolVersion, peer: &PeerAddr) -> Vec<u8> {
+    let addrs = match peer {
+        PeerAddr::Tcp { addr, local } => Some(same_family(*addr, *local)),
+        PeerAddr::Unix => None,
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((src, dst)) => {
+                let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {proto} {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port()
+                )
+                .into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            match addrs {
+                Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+                    out.extend_from_slice(&[0x21, 0x11, 0, 12]);
+                    out.extend_from_slice(&src.ip().octets());
+                    out.extend_from_slice(&dst.ip().octets());
+                    out.extend_from_slice(&src.port().to_be_bytes());
+                    out.extend_from_slice(&dst.port().to_be_bytes());
+                }
+                Some((SocketAddr::V6(src), SocketAddr::V6(dst))) => {
+                    out.extend_from_slice(&[0x21, 0x21, 0, 36]);
+                    out.extend_from_slice(&src.ip().octets());
+                    out.extend_from_slice(&dst.ip().octets());
+                    out.extend_from_slice(&src.port().to_be_bytes());
+                    out.extend_from_slice(&dst.port().to_be_bytes());
+                }
+                _ => out.extend_from_slice(&[0x20, 0x00, 0, 0]),
+            }
+            out
+        }
+    }
+}
+
+/// PROXY headers carry one address family; map IPv4 to IPv6 when the two sides differ.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), a.port()),
+        IpAddr::V6(_) => a,
+    };
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
@@ -0,0 +1,151 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::command::ParsedCommand;
+
+/// Keys tracked before old entries are dropped; past it, the tracker treats every key as just
+/// written for one window, which is safe and bounds memory.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Keys written recently, so that reads of them are served by master for `read_your_writes`
+/// instead of a replica that may not have the write yet.
+///
+/// Keys are tracked regardless of the db they were written in: a read in another db goes to
+/// master too, which is only slower.
+#[derive(Debug, Default)]
+pub struct RecentWrites {
+    /// Key to when its last write was answered.
+    keys: HashMap<Bytes, Instant>,
+    /// Set by writes to every key, e.g. `FLUSHDB`.
+    all: Option<Instant>,
+}
+
+impl RecentWrites {
+    /// Note the keys `cmd` writes. Called once the master has answered it.
+    pub fn record(&mut self, cmd: &ParsedCommand, window: Duration) {
+        let now = Instant::now();
+        match written_keys(cmd) {
+            Written::None => {}
+            Written::All => {
+                self.all = Some(now);
+                self.keys.clear();
+            }
+            Written::Keys(keys) => {
+                if self.keys.len() + keys.len() > MAX_TRACKED_KEYS {
+                    self.keys.retain(|_, at| now.duration_since(*at) < window);
+                }
+                if self.keys.len() + keys.len() > MAX_TRACKED_KEYS {
+                    self.all = Some(now);
+                    self.keys.clear();
+                    return;
+                }
+                for key in keys {
+                    self.keys.insert(key.clone(), now);
+                }
+            }
+        }
+    }
+
+    /// Whether a read of `cmd` could miss a write made less than `window` ago.
+    pub fn touches(&self, cmd: &ParsedCommand, window: Duration) -> bool {
+        let recent = |at: &Instant| at.elapsed() < window;
+        if self.all.as_ref().is_some_and(recent) {
+            return true;
+        }
+        !self.keys.is_empty()
+            && read_keys(cmd)
+                .iter()
+                .any(|key| self.keys.get(*key).is_some_and(recent))
+    }
+}
+
+/// Whether a master-routed command may write, for `pin_after_write`.
+pub fn writes(cmd: &ParsedCommand) -> bool {
+    !matches!(written_keys(cmd), Written::None)
+}
+
+/// Whether the master command `write` may change what the replica read `read` looks at, so that
+/// its reply depends on the order the two run in. Scripts and reads of the whole keyspace
+/// overlap every write.
+pub fn overlaps(read: &ParsedCommand, write: &ParsedCommand) -> bool {
+    let keys = match written_keys(write) {
+        Written::None => return false,
+        Written::All => return true,
+        Written::Keys(keys) => keys,
+    };
+    if matches!(write.name_upper.as_str(), "EVAL" | "EVALSHA" | "FCALL")
+        || matches!(
+            read.name_upper.as_str(),
+            "SCAN" | "KEYS" | "DBSIZE" | "RANDOMKEY"
+        )
+    {
+        return true;
+    }
+    let read = read_keys(read);
+    keys.iter().any(|key| read.contains(key))
+}
+
+#[derive(Debug)]
+enum Written<'a> {
+    None,
+    Keys(Vec<&'a Bytes>),
+    All,
+}
+
+/// The keys a master-routed command may write. Commands that write no key, like `CLIENT` or
+/// `CONFIG`, may still name a "key" here; reads of a key by that name then go to master for
+/// one window, which is harmless.
+fn written_keys(cmd: &ParsedCommand) -> Written<'_> {
+    let args = &cmd.args;
+    match cmd.name_upper.as_str() {
+        "FLUSHDB" | "FLUSHALL" | "SWAPDB" => Written::All,
+        // Connection and transaction control.
+        "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" | "SELECT" | "AUTH" | "HELLO"
+        | "PING" | "ECHO" | "QUIT" | "RESET" | "CLIENT" | "INFO" | "TIME" | "SUBSCRIBE"
+        | "PSUBSCRIBE" | "SSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE"
+        | "PUBLISH" | "SPUBLISH" | "MONITOR" => Written::None,
+        "DEL" | "UNLINK" => Written::Keys(args.iter().collect()),
+        "MSET" | "MSETNX" => Written::Keys(args.iter().step_by(2).collect()),
+        "RENAME" | "RENAMENX" | "COPY" | "SMOVE" | "LMOVE" | "BLMOVE" | "RPOPLPUSH"
+        | "BRPOPLPUSH" | "ZRANGESTORE" | "GEOSEARCHSTORE" => {
+            Written::Keys(args.iter().take(2).collect())
+        }
+        "EVAL" | "EVALSHA" | "FCALL" => Written::Keys(numkeys_keys(args, 1)),
+        _ => Written::Keys(args.iter().take(1).collect()),
+    }
+}
+
+/// The keys a replica read looks at.
+fn read_keys(cmd: &ParsedCommand) -> Vec<&Bytes> {
+    let args = &cmd.args;
+    match cmd.name_upper.as_str() {
+        "PING" | "SCAN" | "SCRIPT" => Vec::new(),
+        "MGET" | "EXISTS" | "SINTER" | "SUNION" | "SDIFF" | "PFCOUNT" => args.iter().collect(),
+        "SINTERCARD" => numkeys_keys(args, 0),
+        // OBJECT ENCODING key, MEMORY USAGE key, DEBUG OBJECT key
+        "OBJECT" | "MEMORY" | "DEBUG" => args.iter().skip(1).take(1).collect(),
+        // XREAD [options] STREAMS key... id...
+        "XREAD" => {
+            let streams = args
+                .iter()
+                .position(|a| a.eq_ignore_ascii_case(b"STREAMS"))
+                .map_or(args.len(), |at| at + 1);
+            let keys = &args[streams..];
+            keys[..keys.len() / 2].iter().collect()
+        }
+        "EVAL_RO" | "EVALSHA_RO" | "FCALL_RO" => numkeys_keys(args, 1),
+        _ => args.iter().take(1).collect(),
+    }
+}
+
+/// The keys of a `numkeys key...` argument list starting at `at`.
+fn numkeys_keys(args: &[Bytes], at: usize) -> Vec<&Bytes> {
+    let numkeys = args
+        .get(at)
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(0);
+    args.iter().skip(at + 1).take(numkeys).collect()
+}
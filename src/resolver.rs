@@ -0,0 +1,80 @@
+use anyhow::{Result, anyhow};
+use dashmap::DashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Caching resolver for backend hostnames.
+///
+/// Every new backend connection goes through `resolve`, so addresses are refreshed once the TTL
+/// expires instead of being pinned for the lifetime of the process. Failed lookups are cached for
+/// `negative_ttl` to avoid hammering DNS during an outage.
+#[derive(Debug)]
+pub struct Resolver {
+    ttl: Duration,
+    negative_ttl: Duration,
+    cache: DashMap<(String, u16), Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    at: Instant,
+    addrs: Result<Vec<SocketAddr>, String>,
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            ttl,
+            negative_ttl,
+            cache: DashMap::new(),
+        }
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+        if let Some(entry) = self.cache.get(&key) {
+            let ttl = if entry.addrs.is_ok() {
+                self.ttl
+            } else {
+                self.negative_ttl
+            };
+            if entry.at.elapsed() < ttl {
+                return entry.addrs.clone().map_err(|e| anyhow!(e));
+            }
+        }
+
+        let addrs = match tokio::net::lookup_host((host, port)).await {
+            Ok(it) => {
+                let addrs: Vec<SocketAddr> = it.collect();
+                if addrs.is_empty() {
+                    Err(format!("no addresses found for '{host}'"))
+                } else {
+                    Ok(addrs)
+                }
+            }
+            Err(e) => Err(format!("failed to resolve '{host}': {e}")),
+        };
+
+        if let Ok(new) = &addrs
+            && let Some(old) = self.cache.get(&key)
+            && let Ok(old) = &old.addrs
+            && old != new
+        {
+            tracing::info!(host, old = ?old, new = ?new, "backend addresses changed");
+        }
+
+        self.cache.insert(
+            key,
+            Entry {
+                at: Instant::now(),
+                addrs: addrs.clone(),
+            },
+        );
+        addrs.map_err(|e| anyhow!(e))
+    }
+
+    /// Drop the cached addresses for `host`, e.g. after none of them accepted a connection.
+    pub fn invalidate(&self, host: &str, port: u16) {
+        self.cache.remove(&(host.to_string(), port));
+    }
+}
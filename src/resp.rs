@@ -1,7 +1,11 @@
 use anyhow::{Result, anyhow};
 use bytes::{Bytes, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, WriteHalf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 
 pub use redis_protocol::resp2::types::BytesFrame as Resp2Frame;
 pub use redis_protocol::resp3::types::BytesFrame as Resp3Frame;
@@ -18,19 +22,85 @@ pub enum Frame {
     Resp3(Resp3Frame),
 }
 
-#[derive(Debug)]
+/// Any byte stream a RESP connection can run over (plain TCP, TLS, ...).
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
 pub struct RespStream {
-    stream: TcpStream,
+    stream: Box<dyn AsyncStream>,
     buf: BytesMut,
     version: RespVersion,
+    /// When the last frame was returned and the last write completed, for latency breakdowns.
+    last_read: Option<Instant>,
+    last_write: Option<Instant>,
+    /// Set by [`RespStream::read_ahead`]; frames then come from it rather than from `stream`.
+    read_ahead: Option<ReadAhead>,
+}
+
+/// Frames a task of its own reads from a connection ahead of its handler.
+struct ReadAhead {
+    frames: mpsc::Receiver<Result<(Frame, Bytes)>>,
+    task: JoinHandle<()>,
+    /// Never sent on; closed when `task` ends, i.e. when the peer closed the connection.
+    alive: watch::Receiver<()>,
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The write half of a stream whose reads went to a [`ReadAhead`] task. Reading it gives EOF.
+struct WriteOnly(WriteHalf<Box<dyn AsyncStream>>);
+
+impl AsyncRead for WriteOnly {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WriteOnly {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl std::fmt::Debug for RespStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RespStream")
+            .field("buffered", &self.buf.len())
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
 }
 
 impl RespStream {
-    pub fn new(stream: TcpStream, version: RespVersion) -> Self {
+    pub fn new(stream: impl AsyncStream + 'static, version: RespVersion) -> Self {
         Self {
-            stream,
+            stream: Box::new(stream),
             buf: BytesMut::with_capacity(8 * 1024),
             version,
+            last_read: None,
+            last_write: None,
+            read_ahead: None,
         }
     }
 
@@ -42,29 +112,98 @@ impl RespStream {
         self.version
     }
 
+    pub fn last_read(&self) -> Option<Instant> {
+        self.last_read
+    }
+
+    pub fn last_write(&self) -> Option<Instant> {
+        self.last_write
+    }
+
+    /// Read frames on a task of their own from now on, up to `depth` ahead of
+    /// [`RespStream::read_frame`], so the peer's next requests arrive while the current one is
+    /// still being answered.
+    ///
+    /// Meant for client connections: requests are arrays of bulk strings under either protocol,
+    /// so they are decoded as RESP2 whatever [`RespStream::set_version`] says later.
+    pub fn read_ahead(&mut self, depth: usize) {
+        if self.read_ahead.is_some() {
+            return;
+        }
+        let stream = std::mem::replace(&mut self.stream, Box::new(tokio::io::empty()));
+        let (mut read, write) = tokio::io::split(stream);
+        self.stream = Box::new(WriteOnly(write));
+        let mut buf = std::mem::take(&mut self.buf);
+        let (tx, frames) = mpsc::channel(depth.max(1));
+        let (alive_tx, alive) = watch::channel(());
+        let task = tokio::spawn(async move {
+            let _alive = alive_tx;
+            loop {
+                let frame = match decode_frame(&mut buf, RespVersion::Resp2) {
+                    Ok(Some(frame)) => Ok(frame),
+                    Ok(None) => match read.read_buf(&mut buf).await {
+                        Ok(0) => return,
+                        Ok(_) => continue,
+                        Err(e) => Err(e.into()),
+                    },
+                    Err(e) => Err(e),
+                };
+                let failed = frame.is_err();
+                if tx.send(frame).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        self.read_ahead = Some(ReadAhead {
+            frames,
+            task,
+            alive,
+        });
+    }
+
+    /// Resolves once the peer has closed the connection, even while frames it sent before are
+    /// still unread. Only known with [`RespStream::read_ahead`]; never resolves without it.
+    pub fn peer_closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let alive = self.read_ahead.as_ref().map(|ahead| ahead.alive.clone());
+        async move {
+            match alive {
+                Some(mut alive) => while alive.changed().await.is_ok() {},
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Whether a complete frame is already buffered or read ahead, so the next
+    /// [`RespStream::read_frame`] won't wait on the socket. A malformed buffer counts, since
+    /// reading it fails at once.
+    pub fn has_buffered_frame(&self) -> bool {
+        if let Some(ahead) = &self.read_ahead {
+            return !ahead.frames.is_empty();
+        }
+        let decoded = match self.version {
+            RespVersion::Resp2 => {
+                redis_protocol::resp2::decode::decode_range(&self.buf).map(|f| f.is_some())
+            }
+            RespVersion::Resp3 => redis_protocol::resp3::decode::complete::decode_range(&self.buf)
+                .map(|f| f.is_some()),
+        };
+        decoded.unwrap_or(true)
+    }
+
     /// Read exactly one RESP frame from the stream.
     ///
     /// Returns `Ok(None)` on clean EOF.
     pub async fn read_frame(&mut self) -> Result<Option<(Frame, Bytes)>> {
+        if let Some(ahead) = self.read_ahead.as_mut() {
+            let frame = ahead.frames.recv().await.transpose()?;
+            if frame.is_some() {
+                self.last_read = Some(Instant::now());
+            }
+            return Ok(frame);
+        }
         loop {
-            let decoded = match self.version {
-                RespVersion::Resp2 => {
-                    match redis_protocol::resp2::decode::decode_bytes_mut(&mut self.buf) {
-                        Ok(Some((frame, _amt, out))) => Some((Frame::Resp2(frame), out)),
-                        Ok(None) => None,
-                        Err(e) => return Err(anyhow!("RESP2 decode error: {e}")),
-                    }
-                }
-                RespVersion::Resp3 => {
-                    match redis_protocol::resp3::decode::complete::decode_bytes_mut(&mut self.buf) {
-                        Ok(Some((frame, _amt, out))) => Some((Frame::Resp3(frame), out)),
-                        Ok(None) => None,
-                        Err(e) => return Err(anyhow!("RESP3 decode error: {e}")),
-                    }
-                }
-            };
-
-            if let Some((frame, raw)) = decoded {
+            if let Some((frame, raw)) = decode_frame(&mut self.buf, self.version)? {
+                self.last_read = Some(Instant::now());
                 return Ok(Some((frame, raw)));
             }
 
@@ -77,6 +216,7 @@ impl RespStream {
 
     pub async fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
         self.stream.write_all(bytes).await?;
+        self.last_write = Some(Instant::now());
         Ok(())
     }
 
@@ -86,6 +226,22 @@ impl RespStream {
     }
 }
 
+/// Take one complete frame off the front of `buf`, if there is one.
+fn decode_frame(buf: &mut BytesMut, version: RespVersion) -> Result<Option<(Frame, Bytes)>> {
+    match version {
+        RespVersion::Resp2 => match redis_protocol::resp2::decode::decode_bytes_mut(buf) {
+            Ok(decoded) => Ok(decoded.map(|(frame, _amt, out)| (Frame::Resp2(frame), out))),
+            Err(e) => Err(anyhow!("RESP2 decode error: {e}")),
+        },
+        RespVersion::Resp3 => {
+            match redis_protocol::resp3::decode::complete::decode_bytes_mut(buf) {
+                Ok(decoded) => Ok(decoded.map(|(frame, _amt, out)| (Frame::Resp3(frame), out))),
+                Err(e) => Err(anyhow!("RESP3 decode error: {e}")),
+            }
+        }
+    }
+}
+
 /// Encode a Redis command as a RESP Array of Bulk/Blob Strings.
 ///
 /// Redis expects requests in this form for both RESP2 and RESP3.
@@ -110,3 +266,84 @@ pub fn encode_command_str(parts: &[&str]) -> BytesMut {
         .collect();
     encode_command(&b)
 }
+
+/// Protocol-independent view of a reply, for the few places where the proxy inspects replies
+/// instead of relaying them. RESP3 maps are flattened into key/value lists like in RESP2.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Nil,
+    Str(Bytes),
+    Int(i64),
+    Error(String),
+    List(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn from_frame(frame: &Frame) -> Self {
+        match frame {
+            Frame::Resp2(f) => Self::from_resp2(f),
+            Frame::Resp3(f) => Self::from_resp3(f),
+        }
+    }
+
+    fn from_resp2(frame: &Resp2Frame) -> Self {
+        match frame {
+            Resp2Frame::SimpleString(b) | Resp2Frame::BulkString(b) => Reply::Str(b.clone()),
+            Resp2Frame::Error(e) => Reply::Error(e.to_string()),
+            Resp2Frame::Integer(i) => Reply::Int(*i),
+            Resp2Frame::Array(items) => Reply::List(items.iter().map(Self::from_resp2).collect()),
+            Resp2Frame::Null => Reply::Nil,
+        }
+    }
+
+    fn from_resp3(frame: &Resp3Frame) -> Self {
+        match frame {
+            Resp3Frame::BlobString { data, .. }
+            | Resp3Frame::SimpleString { data, .. }
+            | Resp3Frame::BigNumber { data, .. }
+            | Resp3Frame::VerbatimString { data, .. }
+            | Resp3Frame::ChunkedString(data) => Reply::Str(data.clone()),
+            Resp3Frame::SimpleError { data, .. } => Reply::Error(data.to_string()),
+            Resp3Frame::BlobError { data, .. } => {
+                Reply::Error(String::from_utf8_lossy(data).to_string())
+            }
+            Resp3Frame::Boolean { data, .. } => Reply::Int(*data as i64),
+            Resp3Frame::Number { data, .. } => Reply::Int(*data),
+            Resp3Frame::Double { data, .. } => Reply::Str(Bytes::from(data.to_string())),
+            Resp3Frame::Null | Resp3Frame::Hello { .. } => Reply::Nil,
+            Resp3Frame::Array { data, .. } | Resp3Frame::Push { data, .. } => {
+                Reply::List(data.iter().map(Self::from_resp3).collect())
+            }
+            Resp3Frame::Set { data, .. } => {
+                Reply::List(data.iter().map(Self::from_resp3).collect())
+            }
+            Resp3Frame::Map { data, .. } => Reply::List(
+                data.iter()
+                    .flat_map(|(k, v)| [Self::from_resp3(k), Self::from_resp3(v)])
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Reply::Str(b) => std::str::from_utf8(b).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Reply]> {
+        match self {
+            Reply::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Look up `key` in a flattened key/value list (RESP2 pair arrays or RESP3 maps).
+    pub fn field(&self, key: &str) -> Option<&Reply> {
+        self.as_list()?
+            .chunks(2)
+            .find(|kv| kv[0].as_str().is_some_and(|k| k.eq_ignore_ascii_case(key)))
+            .and_then(|kv| kv.get(1))
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::command::{ParsedCommand, ProxySubcommand};
+use crate::proxy::{ConnState, apply_route_hint, decide_route, parse_route_hint, update_state};
+use crate::routing::{
+    ConnClass, PatternRules, ReplicaReads, Route, read_only_mode_allows, read_only_variant,
+    rejected_reason, route_cmd, sort_stores,
+};
+use crate::scripts::calls_only_reads;
+
+/// The settings of `run` that change where a command goes.
+#[derive(Debug, Clone)]
+pub struct RouteSettings {
+    pub force_eval_readonly: bool,
+    pub force_evalsha_readonly: bool,
+    pub sort_readonly: bool,
+    pub route_script_flags: bool,
+    pub analyze_scripts: bool,
+    pub read_only: bool,
+    pub classify_connections: bool,
+    pub replica_reads: ReplicaReads,
+    pub key_rules: PatternRules,
+    pub client_rules: PatternRules,
+}
+
+/// The `route-file` report: the route of every command in `path`, then a count per route.
+///
+/// Lines are commands as typed in `redis-cli`, or lines of a `MONITOR` capture, whose client
+/// address keeps each client's MULTI/WATCH state apart. Blank lines and `#` comments are
+/// skipped. The replica is assumed to be up: fallbacks to master at runtime are not shown.
+pub fn render(path: &Path, settings: &RouteSettings) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read command file '{}'", path.display()))?;
+
+    let mut clients: HashMap<String, Client> = HashMap::new();
+    let mut counts: BTreeMap<&'static str, u64> = BTreeMap::new();
+    let mut out = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (client, command) = split_monitor_prefix(line);
+        let (route, note) = match split_args(command) {
+            Some(args) if !args.is_empty() => clients
+                .entry(client.to_string())
+                .or_default()
+                .route(args, settings),
+            _ => (
+                "INVALID",
+                Some(format!("line {}: unbalanced quotes", n + 1)),
+            ),
+        };
+        *counts.entry(route).or_default() += 1;
+        out.push_str(&format!("{route:<8} {command}"));
+        if let Some(note) = note {
+            out.push_str(&format!("  # {note}"));
+        }
+        out.push('\n');
+    }
+
+    out.push_str("\nsummary:\n");
+    for (route, count) in counts {
+        out.push_str(&format!("  {route:<8} {count}\n"));
+    }
+    Ok(out)
+}
+
+/// One client's connection state, as the proxy would track it.
+#[derive(Debug, Default)]
+struct Client {
+    state: ConnState,
+    class: Option<ConnClass>,
+    /// Set by `PROXY ROUTE` for the next command.
+    hint: Option<Route>,
+}
+
+impl Client {
+    /// The route of one command and why, when it isn't plain routing-list lookup.
+    fn route(
+        &mut self,
+        mut args: Vec<Bytes>,
+        settings: &RouteSettings,
+    ) -> (&'static str, Option<String>) {
+        let mut cmd = ParsedCommand {
+            name_upper: String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase(),
+            args,
+        };
+        let mut note = None;
+        if (settings.force_eval_readonly && cmd.name_upper == "EVAL")
+            || (settings.force_evalsha_readonly && cmd.name_upper == "EVALSHA")
+            || (settings.sort_readonly && cmd.name_upper == "SORT" && !sort_stores(&cmd.args))
+        {
+            cmd.name_upper.push_str("_RO");
+            note = Some(format!("sent as {}", cmd.name_upper));
+        }
+        if let Some(variant) = read_only_variant(&cmd.name_upper, &cmd.args) {
+            cmd.name_upper = variant.to_string();
+            note = Some(format!("sent as {variant}"));
+        }
+
+        let hint = self.hint.take();
+        match cmd.name_upper.as_str() {
+            "PROXY"
+                if ProxySubcommand::parse(&cmd) == Some(ProxySubcommand::Route)
+                    && !self.state.in_multi =>
+            {
+                self.hint = parse_route_hint(&cmd);
+                return ("PROXY", Some("hint for the next command".into()));
+            }
+            "AUTH" | "HELLO" | "PROXY" => return ("PROXY", Some("answered by the proxy".into())),
+            "QUIT" => {
+                *self = Client::default();
+                return ("PROXY", Some("answered by the proxy".into()));
+            }
+            _ => {}
+        }
+
+        if settings.classify_connections && self.class.is_none() {
+            self.class = ConnClass::sniff(&cmd.name_upper);
+        }
+        if self.class == Some(ConnClass::Replication) {
+            return ("REJECTED", Some("replication connection".into()));
+        }
+
+        let first_arg_upper = cmd
+            .args
+            .first()
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .map(|s| s.to_ascii_uppercase());
+        if let Some(reason) = rejected_reason(&cmd.name_upper, first_arg_upper.as_deref()) {
+            return ("REJECTED", Some(reason.into()));
+        }
+        if settings.read_only && !read_only_mode_allows(&cmd.name_upper, first_arg_upper.as_deref())
+        {
+            return ("REJECTED", Some("read-only proxy".into()));
+        }
+
+        if cmd.name_upper == "CLIENT" && first_arg_upper.as_deref() == Some("SETNAME") {
+            self.state.named_route = cmd
+                .args
+                .get(1)
+                .and_then(|name| settings.client_rules.matching(name));
+        }
+
+        let replica_available = self.class.is_none_or(|c| c.uses_replica());
+        let analyzed_read_only = settings.analyze_scripts
+            && cmd.name_upper == "EVAL"
+            && cmd.args.first().is_some_and(|body| calls_only_reads(body));
+        let route = decide_route(
+            &cmd,
+            first_arg_upper.as_deref(),
+            &self.state,
+            replica_available,
+            analyzed_read_only,
+            &settings.replica_reads,
+            &settings.key_rules,
+        );
+        let listed = route;
+        let route = apply_route_hint(
+            route,
+            hint,
+            &cmd,
+            first_arg_upper.as_deref(),
+            &self.state,
+            replica_available,
+        );
+        if route != listed {
+            note = Some("PROXY ROUTE hint".into());
+        } else if self.state.in_multi || self.state.watch_active {
+            note = Some("inside MULTI or WATCH".into());
+        } else if let Some(class) = self.class.filter(|c| !c.uses_replica()) {
+            note = Some(format!("{} connection", class.name()));
+        } else if route != Route::Both
+            && settings.key_rules.route_key(&cmd.name_upper, &cmd.args) == Some(route)
+        {
+            note = Some("key rule".into());
+        } else if route != Route::Both && self.state.named_route == Some(route) {
+            note = Some("client name rule".into());
+        } else if settings.route_script_flags
+            && route == Route::Master
+            && matches!(cmd.name_upper.as_str(), "EVAL" | "EVALSHA" | "FCALL")
+        {
+            note = Some("REPLICA if the script or function is flagged no-writes".into());
+        } else if analyzed_read_only && route == Route::Replica {
+            note = Some("the script only calls read commands".into());
+        } else if self.state.readonly
+            && route == Route::Replica
+            && route_cmd(
+                &cmd.name_upper,
+                first_arg_upper.as_deref(),
+                &settings.replica_reads,
+            ) == Route::Master
+        {
+            note = Some("READONLY connection".into());
+        }
+        update_state(&mut self.state, &cmd);
+
+        let name = match route {
+            Route::Master => "MASTER",
+            Route::Replica => "REPLICA",
+            Route::Both => "BOTH",
+        };
+        (name, note)
+    }
+}
+
+/// Split a `MONITOR` line, `1700000000.123456 [0 127.0.0.1:51234] "GET" "key"`, into the client
+/// and the command. Other lines are all command, from a single client.
+fn split_monitor_prefix(line: &str) -> (&str, &str) {
+    let prefixed = line
+        .split_once(' ')
+        .filter(|(ts, _)| ts.contains('.') && ts.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        .and_then(|(_, rest)| rest.strip_prefix('['))
+        .and_then(|rest| rest.split_once("] "));
+    match prefixed {
+        // "<db> <addr>": the address tells clients apart.
+        Some((client, command)) => (client.split_once(' ').map_or(client, |(_, a)| a), command),
+        None => ("", line),
+    }
+}
+
+/// Split a command line into arguments the way `redis-cli` does: whitespace separates them,
+/// and double quotes allow `\n`, `\xHH` and other escapes, single quotes only `\'`. `None` if a
+/// quote isn't closed.
+fn split_args(line: &str) -> Option<Vec<Bytes>> {
+    let bytes = line.as_bytes();
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+            match bytes[i] {
+                b'"' => {
+                    i += 1;
+                    loop {
+                        match *bytes.get(i)? {
+                            b'"' => break,
+                            b'\\' => {
+                                let escaped = *bytes.get(i + 1)?;
+                                let hex = bytes
+                                    .get(i + 2..i + 4)
+                                    .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                                    .and_then(|h| std::str::from_utf8(h).ok())
+                                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                                match (escaped, hex) {
+                                    (b'x', Some(byte)) => {
+                                        arg.push(byte);
+                                        i += 4;
+                                        continue;
+                                    }
+                                    (b'n', _) => arg.push(b'\n'),
+                                    (b'r', _) => arg.push(b'\r'),
+                                    (b't', _) => arg.push(b'\t'),
+                                    (b'b', _) => arg.push(0x08),
+                                    (b'a', _) => arg.push(0x07),
+                                    (other, _) => arg.push(other),
+                                }
+                                i += 2;
+                            }
+                            other => {
+                                arg.push(other);
+                                i += 1;
+                            }
+                        }
+                    }
+                    i += 1;
+                }
+                b'\'' => {
+                    i += 1;
+                    loop {
+                        match *bytes.get(i)? {
+                            b'\'' => break,
+                            b'\\' if bytes.get(i + 1) == Some(&b'\'') => {
+                                arg.push(b'\'');
+                                i += 2;
+                            }
+                            other => {
+                                arg.push(other);
+                                i += 1;
+                            }
+                        }
+                    }
+                    i += 1;
+                }
+                other => {
+                    arg.push(other);
+                    i += 1;
+                }
+            }
+        }
+        args.push(Bytes::from(arg));
+    }
+}
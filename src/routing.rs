@@ -5,73 +5,665 @@ pub enum Route {
     Both,
 }
 
-pub fn route_cmd(cmd_upper: &str, first_arg_upper: Option<&str>) -> Route {
-    match (cmd_upper, first_arg_upper) {
-        ("HELLO", _) => Route::Both,
-        ("SELECT" | "READONLY" | "READWRITE", _) => Route::Both,
-        ("CLIENT", Some("SETNAME" | "SETINFO" | "TRACKING" | "CACHING" | "REPLY")) => Route::Both,
+pub fn route_cmd(
+    cmd_upper: &str,
+    first_arg_upper: Option<&str>,
+    replica_reads: &ReplicaReads,
+) -> Route {
+    if is_listed(DUAL_FORWARD, cmd_upper, first_arg_upper) {
+        Route::Both
+    } else if is_listed(ALWAYS_MASTER, cmd_upper, first_arg_upper) {
+        Route::Master
+    } else if is_listed(&replica_reads.commands, cmd_upper, first_arg_upper) {
+        Route::Replica
+    } else {
+        Route::Master
+    }
+}
+
+/// Whether `list` has the command, either by name or as a `COMMAND SUBCOMMAND` entry.
+pub fn is_listed<S: AsRef<str>>(
+    list: &[S],
+    cmd_upper: &str,
+    first_arg_upper: Option<&str>,
+) -> bool {
+    list.iter()
+        .any(|entry| match entry.as_ref().split_once(' ') {
+            Some((cmd, sub)) => cmd == cmd_upper && first_arg_upper == Some(sub),
+            None => entry.as_ref() == cmd_upper,
+        })
+}
+
+/// The commands read from the replica: [`REPLICA_READS`] with the `--replica-read` and
+/// `--no-replica-read` changes applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaReads {
+    commands: Vec<String>,
+}
+
+impl Default for ReplicaReads {
+    fn default() -> Self {
+        Self {
+            commands: REPLICA_READS.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+impl ReplicaReads {
+    /// The built-in list minus `remove`, where `*` removes all of it, plus `add`. Added commands
+    /// must be known read-only commands that no other list claims; removed ones must be on the
+    /// built-in list. The error is the message for the operator.
+    pub fn new(add: &[String], remove: &[String]) -> Result<Self, String> {
+        let mut reads = Self::default();
+        if remove.iter().any(|c| c.trim() == "*") {
+            reads.commands.clear();
+        } else {
+            for cmd in remove {
+                let cmd = normalize(cmd);
+                if !REPLICA_READS.contains(&cmd.as_str()) {
+                    return Err(format!(
+                        "--no-replica-read {cmd}: not on the built-in replica read list"
+                    ));
+                }
+                reads.remove(&cmd);
+            }
+        }
+        for cmd in add {
+            reads.add(cmd).map_err(|e| format!("--replica-read {e}"))?;
+        }
+        Ok(reads)
+    }
+
+    /// Read `cmd` from the replica; `false` if it already was. Only known read-only commands
+    /// that no other list claims are accepted.
+    pub fn add(&mut self, cmd: &str) -> Result<bool, String> {
+        let cmd = normalize(cmd);
+        let (name, sub) = match cmd.split_once(' ') {
+            Some((name, sub)) => (name, Some(sub)),
+            None => (cmd.as_str(), None),
+        };
+        if is_listed(DUAL_FORWARD, name, sub) {
+            return Err(format!("{cmd}: always sent to both master and replica"));
+        }
+        if is_listed(ALWAYS_MASTER, name, sub) {
+            return Err(format!("{cmd}: always sent to the master"));
+        }
+        if !READ_ONLY_COMMANDS.contains(&cmd.as_str()) {
+            return Err(format!("{cmd}: not a known read-only command"));
+        }
+        if self.commands.contains(&cmd) {
+            return Ok(false);
+        }
+        self.commands.push(cmd);
+        Ok(true)
+    }
+
+    /// Send `cmd` to the master; `false` if it already was.
+    pub fn remove(&mut self, cmd: &str) -> bool {
+        let cmd = normalize(cmd);
+        let before = self.commands.len();
+        self.commands.retain(|c| *c != cmd);
+        self.commands.len() != before
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    /// Whether a connection that sent `READONLY` reads `cmd` from the replica: any known
+    /// read-only command is, except built-in replica reads `--no-replica-read` removed.
+    pub fn readonly_hint(&self, cmd_upper: &str, first_arg_upper: Option<&str>) -> bool {
+        is_listed(&self.commands, cmd_upper, first_arg_upper)
+            || (is_read_only(cmd_upper, first_arg_upper)
+                && !is_listed(REPLICA_READS, cmd_upper, first_arg_upper))
+    }
+
+    /// The differences from [`REPLICA_READS`], as `+CMD` and `-CMD`.
+    pub fn changes(&self) -> Vec<String> {
+        let removed = REPLICA_READS
+            .iter()
+            .filter(|c| !self.commands.iter().any(|o| o == *c))
+            .map(|c| format!("-{c}"));
+        let added = self
+            .commands
+            .iter()
+            .filter(|c| !REPLICA_READS.contains(&c.as_str()))
+            .map(|c| format!("+{c}"));
+        removed.chain(added).collect()
+    }
+}
+
+/// Whether a command is on [`READ_ONLY_COMMANDS`], and so never writes.
+pub fn is_read_only(cmd_upper: &str, first_arg_upper: Option<&str>) -> bool {
+    is_listed(READ_ONLY_COMMANDS, cmd_upper, first_arg_upper)
+}
+
+/// Commands a read-only proxy accepts besides [`READ_ONLY_COMMANDS`]: connection setup and
+/// state, transactions (whose writes are refused on their own), subscriptions and server
+/// introspection.
+#[rustfmt::skip]
+const READ_ONLY_MODE_COMMANDS: &[&str] = &[
+    "AUTH", "HELLO", "SELECT", "QUIT", "RESET", "READONLY", "READWRITE",
+    "CLIENT SETNAME", "CLIENT GETNAME", "CLIENT ID", "CLIENT INFO", "CLIENT SETINFO",
+    "CLIENT TRACKING", "CLIENT CACHING", "CLIENT REPLY",
+    "MULTI", "EXEC", "DISCARD", "WATCH", "UNWATCH",
+    "SUBSCRIBE", "PSUBSCRIBE", "SSUBSCRIBE", "UNSUBSCRIBE", "PUNSUBSCRIBE", "SUNSUBSCRIBE",
+    "INFO", "COMMAND", "LASTSAVE", "ROLE", "FUNCTION LIST", "FUNCTION STATS",
+];
+
+/// Whether a read-only proxy forwards the command; others are refused without reaching a
+/// backend.
+pub fn read_only_mode_allows(cmd_upper: &str, first_arg_upper: Option<&str>) -> bool {
+    is_read_only(cmd_upper, first_arg_upper)
+        || is_listed(READ_ONLY_MODE_COMMANDS, cmd_upper, first_arg_upper)
+}
+
+/// Glob patterns that send matches to the master or the replica: the first key of a command,
+/// for `--master-keys` and `--replica-keys`, or the connection name, for `--master-clients` and
+/// `--replica-clients`. A match of both goes to the master. Replica rules only move known
+/// read-only commands, including ones off the replica read list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatternRules {
+    master: Vec<String>,
+    replica: Vec<String>,
+}
+
+impl PatternRules {
+    pub fn new(master: &[String], replica: &[String]) -> Self {
+        Self {
+            master: master.to_vec(),
+            replica: replica.to_vec(),
+        }
+    }
+
+    /// The route a rule forces for `cmd`, if one matches its first key. Whether the command
+    /// may go there is left to the caller.
+    pub fn route_key(&self, cmd_upper: &str, args: &[bytes::Bytes]) -> Option<Route> {
+        if self.master.is_empty() && self.replica.is_empty() {
+            return None;
+        }
+        self.matching(first_key(cmd_upper, args)?)
+    }
 
-        _ if is_always_master(cmd_upper) => Route::Master,
-        _ if is_replica_read(cmd_upper) => Route::Replica,
+    /// The route a rule forces for `subject`, if one matches.
+    pub fn matching(&self, subject: &[u8]) -> Option<Route> {
+        let matches =
+            |patterns: &[String]| patterns.iter().any(|p| glob_match(p.as_bytes(), subject));
+        if matches(&self.master) {
+            Some(Route::Master)
+        } else if matches(&self.replica) {
+            Some(Route::Replica)
+        } else {
+            None
+        }
+    }
+}
 
-        // scriptings
-        ("SCRIPT", Some("DEBUG" | "FLUSH" | "KILL" | "LOAD")) => Route::Both,
-        ("SCRIPT", None) | ("SCRIPT", Some("HELP")) => Route::Replica,
-        ("SCRIPT", Some("EXISTS")) => Route::Master,
+/// The first key of a command, for [`PatternRules`]. `None` for commands without keys.
+fn first_key<'a>(cmd_upper: &str, args: &'a [bytes::Bytes]) -> Option<&'a bytes::Bytes> {
+    let numkeys = |at: usize| {
+        args.get(at)
+            .and_then(|n| std::str::from_utf8(n).ok())
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .and_then(|_| args.get(at + 1))
+    };
+    match cmd_upper {
+        "PING" | "ECHO" | "TIME" | "SCAN" | "KEYS" | "DBSIZE" | "RANDOMKEY" | "INFO" | "CONFIG"
+        | "CLIENT" | "SCRIPT" | "FUNCTION" | "COMMAND" | "HELLO" | "AUTH" | "SELECT"
+        | "READONLY" | "READWRITE" | "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" | "QUIT"
+        | "RESET" | "FLUSHDB" | "FLUSHALL" | "SWAPDB" | "WAIT" | "WAITAOF" | "LASTSAVE"
+        | "SAVE" | "BGSAVE" | "BGREWRITEAOF" | "SLOWLOG" | "LATENCY" | "ACL" | "MODULE"
+        | "ROLE" | "MONITOR" | "CLUSTER" | "PUBLISH" | "SPUBLISH" | "PUBSUB" | "SUBSCRIBE"
+        | "PSUBSCRIBE" | "SSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => None,
+        // OBJECT ENCODING key, MEMORY USAGE key, DEBUG OBJECT key, XINFO STREAM key,
+        // XGROUP CREATE key group id, BITOP AND destkey key...
+        "OBJECT" | "MEMORY" | "DEBUG" | "XINFO" | "XGROUP" | "BITOP" => args.get(1),
+        // MIGRATE host port key|"" destination-db timeout ... [KEYS key...]
+        "MIGRATE" => match args.get(2) {
+            Some(key) if key.is_empty() => args
+                .iter()
+                .position(|a| a.eq_ignore_ascii_case(b"KEYS"))
+                .and_then(|at| args.get(at + 1)),
+            key => key,
+        },
+        // XREAD [options] STREAMS key... id...
+        "XREAD" | "XREADGROUP" => args
+            .iter()
+            .position(|a| a.eq_ignore_ascii_case(b"STREAMS"))
+            .and_then(|at| args.get(at + 1)),
+        "SINTERCARD" | "ZINTERCARD" | "ZINTER" | "ZUNION" | "ZDIFF" | "LMPOP" | "ZMPOP" => {
+            numkeys(0)
+        }
+        "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO" | "BLMPOP"
+        | "BZMPOP" => numkeys(1),
+        _ => args.first(),
+    }
+}
 
-        ("EVAL" | "EVALSHA", _) => Route::Master,
-        ("EVAL_RO" | "EVALSHA_RO", _) => Route::Replica,
+/// Match `key` against a glob `pattern` the way `KEYS` does: `*`, `?`, `[abc]`, `[a-z]`,
+/// `[^abc]` and `\` to escape the next character.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // The pattern after the last `*`, and where in the key that `*` stopped.
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => {
+                let (matched, len) = class_match(&pattern[p..], key[k]);
+                matched.then_some(len)
+            }
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == key[k]).then_some(2),
+            Some(c) => (*c == key[k]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                k += 1;
+            }
+            // Let the last `*` take one more character.
+            (None, Some((after, at))) => {
+                star = Some((after, at + 1));
+                p = after;
+                k = at + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
 
-        _ => Route::Master,
+/// Match `c` against the `[...]` class at the start of `pattern`; also returns the length of
+/// the class. A class left open runs to the end of the pattern, as in Redis.
+fn class_match(pattern: &[u8], c: u8) -> (bool, usize) {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
     }
+    (matched != negate, (i + 1).min(pattern.len()))
 }
 
+/// `cmd` upper-cased, with single spaces between a command and its subcommand.
+fn normalize(cmd: &str) -> String {
+    cmd.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_ascii_uppercase()
+}
+
+/// Commands sent to both the master and the replica, because they change connection state that
+/// both backend connections must agree on.
+pub const DUAL_FORWARD: &[&str] = &[
+    "HELLO",
+    "SELECT",
+    "RESET",
+    "READONLY",
+    "READWRITE",
+    "CLIENT SETNAME",
+    "CLIENT SETINFO",
+    "CLIENT REPLY",
+    // scripting: keep both script caches in sync
+    "SCRIPT DEBUG",
+    "SCRIPT FLUSH",
+    "SCRIPT KILL",
+    "SCRIPT LOAD",
+];
+
+/// Commands that are always routed to the master regardless of whitelist.
+///
+/// This includes scripting and other constructs where reads/writes can be mixed, or where semantics depend on connection state.
+pub const ALWAYS_MASTER: &[&str] = &[
+    "MULTI",
+    "EXEC",
+    "DISCARD",
+    "WATCH",
+    "UNWATCH",
+    "FUNCTION",
+    "FCALL",
+    "MONITOR",
+    // Client-side caching: only master tracks, and reads of a tracking connection go there too,
+    // so that every invalidation it is owed comes from one node, once.
+    "CLIENT TRACKING",
+    "CLIENT CACHING",
+    // Pub/sub, sharded channels included: a standalone master serves every slot. With several
+    // shards, `SSUBSCRIBE` and `SPUBLISH` would have to go to the owner of their channel's slot.
+    // Keyspace notifications (`__keyspace@*`, `__keyevent@*`) fire where the write happens, so
+    // only master has them; see `--replica-expired-events` for the keys a replica expires.
+    "SUBSCRIBE",
+    "PSUBSCRIBE",
+    "SSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "UNSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "PUBLISH",
+    "SPUBLISH",
+];
+
 /// Extremely conservative whitelist of commands that are safe to route to a read replica.
 ///
 /// Policy: **default master, explicit allow-list only**.
-fn is_replica_read(cmd_upper: &str) -> bool {
-    matches!(
-        cmd_upper,
-        // connection / healthcheck
-        "PING" |
-        // scan family (cursor-based iterators)
-        "SCAN" | "SSCAN" | "HSCAN" | "ZSCAN" |
-        // strings
-        "GET" | "MGET" | "GETRANGE" | "STRLEN" |
-        // hashes
-        "HGET" | "HMGET" | "HGETALL" | "HEXISTS" | "HLEN" | "HSTRLEN" | "HKEYS" | "HVALS" |
-        // lists
-        "LINDEX" | "LLEN" | "LRANGE" |
-        // sets
-        "SCARD" | "SISMEMBER" | "SMISMEMBER" | "SMEMBERS" | "SRANDMEMBER" |
-        // sorted sets
-        "ZCARD" | "ZCOUNT" | "ZRANGE" | "ZRANGEBYSCORE" | "ZREVRANGE" | "ZREVRANGEBYSCORE" |
-        "ZRANK" | "ZREVRANK" | "ZSCORE" | "ZMSCORE" |
-        // generic
-        "EXISTS" | "TYPE" | "TTL" | "PTTL"
-    )
+#[rustfmt::skip]
+pub const REPLICA_READS: &[&str] = &[
+    // connection / healthcheck
+    "PING",
+    // scan family (cursor-based iterators)
+    "SCAN", "SSCAN", "HSCAN", "ZSCAN",
+    // strings
+    "GET", "MGET", "GETRANGE", "STRLEN",
+    // bitmaps; BITFIELD stays on master even with only GET operations
+    "GETBIT", "BITCOUNT", "BITPOS", "BITFIELD_RO",
+    // hashes
+    "HGET", "HMGET", "HGETALL", "HEXISTS", "HLEN", "HSTRLEN", "HKEYS", "HVALS",
+    // random picks differ between backends anyway
+    "HRANDFIELD",
+    // lists
+    "LINDEX", "LLEN", "LRANGE", "LPOS",
+    // sets
+    "SCARD", "SISMEMBER", "SMISMEMBER", "SMEMBERS", "SRANDMEMBER",
+    "SINTER", "SINTERCARD", "SUNION", "SDIFF",
+    // sorted sets
+    "ZCARD", "ZCOUNT", "ZLEXCOUNT", "ZRANGE", "ZRANGEBYSCORE", "ZRANGEBYLEX", "ZREVRANGE",
+    "ZREVRANGEBYSCORE", "ZRANK", "ZREVRANK", "ZSCORE", "ZMSCORE", "ZRANDMEMBER",
+    // geo; GEORADIUS without STORE is sent as GEORADIUS_RO, see `read_only_variant`
+    "GEOPOS", "GEODIST", "GEOHASH", "GEOSEARCH", "GEORADIUS_RO", "GEORADIUSBYMEMBER_RO",
+    // generic
+    "EXISTS", "TYPE", "TTL", "PTTL", "SORT_RO",
+    // scripting
+    "SCRIPT HELP", "EVAL_RO", "EVALSHA_RO", "FCALL_RO",
+];
+
+/// Commands that read and never write, which `--replica-read` may add to [`REPLICA_READS`], and
+/// which `--analyze-scripts` accepts in scripts.
+#[rustfmt::skip]
+pub const READ_ONLY_COMMANDS: &[&str] = &[
+    "PING", "ECHO", "TIME",
+    "SCAN", "SSCAN", "HSCAN", "ZSCAN",
+    // strings and bitmaps
+    "GET", "MGET", "GETRANGE", "SUBSTR", "STRLEN", "LCS", "GETBIT", "BITCOUNT", "BITPOS",
+    "BITFIELD_RO",
+    // hashes
+    "HGET", "HMGET", "HGETALL", "HEXISTS", "HLEN", "HSTRLEN", "HKEYS", "HVALS", "HRANDFIELD",
+    // lists
+    "LINDEX", "LLEN", "LRANGE", "LPOS",
+    // sets
+    "SCARD", "SISMEMBER", "SMISMEMBER", "SMEMBERS", "SRANDMEMBER", "SINTER", "SINTERCARD",
+    "SUNION", "SDIFF",
+    // sorted sets
+    "ZCARD", "ZCOUNT", "ZLEXCOUNT", "ZRANGE", "ZRANGEBYSCORE", "ZRANGEBYLEX", "ZREVRANGE",
+    "ZREVRANGEBYSCORE", "ZREVRANGEBYLEX", "ZRANK", "ZREVRANK", "ZSCORE", "ZMSCORE",
+    "ZRANDMEMBER", "ZINTER", "ZUNION", "ZDIFF", "ZINTERCARD",
+    // geo
+    "GEOPOS", "GEODIST", "GEOHASH", "GEOSEARCH", "GEORADIUS_RO", "GEORADIUSBYMEMBER_RO",
+    // hyperloglog
+    "PFCOUNT",
+    // streams
+    "XLEN", "XRANGE", "XREVRANGE", "XREAD", "XPENDING", "XINFO STREAM", "XINFO GROUPS",
+    "XINFO CONSUMERS",
+    // generic
+    "EXISTS", "TYPE", "TTL", "PTTL", "EXPIRETIME", "PEXPIRETIME", "DUMP", "TOUCH", "SORT_RO",
+    "RANDOMKEY", "KEYS", "DBSIZE", "OBJECT ENCODING", "OBJECT FREQ", "OBJECT IDLETIME",
+    "OBJECT REFCOUNT", "MEMORY USAGE", "DEBUG OBJECT",
+    // scripting
+    "SCRIPT HELP", "SCRIPT EXISTS", "EVAL_RO", "EVALSHA_RO", "FCALL_RO",
+];
+
+/// Stream reads added to the replica reads by `--replica-stream-reads`. `XREADGROUP` moves
+/// consumer group state and stays on master.
+pub const STREAM_READS: &[&str] = &[
+    "XLEN",
+    "XRANGE",
+    "XREVRANGE",
+    "XINFO STREAM",
+    "XINFO GROUPS",
+    "XINFO CONSUMERS",
+    "XREAD",
+];
+
+/// Key introspection added to the replica reads by `--replica-introspection-reads`.
+pub const INTROSPECTION_READS: &[&str] = &[
+    "OBJECT ENCODING",
+    "OBJECT FREQ",
+    "OBJECT IDLETIME",
+    "OBJECT REFCOUNT",
+    "MEMORY USAGE",
+    "DEBUG OBJECT",
+];
+
+/// Read-only commands that walk or sample the whole keyspace, which `--replica-heavy-reads`
+/// moves off master. The stats mark them wherever they are served.
+pub const HEAVY_READS: &[&str] = &["KEYS", "DBSIZE", "RANDOMKEY"];
+
+/// Whether a replica read must go to master anyway for its options: a blocking `XREAD` waits
+/// for entries the master adds, and would hold the replica connection meanwhile.
+pub fn needs_master(cmd_upper: &str, args: &[bytes::Bytes]) -> bool {
+    match cmd_upper {
+        "XREAD" => args
+            .iter()
+            .take_while(|a| !a.eq_ignore_ascii_case(b"STREAMS"))
+            .any(|a| a.eq_ignore_ascii_case(b"BLOCK")),
+        _ => false,
+    }
 }
 
-/// Commands that are always routed to the master regardless of whitelist.
+/// Whether a command may wait on the server for something else to happen, e.g. a `BLPOP` for
+/// a push or a `WAIT` for the replicas.
+pub fn is_blocking(cmd_upper: &str, args: &[bytes::Bytes]) -> bool {
+    match cmd_upper {
+        "BLPOP" | "BRPOP" | "BRPOPLPUSH" | "BLMOVE" | "BLMPOP" | "BZPOPMIN" | "BZPOPMAX"
+        | "BZMPOP" | "WAIT" | "WAITAOF" => true,
+        "XREADGROUP" => args
+            .iter()
+            .take_while(|a| !a.eq_ignore_ascii_case(b"STREAMS"))
+            .any(|a| a.eq_ignore_ascii_case(b"BLOCK")),
+        _ => needs_master(cmd_upper, args),
+    }
+}
+
+/// The read-only twin of a command that only writes with some options, when those options are
+/// absent: `GEORADIUS` and `GEORADIUSBYMEMBER` store their result with `STORE`/`STOREDIST`.
+pub fn read_only_variant(cmd_upper: &str, args: &[bytes::Bytes]) -> Option<&'static str> {
+    let (variant, options_at) = match cmd_upper {
+        // key longitude latitude radius unit [options]
+        "GEORADIUS" => ("GEORADIUS_RO", 5),
+        // key member radius unit [options]
+        "GEORADIUSBYMEMBER" => ("GEORADIUSBYMEMBER_RO", 4),
+        _ => return None,
+    };
+    let stores = args
+        .iter()
+        .skip(options_at)
+        .any(|a| a.eq_ignore_ascii_case(b"STORE") || a.eq_ignore_ascii_case(b"STOREDIST"));
+    (!stores).then_some(variant)
+}
+
+/// Whether `SORT` arguments store the result: `SORT key [BY pattern] [LIMIT offset count]
+/// [GET pattern ...] [ASC|DESC] [ALPHA] [STORE destination]`.
+pub fn sort_stores(args: &[bytes::Bytes]) -> bool {
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        let upper = arg.to_ascii_uppercase();
+        i += match upper.as_slice() {
+            b"STORE" => return true,
+            b"BY" | b"GET" => 2,
+            b"LIMIT" => 3,
+            _ => 1,
+        };
+    }
+    false
+}
+
+/// Master-served commands that change nothing, so they can be sent again like [`REPLICA_READS`].
+#[rustfmt::skip]
+const MASTER_READS: &[&str] = &[
+    "ECHO", "TIME", "DBSIZE", "INFO", "LASTSAVE", "RANDOMKEY", "KEYS",
+];
+
+/// Whether a command can be sent again when the master connection drops before its reply
+/// arrives: running it twice has the same effect as running it once.
+pub fn is_retryable(cmd_upper: &str, first_arg_upper: Option<&str>) -> bool {
+    is_listed(REPLICA_READS, cmd_upper, first_arg_upper)
+        || is_listed(MASTER_READS, cmd_upper, first_arg_upper)
+}
+
+/// Commands the proxy refuses instead of forwarding, with the reason reported to the client.
 ///
-/// This includes scripting and other constructs where reads/writes can be mixed, or where semantics depend on connection state.
-pub fn is_always_master(cmd_upper: &str) -> bool {
-    matches!(
-        cmd_upper,
-        "MULTI"
-            | "EXEC"
-            | "DISCARD"
-            | "WATCH"
-            | "UNWATCH"
-            | "FUNCTION"
-            | "FCALL"
-            | "FCALL_RO"
-            | "MONITOR"
-            | "SUBSCRIBE"
-            | "PSUBSCRIBE"
-            | "SSUBSCRIBE"
-            | "PUNSUBSCRIBE"
-            | "UNSUBSCRIBE"
-            | "SUNSUBSCRIBE"
-    )
+/// `PSYNC`/`SYNC`/`REPLCONF` switch a backend connection into the replication stream, and the
+/// cluster bus commands change cluster topology; neither survives being multiplexed through a
+/// request/reply proxy.
+pub fn rejected_reason(cmd_upper: &str, first_arg_upper: Option<&str>) -> Option<&'static str> {
+    match (cmd_upper, first_arg_upper) {
+        ("PSYNC" | "SYNC" | "REPLCONF", _) => {
+            Some("replication commands are not supported by the proxy")
+        }
+        (
+            "CLUSTER",
+            Some(
+                "MEET" | "FORGET" | "REPLICATE" | "FAILOVER" | "RESET" | "SETSLOT" | "ADDSLOTS"
+                | "ADDSLOTSRANGE" | "DELSLOTS" | "DELSLOTSRANGE" | "FLUSHSLOTS" | "BUMPEPOCH"
+                | "SET-CONFIG-EPOCH",
+            ),
+        ) => Some("cluster management commands are not supported by the proxy"),
+        _ => None,
+    }
+}
+
+/// What a connection is used for, sniffed from its first command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnClass {
+    Regular,
+    /// Opened with a (P/S)SUBSCRIBE: a pub/sub consumer that only ever needs the master.
+    PubSub,
+    /// Opened with MONITOR.
+    Monitor,
+    /// Opened with PSYNC/SYNC/REPLCONF: a replica or replication tool, which can't be proxied.
+    Replication,
+}
+
+impl ConnClass {
+    /// Classify by the first command of a connection. Returns `None` for connection setup
+    /// commands, which say nothing about what the client is going to do.
+    pub fn sniff(cmd_upper: &str) -> Option<Self> {
+        match cmd_upper {
+            "AUTH" | "HELLO" | "SELECT" | "CLIENT" | "PING" | "ECHO" | "READONLY" | "READWRITE" => {
+                None
+            }
+            "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => Some(Self::PubSub),
+            "MONITOR" => Some(Self::Monitor),
+            "PSYNC" | "SYNC" | "REPLCONF" => Some(Self::Replication),
+            _ => Some(Self::Regular),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Regular => "regular",
+            Self::PubSub => "pubsub",
+            Self::Monitor => "monitor",
+            Self::Replication => "replication",
+        }
+    }
+
+    /// Whether connections of this class keep a replica connection.
+    pub fn uses_replica(self) -> bool {
+        self == Self::Regular
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(cmd_upper: &str) -> Route {
+        route_cmd(cmd_upper, None, &ReplicaReads::default())
+    }
+
+    #[test]
+    fn added_reads_go_to_replica() {
+        for cmd in [
+            "HRANDFIELD",
+            "LPOS",
+            "SINTER",
+            "SINTERCARD",
+            "SUNION",
+            "SDIFF",
+            "ZLEXCOUNT",
+            "ZRANGEBYLEX",
+            "ZRANDMEMBER",
+        ] {
+            assert_eq!(route(cmd), Route::Replica, "{cmd}");
+        }
+    }
+
+    #[test]
+    fn subcommand_first_commands_match_their_key() {
+        let rules = PatternRules::new(&["stream*".to_string()], &[]);
+        let args = |args: &[&str]| -> Vec<bytes::Bytes> {
+            args.iter()
+                .map(|a| bytes::Bytes::copy_from_slice(a.as_bytes()))
+                .collect()
+        };
+        for (cmd, args) in [
+            ("XINFO", args(&["STREAM", "stream:1"])),
+            ("XGROUP", args(&["CREATE", "stream:1", "g", "$"])),
+            ("BITOP", args(&["AND", "stream:dest", "a", "b"])),
+            ("MIGRATE", args(&["h", "6379", "stream:1", "0", "1000"])),
+            (
+                "MIGRATE",
+                args(&["h", "6379", "", "0", "1000", "KEYS", "stream:1"]),
+            ),
+        ] {
+            assert_eq!(rules.route_key(cmd, &args), Some(Route::Master), "{cmd}");
+        }
+        let keyless = PatternRules::new(&["STREAM".to_string()], &[]);
+        assert_eq!(
+            keyless.route_key("XINFO", &args(&["STREAM", "other"])),
+            None
+        );
+    }
+
+    #[test]
+    fn their_write_and_blocking_variants_stay_on_master() {
+        for cmd in [
+            "HDEL",
+            "LPOP",
+            "BLPOP",
+            "LMPOP",
+            "BLMPOP",
+            "SINTERSTORE",
+            "SUNIONSTORE",
+            "SDIFFSTORE",
+            "SPOP",
+            "ZRANGESTORE",
+            "ZREMRANGEBYLEX",
+            "ZPOPMIN",
+            "BZPOPMIN",
+        ] {
+            assert_eq!(route(cmd), Route::Master, "{cmd}");
+        }
+    }
 }
@@ -0,0 +1,70 @@
+use crate::routing::{ALWAYS_MASTER, DUAL_FORWARD, ReplicaReads};
+
+/// Output format of `print-routing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RoutingFormat {
+    /// One `ROUTE COMMAND` line per rule, for humans.
+    #[default]
+    Table,
+    /// A JSON object with one list per route.
+    Json,
+}
+
+/// A setting that changes routing away from the built-in lists.
+#[derive(Debug, Clone)]
+pub struct Override {
+    /// The setting's name, as in the config file or `[[listeners]]`.
+    pub setting: String,
+    pub effect: String,
+}
+
+/// The effective routing table: the lists with `replica_reads` for the replica, then the
+/// overrides in effect.
+pub fn render(
+    format: RoutingFormat,
+    replica_reads: &ReplicaReads,
+    overrides: &[Override],
+) -> String {
+    let replica: Vec<&str> = replica_reads
+        .commands()
+        .iter()
+        .map(String::as_str)
+        .collect();
+    match format {
+        RoutingFormat::Table => {
+            let mut out = String::new();
+            for (route, list) in [
+                ("BOTH", DUAL_FORWARD),
+                ("MASTER", ALWAYS_MASTER),
+                ("REPLICA", &replica),
+            ] {
+                for cmd in list {
+                    out.push_str(&format!("{route:<7} {cmd}\n"));
+                }
+            }
+            out.push_str("MASTER  (any other command)\n");
+
+            if !overrides.is_empty() {
+                let width = overrides.iter().map(|o| o.setting.len()).max().unwrap_or(0);
+                out.push_str("\noverrides:\n");
+                for o in overrides {
+                    out.push_str(&format!("  {:<width$}  {}\n", o.setting, o.effect));
+                }
+            }
+            out
+        }
+        RoutingFormat::Json => {
+            let report = serde_json::json!({
+                "dual_forward": DUAL_FORWARD,
+                "always_master": ALWAYS_MASTER,
+                "replica_whitelist": replica,
+                "default": "master",
+                "overrides": overrides
+                    .iter()
+                    .map(|o| serde_json::json!({ "setting": o.setting, "effect": o.effect }))
+                    .collect::<Vec<_>>(),
+            });
+            format!("{report:#}\n")
+        }
+    }
+}
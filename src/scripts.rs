@@ -0,0 +1,225 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::resp::Reply;
+use crate::routing::READ_ONLY_COMMANDS;
+
+/// Scripts whose analysis is kept before the cache starts over, bounding its memory when
+/// clients generate scripts.
+const MAX_ANALYZED_SCRIPTS: usize = 10_000;
+
+/// How long a `FUNCTION LIST` snapshot is trusted before it is fetched again.
+///
+/// Functions changed through the proxy invalidate the snapshot immediately; the TTL only bounds
+/// staleness for changes made directly on the master.
+const FUNCTION_LIST_TTL: Duration = Duration::from_secs(30);
+
+/// Process-wide knowledge of which scripts and functions declare the `no-writes` flag.
+#[derive(Debug, Default)]
+pub struct ScriptRegistry {
+    // Keyed by lowercase SHA1 of scripts loaded through `SCRIPT LOAD`.
+    scripts: DashMap<String, bool>,
+    /// Whether a script only calls read commands, by [`calls_only_reads`]; keyed like `scripts`.
+    analyzed: DashMap<String, bool>,
+    functions: RwLock<FunctionSnapshot>,
+}
+
+#[derive(Debug, Default)]
+struct FunctionSnapshot {
+    fetched_at: Option<Instant>,
+    read_only: HashSet<String>,
+}
+
+impl ScriptRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a script body seen in `SCRIPT LOAD`, which is dual-forwarded and therefore
+    /// cached on the replica as well.
+    pub fn record_script_load(&self, body: &[u8]) {
+        let sha = sha1_smol::Sha1::from(body).digest().to_string();
+        self.scripts.insert(sha.clone(), shebang_no_writes(body));
+        self.remember_analysis(sha, calls_only_reads(body));
+    }
+
+    /// Whether the script of an `EVAL` body or an `EVALSHA` digest only calls read commands, for
+    /// `analyze_scripts`. `EVALSHA` of a script this proxy hasn't seen counts as writing.
+    pub fn analyzed_read_only(&self, cmd_upper: &str, first: &[u8]) -> bool {
+        match cmd_upper {
+            "EVAL" => {
+                let sha = sha1_smol::Sha1::from(first).digest().to_string();
+                if let Some(known) = self.analyzed.get(&sha) {
+                    return *known;
+                }
+                let read_only = calls_only_reads(first);
+                self.remember_analysis(sha, read_only);
+                read_only
+            }
+            "EVALSHA" => {
+                let sha = String::from_utf8_lossy(first).to_ascii_lowercase();
+                self.analyzed.get(&sha).is_some_and(|e| *e.value())
+            }
+            _ => false,
+        }
+    }
+
+    fn remember_analysis(&self, sha: String, read_only: bool) {
+        if self.analyzed.len() >= MAX_ANALYZED_SCRIPTS {
+            self.analyzed.clear();
+        }
+        self.analyzed.insert(sha, read_only);
+    }
+
+    pub fn is_read_only_sha(&self, sha: &[u8]) -> bool {
+        let sha = String::from_utf8_lossy(sha).to_ascii_lowercase();
+        self.scripts.get(&sha).is_some_and(|e| *e.value())
+    }
+
+    pub fn flush_scripts(&self) {
+        self.scripts.clear();
+        self.analyzed.clear();
+    }
+
+    pub fn functions_stale(&self) -> bool {
+        let snapshot = self
+            .functions
+            .read()
+            .expect("function snapshot lock poisoned");
+        snapshot
+            .fetched_at
+            .is_none_or(|t| t.elapsed() >= FUNCTION_LIST_TTL)
+    }
+
+    pub fn set_functions(&self, read_only: HashSet<String>) {
+        let mut snapshot = self
+            .functions
+            .write()
+            .expect("function snapshot lock poisoned");
+        snapshot.fetched_at = Some(Instant::now());
+        snapshot.read_only = read_only;
+    }
+
+    pub fn invalidate_functions(&self) {
+        let mut snapshot = self
+            .functions
+            .write()
+            .expect("function snapshot lock poisoned");
+        snapshot.fetched_at = None;
+    }
+
+    pub fn is_read_only_function(&self, name: &[u8]) -> bool {
+        let Ok(name) = std::str::from_utf8(name) else {
+            return false;
+        };
+        let snapshot = self
+            .functions
+            .read()
+            .expect("function snapshot lock poisoned");
+        snapshot.read_only.contains(name)
+    }
+}
+
+/// Whether a script declares `no-writes` in its shebang, e.g. `#!lua flags=no-writes,allow-stale`.
+pub fn shebang_no_writes(body: &[u8]) -> bool {
+    let Some(rest) = body.strip_prefix(b"#!") else {
+        return false;
+    };
+    let line = rest.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    line.split_ascii_whitespace()
+        .filter_map(|tok| tok.strip_prefix("flags="))
+        .any(|flags| flags.split(',').any(|f| f == "no-writes"))
+}
+
+/// Whether a script only calls read commands: every `redis.call`/`redis.pcall` (or `server.`)
+/// names a [`READ_ONLY_COMMANDS`] command as a string literal. The analysis is conservative:
+/// any other use of `redis` or `server`, like passing `redis.call` around or indexing
+/// `redis[...]`, and any `load`/`loadstring` of code, count as writing.
+pub fn calls_only_reads(body: &[u8]) -> bool {
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let skip_ws = |mut i: usize| {
+        while body.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        i
+    };
+    let word_at = |i: usize, word: &[u8]| {
+        body[i..].starts_with(word)
+            && (i == 0 || !is_ident(body[i - 1]))
+            && !body.get(i + word.len()).copied().is_some_and(is_ident)
+    };
+
+    let mut i = 0;
+    while i < body.len() {
+        if word_at(i, b"load") || word_at(i, b"loadstring") {
+            return false;
+        }
+        let Some(len) = [&b"redis"[..], b"server"]
+            .into_iter()
+            .find(|w| word_at(i, w))
+            .map(<[u8]>::len)
+        else {
+            i += 1;
+            continue;
+        };
+        let mut j = skip_ws(i + len);
+        if body.get(j) != Some(&b'.') {
+            return false;
+        }
+        j = skip_ws(j + 1);
+        let name_end = j + body[j..].iter().take_while(|b| is_ident(**b)).count();
+        let name = &body[j..name_end];
+        if name == b"call" || name == b"pcall" {
+            j = skip_ws(name_end);
+            if body.get(j) != Some(&b'(') {
+                return false;
+            }
+            j = skip_ws(j + 1);
+            let Some(quote) = body.get(j).copied().filter(|q| *q == b'\'' || *q == b'"') else {
+                return false;
+            };
+            let Some(len) = body[j + 1..]
+                .iter()
+                .position(|b| *b == quote || *b == b'\\')
+            else {
+                return false;
+            };
+            let command = String::from_utf8_lossy(&body[j + 1..j + 1 + len]).to_ascii_uppercase();
+            if body[j + 1 + len] != quote || !READ_ONLY_COMMANDS.contains(&command.as_str()) {
+                return false;
+            }
+            j += len + 2;
+        }
+        i = j.max(name_end);
+    }
+    true
+}
+
+/// Collect the names of `no-writes` functions from a `FUNCTION LIST` reply.
+pub fn parse_function_list(reply: &Reply) -> HashSet<String> {
+    let mut out = HashSet::new();
+    for library in reply.as_list().unwrap_or_default() {
+        let functions = library
+            .field("functions")
+            .and_then(Reply::as_list)
+            .unwrap_or_default();
+        for function in functions {
+            let Some(name) = function.field("name").and_then(Reply::as_str) else {
+                continue;
+            };
+            let no_writes = function
+                .field("flags")
+                .and_then(Reply::as_list)
+                .unwrap_or_default()
+                .iter()
+                .any(|f| f.as_str() == Some("no-writes"));
+            if no_writes {
+                out.insert(name.to_string());
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::resp::RespStream;
+use crate::routing::Route;
+
+/// Slow commands kept for `PROXY SLOWLOG GET`; older entries are dropped.
+const SLOWLOG_MAX_LEN: usize = 128;
+
+/// Where the time of one command went, from the moment the proxy read it from the client to the
+/// moment the reply was written back.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandTiming {
+    pub total: Duration,
+    /// From the first backend write to the last backend reply, including replica fallbacks.
+    pub backend: Duration,
+}
+
+impl CommandTiming {
+    /// Derive the breakdown from the read/write timestamps the streams keep. Backends that were
+    /// not used for this command have older timestamps and are ignored.
+    pub fn measure<'a>(
+        client: &RespStream,
+        backends: impl IntoIterator<Item = &'a RespStream>,
+    ) -> Option<Self> {
+        let read = client.last_read()?;
+        let written = client.last_write().filter(|t| *t >= read)?;
+
+        let mut first_write: Option<Instant> = None;
+        let mut last_reply: Option<Instant> = None;
+        for backend in backends {
+            if let Some(w) = backend.last_write().filter(|t| *t >= read) {
+                first_write = Some(first_write.map_or(w, |f| f.min(w)));
+            }
+            // Replies drained after the client was answered don't delay the client.
+            if let Some(r) = backend.last_read().filter(|t| *t >= read && *t <= written) {
+                last_reply = Some(last_reply.map_or(r, |l| l.max(r)));
+            }
+        }
+        let backend = match (first_write, last_reply) {
+            (Some(w), Some(r)) => r.saturating_duration_since(w),
+            _ => Duration::ZERO,
+        };
+        Some(Self {
+            total: written - read,
+            backend,
+        })
+    }
+
+    /// Time spent in the proxy itself: parsing, routing, connecting and relaying.
+    pub fn proxy(&self) -> Duration {
+        self.total.saturating_sub(self.backend)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    at: SystemTime,
+    client: String,
+    command: String,
+    route: Route,
+    timing: CommandTiming,
+}
+
+/// Commands slower than the configured threshold, with their latency breakdown.
+#[derive(Debug, Default)]
+pub struct SlowLog {
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64,
+}
+
+impl SlowLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trace every command's breakdown, and keep and log it if it took at least `threshold`.
+    pub fn observe(
+        &self,
+        threshold: Option<Duration>,
+        client: &str,
+        command: &str,
+        route: Route,
+        timing: CommandTiming,
+    ) {
+        let (total_us, backend_us, proxy_us) = (
+            timing.total.as_micros(),
+            timing.backend.as_micros(),
+            timing.proxy().as_micros(),
+        );
+        tracing::trace!(
+            command,
+            route = route_name(route),
+            total_us,
+            backend_us,
+            proxy_us,
+            "command timing"
+        );
+        if threshold.is_none_or(|t| timing.total < t) {
+            return;
+        }
+
+        tracing::info!(
+            command,
+            route = route_name(route),
+            total_us,
+            backend_us,
+            proxy_us,
+            "slow command"
+        );
+        let mut entries = self.entries.lock().expect("slowlog lock poisoned");
+        if entries.len() == SLOWLOG_MAX_LEN {
+            entries.pop_back();
+        }
+        entries.push_front(Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            at: SystemTime::now(),
+            client: client.to_string(),
+            command: command.to_string(),
+            route,
+            timing,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("slowlog lock poisoned").len()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().expect("slowlog lock poisoned").clear();
+    }
+
+    /// The newest `count` entries as a RESP array, newest first. Each entry is
+    /// `[id, unix time, total µs, backend µs, proxy µs, command, route, client]`.
+    pub fn render(&self, count: usize) -> Vec<u8> {
+        let entries = self.entries.lock().expect("slowlog lock poisoned");
+        let shown = entries.len().min(count);
+        let mut out = format!("*{shown}\r\n").into_bytes();
+        for e in entries.iter().take(shown) {
+            let unix =
+                e.at.duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+            out.extend_from_slice(b"*8\r\n");
+            for n in [
+                e.id,
+                unix,
+                e.timing.total.as_micros() as u64,
+                e.timing.backend.as_micros() as u64,
+                e.timing.proxy().as_micros() as u64,
+            ] {
+                out.extend_from_slice(format!(":{n}\r\n").as_bytes());
+            }
+            for s in [e.command.as_str(), route_name(e.route), e.client.as_str()] {
+                out.extend_from_slice(format!("${}\r\n{s}\r\n", s.len()).as_bytes());
+            }
+        }
+        out
+    }
+}
+
+fn route_name(route: Route) -> &'static str {
+    match route {
+        Route::Master => "master",
+        Route::Replica => "replica",
+        Route::Both => "both",
+    }
+}
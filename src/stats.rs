@@ -1,20 +1,25 @@
 use dashmap::DashMap;
 
-use crate::routing::Route;
+use crate::routing::{HEAVY_READS, Route};
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct CmdStats {
     pub total: u64,
     pub replica_fallback_to_master: u64,
+    /// Dual-forwarded commands the replica refused while master accepted them, or the reverse.
+    pub replica_mismatch: u64,
+    /// Retries the retry budget didn't allow.
+    pub retries_denied: u64,
 }
 
 /// Process-wide statistics (shared across all client connections).
 ///
-/// The intent is operational visibility: "which commands actually go where".
+/// The intent is operational visibility: "which commands actually go where", and on behalf of
+/// which proxy user.
 #[derive(Debug, Default)]
 pub struct Stats {
-    // Keyed by (route, command_upper).
-    by_route_cmd: DashMap<(Route, String), CmdStats>,
+    // Keyed by (user, route, command_upper).
+    by_route_cmd: DashMap<(String, Route, String), CmdStats>,
 }
 
 impl Stats {
@@ -22,69 +27,291 @@ impl Stats {
         Self::default()
     }
 
-    pub fn record(&self, route: Route, cmd_upper: &str) {
-        let key = (route, cmd_upper.to_string());
+    pub fn record(&self, user: &str, route: Route, cmd_upper: &str) {
+        let key = (user.to_string(), route, cmd_upper.to_string());
         let mut entry = self.by_route_cmd.entry(key).or_default();
         entry.total = entry.total.saturating_add(1);
     }
 
-    pub fn record_replica_fallback(&self, cmd_upper: &str) {
-        let key = (Route::Replica, cmd_upper.to_string());
+    pub fn record_replica_fallback(&self, user: &str, cmd_upper: &str) {
+        let key = (user.to_string(), Route::Replica, cmd_upper.to_string());
         let mut entry = self.by_route_cmd.entry(key).or_default();
         entry.replica_fallback_to_master = entry.replica_fallback_to_master.saturating_add(1);
     }
 
-    /// Render summary lines similar to:
-    ///
-    /// ```text
-    /// BOTH    CLIENT 125 times
-    /// REPLICA GET    8056 times
-    /// ...
-    /// ```
-    pub fn render_summary_lines(&self) -> Vec<String> {
-        let mut rows: Vec<(Route, String, CmdStats)> = self
+    pub fn record_replica_mismatch(&self, user: &str, cmd_upper: &str) {
+        let key = (user.to_string(), Route::Both, cmd_upper.to_string());
+        let mut entry = self.by_route_cmd.entry(key).or_default();
+        entry.replica_mismatch = entry.replica_mismatch.saturating_add(1);
+    }
+
+    pub fn record_retry_denied(&self, user: &str, route: Route, cmd_upper: &str) {
+        let key = (user.to_string(), route, cmd_upper.to_string());
+        let mut entry = self.by_route_cmd.entry(key).or_default();
+        entry.retries_denied = entry.retries_denied.saturating_add(1);
+    }
+
+    /// Snapshot of the counters selected by `view`, in its sort order.
+    pub fn rows(&self, view: &SummaryView) -> Vec<SummaryRow> {
+        let mut rows: Vec<SummaryRow> = self
             .by_route_cmd
             .iter()
             .map(|e| {
-                let ((route, cmd), stats) = (e.key(), *e.value());
-                (*route, cmd.clone(), stats)
+                let ((user, route, cmd), stats) = (e.key(), *e.value());
+                SummaryRow {
+                    user: user.clone(),
+                    route: *route,
+                    command: cmd.clone(),
+                    heavy_read: HEAVY_READS.contains(&cmd.as_str()),
+                    stats,
+                }
             })
+            .filter(|r| view.matches(r))
             .collect();
 
         rows.sort_by(|a, b| {
             // Prefer BOTH/REPLICA visibility first (typical interest for this proxy).
-            let ra = route_rank(a.0);
-            let rb = route_rank(b.0);
-            ra.cmp(&rb)
-                .then_with(|| b.2.total.cmp(&a.2.total))
-                .then_with(|| a.1.cmp(&b.1))
-        });
+            let by_route = || route_rank(a.route).cmp(&route_rank(b.route));
+            let by_total = || b.stats.total.cmp(&a.stats.total);
+            let by_command = || a.command.cmp(&b.command);
+            let by_fallback = || {
+                b.stats
+                    .replica_fallback_to_master
+                    .cmp(&a.stats.replica_fallback_to_master)
+            };
 
-        let mut out = Vec::with_capacity(rows.len());
-        for (route, cmd, stats) in rows {
-            let route_s = match route {
-                Route::Both => "BOTH",
-                Route::Replica => "REPLICA",
-                Route::Master => "MASTER",
+            let ord = match view.sort {
+                SummarySort::Route => by_route().then_with(by_total),
+                SummarySort::Total => by_total().then_with(by_route),
+                SummarySort::Command => by_command().then_with(by_route),
+                SummarySort::Fallback => by_fallback().then_with(by_total),
             };
+            a.user
+                .cmp(&b.user)
+                .then(ord)
+                .then_with(by_command)
+                .then_with(by_route)
+        });
 
-            // Keep formatting close to the example while staying readable.
-            let mut line = format!("{:<7} {:<16} {} times", route_s, cmd, stats.total);
+        rows
+    }
 
-            if route == Route::Replica && stats.replica_fallback_to_master > 0 {
-                line.push_str(&format!(
-                    " (fallback {}times)",
-                    stats.replica_fallback_to_master
-                ));
+    /// Render the summary in the requested format.
+    pub fn render_summary(&self, view: &SummaryView) -> String {
+        let rows = self.rows(view);
+        match view.format {
+            SummaryFormat::Table => {
+                let mut out = String::new();
+                for line in render_table(&rows) {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+                out
             }
+            SummaryFormat::Json => {
+                let items: Vec<serde_json::Value> = rows
+                    .iter()
+                    .map(|r| {
+                        serde_json::json!({
+                            "user": r.user,
+                            "route": route_name(r.route),
+                            "command": r.command,
+                            "total": r.stats.total,
+                            "replica_fallback_to_master": r.stats.replica_fallback_to_master,
+                            "replica_mismatch": r.stats.replica_mismatch,
+                            "retries_denied": r.stats.retries_denied,
+                            "heavy_read": r.heavy_read,
+                        })
+                    })
+                    .collect();
+                format!("{}\n", serde_json::Value::Array(items))
+            }
+            SummaryFormat::Csv => {
+                let mut out = String::from(
+                    "user,route,command,total,replica_fallback_to_master,replica_mismatch,retries_denied,heavy_read\n",
+                );
+                for r in &rows {
+                    out.push_str(&format!(
+                        "{},{},{},{},{},{},{},{}\n",
+                        csv_field(&r.user),
+                        route_name(r.route),
+                        csv_field(&r.command),
+                        r.stats.total,
+                        r.stats.replica_fallback_to_master,
+                        r.stats.replica_mismatch,
+                        r.stats.retries_denied,
+                        r.heavy_read
+                    ));
+                }
+                out
+            }
+        }
+    }
+}
+
+/// One (user, route, command) counter line of the summary.
+#[derive(Debug, Clone)]
+pub struct SummaryRow {
+    pub user: String,
+    pub route: Route,
+    pub command: String,
+    /// One of the keyspace walks in [`HEAVY_READS`].
+    pub heavy_read: bool,
+    pub stats: CmdStats,
+}
+
+/// How the summary is rendered: format, order and which rows are shown.
+#[derive(Debug, Clone, Default)]
+pub struct SummaryView {
+    pub format: SummaryFormat,
+    pub sort: SummarySort,
+    pub filters: Vec<SummaryFilter>,
+}
+
+impl SummaryView {
+    /// Rows must match at least one route filter and one command filter, when any are given.
+    fn matches(&self, row: &SummaryRow) -> bool {
+        let (routes, prefixes): (Vec<_>, Vec<_>) = self
+            .filters
+            .iter()
+            .partition(|f| matches!(f, SummaryFilter::Route(_)));
+        let route_ok = routes.is_empty() || routes.iter().any(|f| f.matches(row));
+        let prefix_ok = prefixes.is_empty() || prefixes.iter().any(|f| f.matches(row));
+        route_ok && prefix_ok
+    }
+}
+
+/// Row order of the stats summary. Rows are always grouped by user first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummarySort {
+    /// BOTH, then REPLICA, then MASTER; busiest first within a route.
+    #[default]
+    Route,
+    /// Busiest commands first.
+    Total,
+    /// Alphabetical by command name.
+    Command,
+    /// Most replica fallbacks first.
+    Fallback,
+}
+
+/// Restricts the summary to a route (`BOTH`, `REPLICA`, `MASTER`), a command name prefix or the
+/// heavy reads (`HEAVY`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SummaryFilter {
+    Route(Route),
+    CommandPrefix(String),
+    HeavyReads,
+}
+
+impl SummaryFilter {
+    fn matches(&self, row: &SummaryRow) -> bool {
+        match self {
+            SummaryFilter::Route(r) => *r == row.route,
+            SummaryFilter::CommandPrefix(p) => row.command.starts_with(p.as_str()),
+            SummaryFilter::HeavyReads => row.heavy_read,
+        }
+    }
+}
+
+impl std::str::FromStr for SummaryFilter {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        Ok(match upper.as_str() {
+            "BOTH" => SummaryFilter::Route(Route::Both),
+            "REPLICA" => SummaryFilter::Route(Route::Replica),
+            "MASTER" => SummaryFilter::Route(Route::Master),
+            "HEAVY" => SummaryFilter::HeavyReads,
+            _ => SummaryFilter::CommandPrefix(upper),
+        })
+    }
+}
+
+/// Output format of the stats summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryFormat {
+    /// Fixed-width text for humans.
+    #[default]
+    Table,
+    /// A JSON array of row objects.
+    Json,
+    /// CSV with a header line.
+    Csv,
+}
+
+/// Render summary lines similar to:
+///
+/// ```text
+/// BOTH    CLIENT 125 times
+/// REPLICA GET    8056 times
+/// ...
+/// ```
+///
+/// When more than one user has been seen, each line is prefixed with the user.
+fn render_table(rows: &[SummaryRow]) -> Vec<String> {
+    let multi_user = rows.iter().any(|r| r.user != rows[0].user);
+    let user_width = rows.iter().map(|r| r.user.len()).max().unwrap_or(0);
 
-            out.push(line);
+    let mut out = Vec::with_capacity(rows.len());
+    for r in rows {
+        // Keep formatting close to the example while staying readable.
+        let mut line = format!(
+            "{:<7} {:<16} {} times",
+            route_name(r.route),
+            r.command,
+            r.stats.total
+        );
+        if multi_user {
+            line = format!("{:<user_width$} {line}", r.user);
         }
 
-        out
+        if r.route == Route::Replica && r.stats.replica_fallback_to_master > 0 {
+            line.push_str(&format!(
+                " (fallback {}times)",
+                r.stats.replica_fallback_to_master
+            ));
+        }
+        if r.heavy_read {
+            line.push_str(" (heavy read)");
+        }
+        if r.route == Route::Both && r.stats.replica_mismatch > 0 {
+            line.push_str(&format!(
+                " (replica mismatch {} times)",
+                r.stats.replica_mismatch
+            ));
+        }
+        if r.stats.retries_denied > 0 {
+            line.push_str(&format!(
+                " (retries denied {} times)",
+                r.stats.retries_denied
+            ));
+        }
+
+        out.push(line);
     }
+
+    out
 }
 
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn route_name(r: Route) -> &'static str {
+    match r {
+        Route::Both => "BOTH",
+        Route::Replica => "REPLICA",
+        Route::Master => "MASTER",
+    }
+}
 fn route_rank(r: Route) -> u8 {
     match r {
         Route::Both => 0,
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::proxy::Shared;
+
+/// Send `state`, e.g. `READY=1`, to systemd's `$NOTIFY_SOCKET`. Does nothing when the proxy
+/// doesn't run under a `Type=notify` unit.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        tracing::warn!(error = %e, state, "failed to notify systemd");
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract sockets are only supported on Linux",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "systemd notification needs Unix sockets",
+    ))
+}
+
+/// How often to send `WATCHDOG=1`: half of the unit's `WatchdogSec`, as systemd recommends.
+/// `None` without a watchdog, or when it is meant for another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Keep systemd's watchdog fed while the proxy can still reach the master. A master that is
+/// known to be down still counts: clients are answered `-MASTERDOWN` and restarting the proxy
+/// wouldn't help. The replica isn't checked, since reads fall back to master without it.
+pub async fn watchdog_loop(shared: Arc<Shared>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let cfg = shared.config();
+        let checked = tokio::time::timeout(
+            interval,
+            crate::check::ping(&cfg, &shared.pool.master(), &shared.resolver),
+        )
+        .await;
+        match checked {
+            Ok(Ok(mut conn)) => {
+                let _ = conn.shutdown().await;
+                notify("WATCHDOG=1");
+            }
+            _ if shared.master_watch.is_down() => notify("WATCHDOG=1"),
+            Ok(Err(e)) => {
+                tracing::warn!(error = %format!("{e:#}"), "watchdog PING of master failed")
+            }
+            Err(_) => tracing::warn!("watchdog PING of master timed out"),
+        }
+    }
+}
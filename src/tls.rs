@@ -0,0 +1,236 @@
+use anyhow::{Context, Result, anyhow};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{CertUserMapping, ListenerTls};
+
+/// Build the acceptor used to terminate client TLS on the listener.
+///
+/// When a client CA is configured, every client must present a certificate signed by it.
+pub fn build_acceptor(tls: &ListenerTls) -> Result<TlsAcceptor> {
+    let certs = load_certs(&tls.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+        .with_context(|| format!("Failed to read TLS key '{}'", tls.key_path.display()))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca_path {
+        Some(ca) => {
+            let roots = load_roots(ca)?;
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context("Failed to build client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server = builder
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate/key pair")?;
+
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Listener acceptor whose certificate can be swapped at runtime.
+///
+/// Established connections keep the config they were accepted with; only new handshakes see the
+/// reloaded certificate.
+pub struct ReloadableAcceptor {
+    settings: ListenerTls,
+    current: RwLock<TlsAcceptor>,
+    stamps: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(settings: ListenerTls) -> Result<Self> {
+        let acceptor = build_acceptor(&settings)?;
+        let stamps = Mutex::new(file_stamps(&settings));
+        Ok(Self {
+            settings,
+            current: RwLock::new(acceptor),
+            stamps,
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.current
+            .read()
+            .expect("tls acceptor lock poisoned")
+            .clone()
+    }
+
+    /// Rebuild the acceptor from disk. On error the previous certificate stays in use.
+    pub fn reload(&self) -> Result<()> {
+        // Record the stamps first so a broken file is reported once, not on every poll.
+        *self.stamps.lock().expect("tls stamps lock poisoned") = file_stamps(&self.settings);
+        let acceptor = build_acceptor(&self.settings)?;
+        *self.current.write().expect("tls acceptor lock poisoned") = acceptor;
+        Ok(())
+    }
+
+    /// Whether any of the certificate/key/CA files changed since the last (re)load.
+    pub fn files_changed(&self) -> bool {
+        *self.stamps.lock().expect("tls stamps lock poisoned") != file_stamps(&self.settings)
+    }
+}
+
+fn file_stamps(tls: &ListenerTls) -> Vec<Option<SystemTime>> {
+    [
+        Some(tls.cert_path.as_path()),
+        Some(tls.key_path.as_path()),
+        tls.client_ca_path.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read certificates '{}'", path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM in '{}'", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in '{}'", path.display()));
+    }
+    Ok(certs)
+}
+
+pub fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in '{}'", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// Identities carried by a client certificate: the subject CN plus DNS/email/URI SANs.
+pub fn cert_identities(cert: &CertificateDer<'_>) -> Vec<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let Ok((_, parsed)) = x509_parser::parse_x509_certificate(cert.as_ref()) else {
+        return Vec::new();
+    };
+
+    let mut out: Vec<String> = parsed
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(|s| s.to_string())
+        .collect();
+
+    if let Ok(Some(san)) = parsed.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                    out.push(s.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    out
+}
+
+/// Resolve the proxy user for a verified client certificate, if any mapping matches.
+pub fn map_cert_user(mappings: &[CertUserMapping], cert: &CertificateDer<'_>) -> Option<String> {
+    let identities = cert_identities(cert);
+    mappings
+        .iter()
+        .find(|m| identities.contains(&m.identity))
+        .map(|m| m.user.clone())
+}
+
+/// Build the connector used to reach a `rediss://` backend.
+///
+/// Without a custom CA, the server certificate is verified against the bundled web PKI roots.
+pub fn build_connector(ca_path: Option<&Path>, insecure: bool) -> Result<TlsConnector> {
+    let builder = ClientConfig::builder();
+    let client = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerify(
+                CryptoProvider::get_default().cloned().unwrap_or_else(|| {
+                    Arc::new(tokio_rustls::rustls::crypto::ring::default_provider())
+                }),
+            )))
+            .with_no_client_auth()
+    } else {
+        let roots = match ca_path {
+            Some(ca) => load_roots(ca)?,
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+
+    Ok(TlsConnector::from(Arc::new(client)))
+}
+
+/// Accepts any server certificate. Signatures are still checked so the handshake stays sound.
+#[derive(Debug)]
+struct NoVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::command::ParsedCommand;
+use crate::resp::{RespStream, encode_command_str};
+
+/// A `MULTI` the proxy queues itself for `replica_transactions`, so that a transaction of only
+/// replica reads can run on the replica at `EXEC`. The client is answered `+OK` and `+QUEUED`
+/// locally; once a command that isn't a replica read arrives, the queue is replayed on master
+/// and the transaction continues there.
+///
+/// A command the backend refuses to queue is answered `+QUEUED` all the same; the backend then
+/// aborts the transaction at `EXEC` with `-EXECABORT`, as it would have directly.
+#[derive(Debug, Default)]
+pub struct QueuedReads {
+    commands: Vec<(ParsedCommand, Bytes)>,
+}
+
+impl QueuedReads {
+    pub fn push(&mut self, cmd: ParsedCommand, raw: Bytes) {
+        self.commands.push((cmd, raw));
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &ParsedCommand> {
+        self.commands.iter().map(|(cmd, _)| cmd)
+    }
+
+    /// Open the transaction on `backend` with `MULTI` and the queued commands, discarding the
+    /// replies the client was already given.
+    pub async fn replay(&self, backend: &mut RespStream, wait: Option<Duration>) -> Result<()> {
+        let mut pipeline = encode_command_str(&["MULTI"]);
+        for (_, raw) in &self.commands {
+            pipeline.extend_from_slice(raw);
+        }
+        backend.write_all(&pipeline).await?;
+        for _ in 0..=self.commands.len() {
+            read_reply(backend, wait).await?;
+        }
+        Ok(())
+    }
+
+    /// Run the whole transaction on `backend` and return the reply to `EXEC`. `Err` means the
+    /// backend connection failed.
+    pub async fn exec(&self, backend: &mut RespStream, wait: Option<Duration>) -> Result<Bytes> {
+        self.replay(backend, wait).await?;
+        backend.write_all(&encode_command_str(&["EXEC"])).await?;
+        read_reply(backend, wait).await
+    }
+}
+
+async fn read_reply(backend: &mut RespStream, wait: Option<Duration>) -> Result<Bytes> {
+    loop {
+        let read = backend.read_frame();
+        let frame = match wait {
+            Some(wait) => timeout(wait, read)
+                .await
+                .context("transaction read timeout")?,
+            None => read.await,
+        }?;
+        let Some((_, raw)) = frame else {
+            return Err(anyhow!("backend closed during a transaction"));
+        };
+        // Out-of-band RESP3 pushes are not part of the reply.
+        if raw.first() != Some(&b'>') {
+            return Ok(raw);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use anyhow::{Context, Result, anyhow};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::migrate;
+use crate::proxy::Shared;
+
+/// A setting that `PROXY CONFIG GET/SET` can read and change at runtime.
+///
+/// Names are the setting's key in the config file, prefixed by its section, e.g.
+/// `timeouts.replica_ms`.
+struct Tunable {
+    name: &'static str,
+    get: fn(&Config) -> Value,
+    set: fn(&mut Config, Value),
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Milliseconds; 0 disables settings that are optional.
+    Millis,
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Millis(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn parse(kind: Kind, s: &str) -> Result<Self> {
+        match kind {
+            Kind::Millis => s
+                .parse()
+                .map(Value::Millis)
+                .map_err(|_| anyhow!("expected milliseconds, got '{s}'")),
+            Kind::Bool => match s.to_ascii_lowercase().as_str() {
+                "yes" | "true" => Ok(Value::Bool(true)),
+                "no" | "false" => Ok(Value::Bool(false)),
+                _ => Err(anyhow!("expected yes or no, got '{s}'")),
+            },
+        }
+    }
+
+    fn millis(self) -> u64 {
+        match self {
+            Value::Millis(ms) => ms,
+            Value::Bool(_) => unreachable!("tunable kinds are fixed"),
+        }
+    }
+
+    fn bool(self) -> bool {
+        match self {
+            Value::Bool(b) => b,
+            Value::Millis(_) => unreachable!("tunable kinds are fixed"),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Millis(ms) => write!(f, "{ms}"),
+            Value::Bool(b) => f.write_str(if *b { "yes" } else { "no" }),
+        }
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+fn optional_millis(d: Option<Duration>) -> u64 {
+    d.map(millis).unwrap_or(0)
+}
+
+fn nonzero(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+const TUNABLES: &[Tunable] = &[
+    Tunable {
+        name: "timeouts.connect_ms",
+        get: |c| Value::Millis(millis(c.connect_timeout)),
+        set: |c, v| c.connect_timeout = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.handshake_ms",
+        get: |c| Value::Millis(optional_millis(c.handshake_timeout)),
+        set: |c, v| c.handshake_timeout = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.replica_ms",
+        get: |c| Value::Millis(millis(c.replica_timeout)),
+        set: |c, v| c.replica_timeout = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.master_ms",
+        get: |c| Value::Millis(optional_millis(c.master_timeout)),
+        set: |c, v| c.master_timeout = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.replica_drain_ms",
+        get: |c| Value::Millis(millis(c.replica_drain_timeout)),
+        set: |c, v| c.replica_drain_timeout = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.readonly_retry_ms",
+        get: |c| Value::Millis(optional_millis(c.readonly_retry)),
+        set: |c, v| c.readonly_retry = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "timeouts.transient_retry_ms",
+        get: |c| Value::Millis(optional_millis(c.transient_retry)),
+        set: |c, v| c.transient_retry = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "retries.backoff_ms",
+        get: |c| Value::Millis(millis(c.retry_backoff)),
+        set: |c, v| c.retry_backoff = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "replica_retry.after_ms",
+        get: |c| Value::Millis(optional_millis(c.replica_retry_after)),
+        set: |c, v| c.replica_retry_after = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "replica_breaker.cooldown_ms",
+        get: |c| Value::Millis(millis(c.replica_breaker_cooldown)),
+        set: |c, v| c.replica_breaker_cooldown = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "replica_health.interval_ms",
+        get: |c| Value::Millis(optional_millis(c.replica_health_interval)),
+        set: |c, v| c.replica_health_interval = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "read_your_writes.window_ms",
+        get: |c| Value::Millis(optional_millis(c.read_your_writes)),
+        set: |c, v| c.read_your_writes = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "read_your_writes.global",
+        get: |c| Value::Bool(c.read_your_writes_global),
+        set: |c, v| c.read_your_writes_global = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "read_your_writes.pin_after_write_ms",
+        get: |c| Value::Millis(optional_millis(c.pin_after_write)),
+        set: |c, v| c.pin_after_write = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "hedged_reads.enabled",
+        get: |c| Value::Bool(c.hedged_reads),
+        set: |c, v| c.hedged_reads = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "hedged_reads.speculative_retry_ms",
+        get: |c| Value::Millis(optional_millis(c.speculative_retry)),
+        set: |c, v| c.speculative_retry = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "consistency.wait_timeout_ms",
+        get: |c| Value::Millis(millis(c.wait_timeout)),
+        set: |c, v| c.wait_timeout = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "replica_disable.window_ms",
+        get: |c| Value::Millis(millis(c.replica_disable_window)),
+        set: |c, v| c.replica_disable_window = Duration::from_millis(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "slowlog.threshold_ms",
+        get: |c| Value::Millis(optional_millis(c.slowlog_threshold)),
+        set: |c, v| c.slowlog_threshold = nonzero(v.millis()),
+        kind: Kind::Millis,
+    },
+    Tunable {
+        name: "routing.force_eval_readonly",
+        get: |c| Value::Bool(c.force_eval_readonly),
+        set: |c, v| c.force_eval_readonly = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.force_evalsha_readonly",
+        get: |c| Value::Bool(c.force_evalsha_readonly),
+        set: |c, v| c.force_evalsha_readonly = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.sort_readonly",
+        get: |c| Value::Bool(c.sort_readonly),
+        set: |c, v| c.sort_readonly = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.route_script_flags",
+        get: |c| Value::Bool(c.route_script_flags),
+        set: |c, v| c.route_script_flags = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.analyze_scripts",
+        get: |c| Value::Bool(c.analyze_scripts),
+        set: |c, v| c.analyze_scripts = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.read_only",
+        get: |c| Value::Bool(c.read_only),
+        set: |c, v| c.read_only = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.replica_transactions",
+        get: |c| Value::Bool(c.replica_transactions),
+        set: |c, v| c.replica_transactions = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.classify_connections",
+        get: |c| Value::Bool(c.classify_connections),
+        set: |c, v| c.classify_connections = v.bool(),
+        kind: Kind::Bool,
+    },
+    Tunable {
+        name: "routing.replica_expired_events",
+        get: |c| Value::Bool(c.replica_expired_events),
+        set: |c, v| c.replica_expired_events = v.bool(),
+        kind: Kind::Bool,
+    },
+];
+
+/// Kept outside [`Config`]: it lives in the log subscriber.
+const LOG_LEVEL: &str = "log_level";
+
+/// `PROXY CONFIG GET pattern`: name/value pairs of the tunables matching a glob pattern.
+pub fn get(shared: &Shared, pattern: &str) -> Vec<(String, String)> {
+    let cfg = shared.config();
+    let mut out: Vec<_> = TUNABLES
+        .iter()
+        .filter(|t| glob_match(pattern, t.name))
+        .map(|t| (t.name.to_string(), (t.get)(&cfg).to_string()))
+        .collect();
+    if glob_match(pattern, LOG_LEVEL) {
+        out.push((LOG_LEVEL.to_string(), shared.log.current()));
+    }
+    out
+}
+
+/// `PROXY CONFIG SET name value [name value ...]`. Every pair is validated before any is applied.
+pub fn set(shared: &Shared, pairs: &[(String, String)]) -> Result<()> {
+    let mut cfg = (*shared.config()).clone();
+    let mut log_level = None;
+    for (name, value) in pairs {
+        let name = name.to_ascii_lowercase();
+        if name == LOG_LEVEL {
+            tracing_subscriber::EnvFilter::try_new(value)
+                .map_err(|_| anyhow!("invalid log filter '{value}'"))?;
+            log_level = Some(value);
+            continue;
+        }
+        let tunable = TUNABLES
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| anyhow!("unknown or read-only setting '{name}'"))?;
+        let value = Value::parse(tunable.kind, value)
+            .with_context(|| format!("invalid value for '{name}'"))?;
+        (tunable.set)(&mut cfg, value);
+    }
+
+    shared.set_config(cfg);
+    if let Some(level) = log_level {
+        shared.log.set(level)?;
+    }
+    tracing::info!(
+        settings = ?pairs.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(),
+        "configuration changed by PROXY CONFIG SET"
+    );
+    Ok(())
+}
+
+/// `PROXY CONFIG REWRITE`: write the current tunables back to the `--config` file, keeping its
+/// other contents and comments.
+pub fn rewrite(shared: &Shared) -> Result<()> {
+    let path = shared
+        .config_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("the proxy was started without --config"))?;
+    let mut doc = migrate::load(path)?;
+    // Rewritten files are brought up to the current schema.
+    migrate::upgrade(&mut doc)?;
+
+    let cfg = shared.config();
+    for tunable in TUNABLES {
+        let (section, key) = tunable
+            .name
+            .split_once('.')
+            .expect("tunable names are section.key");
+        let item = match (tunable.get)(&cfg) {
+            Value::Millis(ms) => toml_edit::value(i64::try_from(ms).unwrap_or(i64::MAX)),
+            Value::Bool(b) => toml_edit::value(b),
+        };
+        if !doc.contains_table(section) {
+            doc[section] = toml_edit::table();
+        }
+        doc[section][key] = item;
+    }
+    doc[LOG_LEVEL] = toml_edit::value(shared.log.current());
+
+    migrate::save(path, &doc)?;
+    tracing::info!(path = %path.display(), "configuration rewritten by PROXY CONFIG REWRITE");
+    Ok(())
+}
+
+/// Redis-style glob supporting `*` and `?`, matched case-insensitively.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn go(p: &[u8], n: &[u8]) -> bool {
+        match (p.first(), n.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => go(&p[1..], n) || (!n.is_empty() && go(p, &n[1..])),
+            (Some(b'?'), Some(_)) => go(&p[1..], &n[1..]),
+            (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => go(&p[1..], &n[1..]),
+            _ => false,
+        }
+    }
+    go(pattern.as_bytes(), name.as_bytes())
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::resp::{Reply, RespStream, encode_command_str};
+
+/// Oldest Redis release the proxy supports: it relies on HELLO and ACL-style AUTH (6.0).
+pub const MIN_SUPPORTED_VERSION: (u32, u32, u32) = (6, 0, 0);
+
+/// What a backend reports in `INFO server`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub version: String,
+    /// `standalone`, `cluster` or `sentinel`.
+    pub mode: String,
+}
+
+impl ServerInfo {
+    fn parse(info: &str) -> Result<Self> {
+        let field = |name: &str| {
+            info.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(|v| v.trim().to_string())
+        };
+        Ok(Self {
+            version: field("redis_version").ok_or_else(|| anyhow!("no redis_version in INFO"))?,
+            mode: field("redis_mode").unwrap_or_else(|| "standalone".to_string()),
+        })
+    }
+}
+
+/// `INFO server` on a freshly connected backend.
+pub async fn query_server_info(stream: &mut RespStream, wait: Duration) -> Result<ServerInfo> {
+    stream
+        .write_all(&encode_command_str(&["INFO", "server"]))
+        .await?;
+    let (frame, _) = timeout(wait, stream.read_frame())
+        .await
+        .context("INFO timeout")??
+        .ok_or_else(|| anyhow!("backend closed during INFO"))?;
+    match Reply::from_frame(&frame) {
+        Reply::Str(text) => ServerInfo::parse(&String::from_utf8_lossy(&text)),
+        Reply::Error(e) => Err(anyhow!("INFO failed: {e}")),
+        _ => Err(anyhow!("unexpected reply to INFO")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendRole {
+    Master,
+    Replica,
+}
+
+impl BackendRole {
+    fn name(self) -> &'static str {
+        match self {
+            BackendRole::Master => "master",
+            BackendRole::Replica => "replica",
+        }
+    }
+}
+
+/// Versions reported by the backends, so each one is logged when first seen or when it changes
+/// (e.g. after an upgrade) rather than on every connect.
+#[derive(Debug, Default)]
+pub struct BackendVersions {
+    state: Mutex<VersionState>,
+}
+
+#[derive(Debug, Default)]
+struct VersionState {
+    by_addr: HashMap<String, ServerInfo>,
+    latest: HashMap<BackendRole, String>,
+    /// (master, replica) version pairs already warned about.
+    mismatches: HashSet<(String, String)>,
+}
+
+impl BackendVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Version the master last reported.
+    pub fn master_version(&self) -> Option<String> {
+        let state = self.state.lock().expect("backend versions lock poisoned");
+        state.latest.get(&BackendRole::Master).cloned()
+    }
+
+    pub fn record(&self, role: BackendRole, addr: &str, info: ServerInfo) {
+        let mut state = self.state.lock().expect("backend versions lock poisoned");
+        if state.by_addr.get(addr) == Some(&info) {
+            return;
+        }
+
+        tracing::info!(
+            role = role.name(),
+            %addr,
+            version = %info.version,
+            mode = %info.mode,
+            "backend server info"
+        );
+        if parse_version(&info.version).is_some_and(|v| v < MIN_SUPPORTED_VERSION) {
+            let (major, minor, patch) = MIN_SUPPORTED_VERSION;
+            tracing::warn!(
+                role = role.name(),
+                %addr,
+                version = %info.version,
+                minimum = %format!("{major}.{minor}.{patch}"),
+                "backend Redis version is below the supported minimum"
+            );
+        }
+        if info.mode != "standalone" {
+            tracing::warn!(
+                role = role.name(),
+                %addr,
+                mode = %info.mode,
+                "backend is not a standalone Redis server"
+            );
+        }
+
+        state.latest.insert(role, info.version.clone());
+        state.by_addr.insert(addr.to_string(), info);
+
+        if let (Some(master), Some(replica)) = (
+            state.latest.get(&BackendRole::Master).cloned(),
+            state.latest.get(&BackendRole::Replica).cloned(),
+        ) && master != replica
+            && state.mismatches.insert((master.clone(), replica.clone()))
+        {
+            tracing::warn!(
+                master_version = %master,
+                replica_version = %replica,
+                "master and replica run different Redis versions"
+            );
+        }
+    }
+}
+
+fn parse_version(s: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = s.split('.').map(|p| p.parse::<u32>().ok());
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
@@ -0,0 +1,191 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+use crate::config::RedisEndpoint;
+use crate::proxy::{Shared, connect_and_handshake, record_server_info};
+use crate::resp::{Reply, RespStream, encode_command_str};
+use crate::versions::BackendRole;
+
+/// How often [`refill_loop`] tops the pool up.
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+/// Idle connections are PINGed after this long, so one the backend closed isn't handed out.
+const PING_AFTER: Duration = Duration::from_secs(10);
+/// Connections dialed at once per backend while the pool fills, so that a reconnect storm
+/// after a failover isn't made worse by the pool itself.
+const DIALS_PER_REFILL: usize = 4;
+
+/// `--warm-connections`: backend connections dialed, authenticated and `SELECT`ed ahead of the
+/// clients that need them, so that a client's first command doesn't wait for connect, TLS and
+/// AUTH. Kept for the current master and every replica in the pool.
+///
+/// Not used with `--send-proxy-protocol`, whose header names the client on each connection.
+#[derive(Debug)]
+pub struct WarmPool {
+    size: usize,
+    idle: Mutex<HashMap<(String, u16), Vec<Idle>>>,
+}
+
+#[derive(Debug)]
+struct Idle {
+    conn: RespStream,
+    /// When it was dialed or last answered a PING.
+    checked: Instant,
+}
+
+impl WarmPool {
+    /// Keep `size` idle connections per backend.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A ready connection to `endpoint`, if the pool has one.
+    pub fn checkout(&self, endpoint: &RedisEndpoint) -> Option<RespStream> {
+        let mut idle = self.idle.lock().expect("warm pool lock poisoned");
+        let conns = idle.get_mut(&(endpoint.host.clone(), endpoint.port))?;
+        // The most recently checked one is the likeliest to still be open.
+        conns.pop().map(|idle| idle.conn)
+    }
+
+    /// Idle connections per backend, for `PROXY STATUS`.
+    pub fn render(&self) -> String {
+        let idle = self.idle.lock().expect("warm pool lock poisoned");
+        let mut backends: Vec<_> = idle
+            .iter()
+            .map(|((host, port), conns)| format!("{host}:{port}={}", conns.len()))
+            .collect();
+        backends.sort();
+        format!("warm_connections:{}\r\n", backends.join(","))
+    }
+
+    fn idle_count(&self, key: &(String, u16)) -> usize {
+        let idle = self.idle.lock().expect("warm pool lock poisoned");
+        idle.get(key).map_or(0, Vec::len)
+    }
+
+    fn put(&self, key: (String, u16), conn: Idle) {
+        let mut idle = self.idle.lock().expect("warm pool lock poisoned");
+        idle.entry(key).or_default().push(conn);
+    }
+
+    /// Take out the connections that are due for a PING.
+    fn take_unchecked(&self, key: &(String, u16)) -> Vec<Idle> {
+        let mut idle = self.idle.lock().expect("warm pool lock poisoned");
+        let Some(conns) = idle.get_mut(key) else {
+            return Vec::new();
+        };
+        let (due, fresh) = std::mem::take(conns)
+            .into_iter()
+            .partition(|idle| idle.checked.elapsed() >= PING_AFTER);
+        *conns = fresh;
+        due
+    }
+
+    /// Drop the connections to backends that aren't `current` any more.
+    fn retain(&self, current: &[(String, u16)]) -> Vec<Idle> {
+        let mut idle = self.idle.lock().expect("warm pool lock poisoned");
+        let gone: Vec<_> = idle
+            .keys()
+            .filter(|key| !current.contains(key))
+            .cloned()
+            .collect();
+        gone.iter()
+            .filter_map(|key| idle.remove(key))
+            .flatten()
+            .collect()
+    }
+}
+
+/// Keep [`Shared::warm`] filled for the current master and replicas.
+pub async fn refill_loop(shared: Arc<Shared>) {
+    let Some(pool) = shared.warm.as_ref() else {
+        return;
+    };
+    loop {
+        let mut backends = vec![shared.pool.master()];
+        backends.extend(
+            shared
+                .pool
+                .replicas()
+                .into_iter()
+                .filter(|r| shared.replica_health.is_usable(&r.host, r.port)),
+        );
+        let keys: Vec<_> = backends.iter().map(|b| (b.host.clone(), b.port)).collect();
+        for mut gone in pool.retain(&keys) {
+            let _ = gone.conn.shutdown().await;
+        }
+        let master_down = shared.master_watch.is_down();
+        for (i, (endpoint, key)) in backends.iter().zip(keys).enumerate() {
+            let role = if i == 0 {
+                BackendRole::Master
+            } else {
+                BackendRole::Replica
+            };
+            // Dialing a master that is down is up to `master_watch`.
+            if role == BackendRole::Master && master_down {
+                continue;
+            }
+            for mut idle in pool.take_unchecked(&key) {
+                match ping(&mut idle.conn, &shared, endpoint).await {
+                    Ok(()) => {
+                        idle.checked = Instant::now();
+                        pool.put(key.clone(), idle);
+                    }
+                    Err(e) => {
+                        tracing::debug!(host = %key.0, port = key.1, error = %format!("{e:#}"), "dropping warm connection");
+                        let _ = idle.conn.shutdown().await;
+                    }
+                }
+            }
+            let missing = pool.size.saturating_sub(pool.idle_count(&key));
+            for _ in 0..missing.min(DIALS_PER_REFILL) {
+                let cfg = shared.config();
+                let dialed = async {
+                    let mut conn = connect_and_handshake(
+                        endpoint,
+                        cfg.connect_timeout,
+                        &shared.resolver,
+                        None,
+                    )
+                    .await?;
+                    record_server_info(&shared, role, endpoint, &mut conn).await?;
+                    anyhow::Ok(conn)
+                };
+                match dialed.await {
+                    Ok(conn) => pool.put(
+                        key.clone(),
+                        Idle {
+                            conn,
+                            checked: Instant::now(),
+                        },
+                    ),
+                    Err(e) => {
+                        tracing::debug!(host = %key.0, port = key.1, error = %format!("{e:#}"), "warm connection dial failed");
+                        break;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(REFILL_INTERVAL).await;
+    }
+}
+
+async fn ping(conn: &mut RespStream, shared: &Shared, endpoint: &RedisEndpoint) -> Result<()> {
+    let wait = endpoint
+        .read_timeout
+        .unwrap_or(shared.config().connect_timeout);
+    conn.write_all(&encode_command_str(&["PING"])).await?;
+    let (frame, _) = timeout(wait, conn.read_frame())
+        .await
+        .map_err(|_| anyhow!("PING timeout"))??
+        .ok_or_else(|| anyhow!("backend closed the connection"))?;
+    match Reply::from_frame(&frame) {
+        Reply::Str(pong) if pong.as_ref() == b"PONG" => Ok(()),
+        reply => Err(anyhow!("unexpected reply to PING: {reply:?}")),
+    }
+}